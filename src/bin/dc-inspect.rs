@@ -0,0 +1,143 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use anyhow::{anyhow, Context, Result};
+use clap::Parser;
+use oks::{
+    ca::{root_key_hash, Ca, DebugCredential},
+    sandbox::SANDBOX_DIR,
+};
+use rsa::{
+    pkcs8::{EncodePublicKey, LineEnding},
+    traits::PublicKeyParts,
+    RsaPublicKey,
+};
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+};
+
+/// Print the fields of a debug credential produced by oks. If the label of
+/// the CA that signed it is provided the signature is verified. The root key
+/// table is checked against the root CAs or CMPA if either is provided.
+#[derive(Parser, Debug)]
+struct Args {
+    /// Directory holding the CA state
+    #[clap(long, env, default_value = "/var/lib/oks/ca-state")]
+    state: PathBuf,
+
+    /// Label of the CA expected to have signed the credential
+    #[clap(long)]
+    label: Option<String>,
+
+    /// Labels of the root CAs expected in the root key table, in order
+    #[clap(long, num_args = 1..=4, value_delimiter = ',')]
+    root_labels: Vec<String>,
+
+    /// CMPA expected to hold the root key table hash
+    #[clap(long, conflicts_with = "root_labels")]
+    cmpa: Option<PathBuf>,
+
+    /// Debug credential file
+    dc: PathBuf,
+}
+
+// Load the `Ca`s from the state directory, each by its label. The state
+// directory is only needed to verify the credential.
+fn load_cas(state: &Path) -> Result<HashMap<String, Ca>> {
+    let mut cas = HashMap::new();
+    for entry in fs::read_dir(state)? {
+        let path = entry?.path();
+        if path.is_dir() && !path.ends_with(SANDBOX_DIR) {
+            let ca = Ca::load(path)?;
+            cas.insert(ca.name(), ca);
+        }
+    }
+
+    Ok(cas)
+}
+
+fn get_ca<'a>(cas: &'a HashMap<String, Ca>, label: &str) -> Result<&'a Ca> {
+    cas.get(label)
+        .ok_or(anyhow!("no CA \"{}\" in the state directory", label))
+}
+
+fn print_key(name: &str, key: &RsaPublicKey) -> Result<()> {
+    println!("{}: RSA {} bits", name, key.size() * 8);
+    print!("{}", key.to_public_key_pem(LineEnding::default())?);
+
+    Ok(())
+}
+
+fn main() -> Result<()> {
+    let args = Args::parse();
+
+    let data = fs::read(&args.dc).with_context(|| {
+        format!("Failed to read debug credential from {}", args.dc.display())
+    })?;
+    let dc = DebugCredential::from_bytes(&data)?;
+
+    let cas = if args.label.is_some() || !args.root_labels.is_empty() {
+        load_cas(&args.state)?
+    } else {
+        HashMap::new()
+    };
+
+    // the root CAs we know, by the hash of their key
+    let mut roots = HashMap::new();
+    for ca in cas.values() {
+        if let Ok(hash) = ca.cert().and_then(|cert| root_key_hash(&cert)) {
+            roots.insert(hash, ca.name());
+        }
+    }
+
+    println!("version: {}.{}", dc.version.0, dc.version.1);
+    println!("soc class: {:#010x}", dc.soc_class);
+    println!("uuid: {}", hex::encode(dc.uuid));
+    println!("root key table:");
+    for (i, hash) in dc.root_key_hashes.iter().enumerate() {
+        match roots.get(hash) {
+            Some(name) => println!("  {}: {} ({})", i, hex::encode(hash), name),
+            None => println!("  {}: {}", i, hex::encode(hash)),
+        }
+    }
+    println!(
+        "root key table hash: {}",
+        hex::encode(dc.root_key_table_hash())
+    );
+    println!("CC_SOCU: {:#010x}", dc.cc_socu);
+    println!("CC_VU: {:#010x}", dc.cc_vu);
+    println!("CC_BEACON: {:#010x}", dc.cc_beacon);
+    match dc.rot_key_index() {
+        Some(i) => println!("signer: root key {}", i),
+        None => println!("signer: not in the root key table"),
+    }
+    print_key("signer key", &dc.rot_key)?;
+    print_key("debug key", &dc.debug_key)?;
+
+    if let Some(label) = &args.label {
+        dc.verify(label, &get_ca(&cas, label)?.cert()?)?;
+        println!("signature: verified with the key of CA {}", label);
+    }
+
+    if !args.root_labels.is_empty() {
+        let mut certs = Vec::new();
+        for label in &args.root_labels {
+            certs.push(get_ca(&cas, label)?.cert()?);
+        }
+        dc.check_roots(&certs)?;
+        println!("root keys: match {}", args.root_labels.join(", "));
+    }
+
+    if let Some(cmpa) = &args.cmpa {
+        let cmpa_data = fs::read(cmpa).with_context(|| {
+            format!("Failed to read CMPA from {}", cmpa.display())
+        })?;
+        dc.check_cmpa(&cmpa_data)?;
+        println!("root keys: match CMPA {}", cmpa.display());
+    }
+
+    Ok(())
+}
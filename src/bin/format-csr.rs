@@ -3,7 +3,7 @@
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
 use clap::Parser;
-use oks::config::CsrSpec;
+use oks::config::{CsrSpec, ValiditySpec};
 use std::{
     fs,
     io::{self, Read},
@@ -21,6 +21,16 @@ struct Config {
     /// CSR file. Read from stdin if omitted.
     #[clap(long)]
     csr: Option<PathBuf>,
+
+    /// Number of days the cert should be valid for. If omitted the cert
+    /// gets the longest validity period allowed.
+    #[clap(long)]
+    days: Option<u32>,
+
+    /// Name of the certificate profile for the cert. Only required if the
+    /// CA may issue certs with more than one profile.
+    #[clap(long)]
+    profile: Option<String>,
 }
 
 fn main() -> anyhow::Result<()> {
@@ -38,6 +48,11 @@ fn main() -> anyhow::Result<()> {
     let csr_spec = CsrSpec {
         label: cfg.label,
         csr,
+        validity: cfg.days.map(|days| ValiditySpec {
+            days: Some(days),
+            ..Default::default()
+        }),
+        profile: cfg.profile,
     };
 
     println!("{}", csr_spec.json()?);
@@ -6,6 +6,7 @@ use anyhow::Result;
 use clap::{Parser, Subcommand};
 use env_logger::Builder;
 use log::LevelFilter;
+use oks::hsm::ListArgs;
 use std::{path::PathBuf, str::FromStr};
 use yubihsm::{
     object::{Id, Type},
@@ -100,6 +101,12 @@ enum Command {
     /// Display device info.
     Info,
 
+    /// List objects.
+    List {
+        #[clap(flatten)]
+        list: ListArgs,
+    },
+
     /// Reset to factory defaults
     Reset,
 
@@ -185,6 +192,7 @@ fn main() -> Result<()> {
             oks::hsm::delete(&client, id, kind)
         }
         Command::Info => oks::hsm::dump_info(&client),
+        Command::List { list } => oks::hsm::dump_objects(&client, &list),
         Command::Reset => oks::hsm::reset(&client),
         Command::Restore { file } => oks::hsm::restore(&client, file),
     }
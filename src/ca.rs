@@ -4,24 +4,84 @@
 
 use anyhow::{anyhow, Context, Result};
 use log::{debug, error, info, warn};
+use num_bigint::BigUint;
+use pem_rfc7468::LineEnding;
+use rsa::{
+    pkcs1v15,
+    pkcs8::DecodePublicKey,
+    signature::{hazmat::PrehashVerifier, Verifier},
+    traits::PublicKeyParts,
+    RsaPublicKey,
+};
+use serde::Serialize;
+use sha1::Sha1;
+use sha2::{Digest, Sha256, Sha384, Sha512};
 use std::{
     collections::HashMap,
-    env,
-    fs::{self, OpenOptions, Permissions},
-    os::unix::fs::PermissionsExt,
+    fmt::{self, Display, Formatter},
+    fs, mem,
+    net::IpAddr,
     path::{Path, PathBuf},
-    process::{Child, Command, Stdio},
     str::FromStr,
-    thread,
-    time::Duration,
+    time::{Duration, SystemTime},
 };
-use tempfile::NamedTempFile;
 use thiserror::Error;
-use x509_cert::{certificate::Certificate, der::DecodePem};
-use yubihsm::Client;
-use zeroize::Zeroizing;
+use x509_cert::{
+    attr::AttributeTypeAndValue,
+    certificate::{Certificate, TbsCertificate, Version},
+    crl::{CertificateList, RevokedCert, TbsCertList},
+    der::{
+        asn1::{
+            Any, BitString, GeneralizedTime, Ia5String, ObjectIdentifier,
+            OctetString, SetOfVec, Uint, UtcTime,
+        },
+        flagset::FlagSet,
+        oid::AssociatedOid,
+        DateTime, Decode, DecodePem, Encode, EncodePem, Tag,
+    },
+    ext::{
+        pkix::{
+            certpolicy::PolicyInformation,
+            constraints::name::{GeneralSubtree, NameConstraints},
+            name::GeneralName,
+            AuthorityKeyIdentifier, BasicConstraints, CertificatePolicies,
+            CrlNumber, CrlReason, ExtendedKeyUsage, KeyUsage, KeyUsages,
+            SubjectKeyIdentifier,
+        },
+        Extension,
+    },
+    name::{Name, RdnSequence, RelativeDistinguishedName},
+    request::{self, CertReq, CertReqInfo, ExtensionReq},
+    serial_number::SerialNumber,
+    spki::{AlgorithmIdentifierOwned, SubjectPublicKeyInfoOwned},
+    time::{Time, Validity},
+};
+use yubihsm::{
+    asymmetric,
+    object::{Filter, Id, Type},
+    opaque, Capability, Client,
+};
 
-use crate::config::{CsrSpec, DcsrSpec, KeySpec, Purpose};
+use crate::{
+    config::{
+        CsrSpec, DcsrSpec, Hash, ImageSpec, KeySpec, NameAttribute,
+        PolicyMatch, PolicyRule, SerialPolicy, ValiditySpec, SERIAL_LEN,
+        SERIAL_MIN_RANDOM,
+    },
+    hsm::{
+        self, BACKUP_EXT, OID_EC_PUBLIC_KEY, OID_ED25519, OID_SECP256R1,
+        OID_SECP384R1, OID_SECP521R1,
+    },
+    issued::{
+        compact_db, expand_db, parse_serial, IssuanceDb, IssuedCert,
+        Provenance, Revocation, RevokeReason, COMPACT_LEN, ISSUED_DB,
+        NEWCERTS_DIR,
+    },
+    profile::{
+        ConstraintName, DeviceUuid, KeyAlgorithm, KeyUsageBit, Profile,
+        Profiles,
+    },
+};
 
 /// Name of file in root of a CA directory with key spec used to generate key
 /// in HSM.
@@ -30,11 +90,168 @@ const CA_KEY_SPEC: &str = "key.spec";
 /// Name of file in root of a CA directory containing the CA's own certificate.
 const CA_CERT: &str = "ca.cert.pem";
 
-// Name of the environment variable to get the YubiHSM auth id and password
-// into the PKCS#11 module, the format is "<auth-id>:<passwd>" where
-// `auth-id` is a 4 digit decimal number and `password` is a string. We use
-// a second variable to accommodate the `auth-id`.
-pub const ENV_CA_PASSWORD: &str = "OKM_HSM_PKCS11_AUTH";
+/// Name of file in root of a CA directory that marks the CA as one whose key
+/// is held by the sandbox keystore and not a YubiHSM.
+const CA_SANDBOX: &str = "sandbox";
+
+/// Name of file in root of a CA directory holding the next serial number.
+const CA_SERIAL: &str = "serial";
+
+/// The number of random serial numbers drawn for a cert before giving up if
+/// each has already been issued.
+const SERIAL_ATTEMPTS: usize = 8;
+
+/// Name of directory in root of a CA directory that holds a copy of every
+/// CRL issued by the CA, named for its CRL number.
+const CA_CRL_DIR: &str = "crl";
+
+/// Name of file in root of a CA directory holding the next CRL number.
+const CA_CRL_NUMBER: &str = "crlnumber";
+
+// A copy of the state for each CA is kept in opaque objects in the YubiHSM
+// so that the CA state directory can be rebuilt from a restored YubiHSM.
+// Each object has the same label as the CA key and its id is the id of the
+// key plus one of these offsets.
+const OPAQUE_CERT_OFFSET: Id = 0x1000;
+const OPAQUE_KEY_SPEC_OFFSET: Id = 0x2000;
+const OPAQUE_SERIAL_OFFSET: Id = 0x3000;
+const OPAQUE_CRL_NUMBER_OFFSET: Id = 0x4000;
+
+// The issuance database is kept in its compact form & that may be too big
+// for a single opaque object so it's split across up to
+// OPAQUE_ISSUED_OBJECTS objects: the Nth has the id of the key plus
+// OPAQUE_ISSUED_OFFSET plus N * OPAQUE_CERT_OFFSET.
+const OPAQUE_ISSUED_OFFSET: Id = 0x5000;
+const OPAQUE_ISSUED_OBJECTS: Id = 11;
+
+/// The largest opaque object the YubiHSM will store.
+const OPAQUE_MAX_LEN: usize = 1968;
+
+/// The most certs a CA with its state in the YubiHSM can issue: the compact
+/// record for each must fit in the issuance database objects.
+const OPAQUE_ISSUED_MAX: usize =
+    OPAQUE_ISSUED_OBJECTS as usize * OPAQUE_MAX_LEN / COMPACT_LEN;
+
+/// Country & organization in the subject of CAs that don't have a subject in
+/// their key spec.
+const CA_COUNTRY: &str = "US";
+const CA_ORGANIZATION: &str = "Oxide Computer Company";
+
+/// Organizational unit added to the subject of every sandbox CA.
+const SANDBOX_OU: &str = "SANDBOX";
+
+// Attribute types used in subject names.
+const OID_COUNTRY_NAME: ObjectIdentifier =
+    ObjectIdentifier::new_unwrap("2.5.4.6");
+const OID_LOCALITY_NAME: ObjectIdentifier =
+    ObjectIdentifier::new_unwrap("2.5.4.7");
+const OID_STATE_OR_PROVINCE_NAME: ObjectIdentifier =
+    ObjectIdentifier::new_unwrap("2.5.4.8");
+const OID_ORGANIZATION_NAME: ObjectIdentifier =
+    ObjectIdentifier::new_unwrap("2.5.4.10");
+const OID_ORGANIZATIONAL_UNIT_NAME: ObjectIdentifier =
+    ObjectIdentifier::new_unwrap("2.5.4.11");
+const OID_COMMON_NAME: ObjectIdentifier =
+    ObjectIdentifier::new_unwrap("2.5.4.3");
+
+// Signature algorithms.
+const OID_RSA_ENCRYPTION: ObjectIdentifier =
+    ObjectIdentifier::new_unwrap("1.2.840.113549.1.1.1");
+const OID_SHA256_WITH_RSA: ObjectIdentifier =
+    ObjectIdentifier::new_unwrap("1.2.840.113549.1.1.11");
+const OID_SHA384_WITH_RSA: ObjectIdentifier =
+    ObjectIdentifier::new_unwrap("1.2.840.113549.1.1.12");
+const OID_SHA512_WITH_RSA: ObjectIdentifier =
+    ObjectIdentifier::new_unwrap("1.2.840.113549.1.1.13");
+const OID_ECDSA_WITH_SHA256: ObjectIdentifier =
+    ObjectIdentifier::new_unwrap("1.2.840.10045.4.3.2");
+const OID_ECDSA_WITH_SHA384: ObjectIdentifier =
+    ObjectIdentifier::new_unwrap("1.2.840.10045.4.3.3");
+const OID_ECDSA_WITH_SHA512: ObjectIdentifier =
+    ObjectIdentifier::new_unwrap("1.2.840.10045.4.3.4");
+
+/// The policy applied to the subject of CSRs by CAs that don't have one in
+/// their key spec.
+const SUBJECT_POLICY: [PolicyRule; 5] = [
+    PolicyRule {
+        attr: NameAttribute::C,
+        rule: PolicyMatch::Supplied,
+    },
+    PolicyRule {
+        attr: NameAttribute::ST,
+        rule: PolicyMatch::Optional,
+    },
+    PolicyRule {
+        attr: NameAttribute::O,
+        rule: PolicyMatch::Supplied,
+    },
+    PolicyRule {
+        attr: NameAttribute::OU,
+        rule: PolicyMatch::Optional,
+    },
+    PolicyRule {
+        attr: NameAttribute::CN,
+        rule: PolicyMatch::Supplied,
+    },
+];
+
+/// The rules checked before a CSR is signed.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum CsrRule {
+    /// The CSR must be signed by the key it's for.
+    ProofOfPossession,
+    /// The key must be one the profile allows.
+    KeyAlgorithm,
+    /// The subject must satisfy the CA's subject policy.
+    SubjectPolicy,
+    /// The key must not be a CA key or have been certified before.
+    KeyReuse,
+    /// The CSR must not request extensions: they'd be dropped.
+    RequestedExtensions,
+}
+
+impl Display for CsrRule {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let rule = match self {
+            CsrRule::ProofOfPossession => "proof-of-possession",
+            CsrRule::KeyAlgorithm => "key-algorithm",
+            CsrRule::SubjectPolicy => "subject-policy",
+            CsrRule::KeyReuse => "key-reuse",
+            CsrRule::RequestedExtensions => "requested-extensions",
+        };
+        write!(f, "{}", rule)
+    }
+}
+
+/// The rules checked before a debug credential is signed.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum DcsrRule {
+    /// The root key table must hold 1 to 4 distinct keys.
+    RootKeys,
+    /// The signing CA must be one of the roots & its profile must allow
+    /// debug credentials.
+    Signer,
+    /// The debug key must be the size of the signing key & no smaller than
+    /// the profile allows.
+    KeySize,
+    /// Only the debug permissions the profile allows may be granted.
+    Permissions,
+    /// The device must be one the profile allows.
+    Uuid,
+}
+
+impl Display for DcsrRule {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let rule = match self {
+            DcsrRule::RootKeys => "root-keys",
+            DcsrRule::Signer => "signer",
+            DcsrRule::KeySize => "key-size",
+            DcsrRule::Permissions => "permissions",
+            DcsrRule::Uuid => "uuid",
+        };
+        write!(f, "{}", rule)
+    }
+}
 
 #[derive(Error, Debug)]
 pub enum CaError {
@@ -44,163 +261,172 @@ pub enum CaError {
     BadDcsrSpecPath,
     #[error("Invalid path to KeySpec file")]
     BadKeySpecPath,
-    #[error("Invalid purpose for root CA key")]
-    BadPurpose,
     #[error("path not a directory")]
     BadSpecDirectory,
-    #[error("failed to generate certificate")]
-    CertGenFail,
-    #[error("failed to create self signed cert for key")]
-    SelfCertGenFail,
     #[error("CA state directory has no key.spec")]
     NoKeySpec,
+    #[error("key id {id:#06x} is too large to store CA state in the YubiHSM")]
+    BadStateId { id: Id },
+    #[error("sandbox CAs have no state in the YubiHSM")]
+    SandboxState,
+    #[error(
+        "CA {name} has issued {certs} certs, the most its issuance database \
+         in the YubiHSM can hold"
+    )]
+    IssuedFull { name: String, certs: usize },
+    #[error("invalid serial number in CA state: \"{serial}\"")]
+    BadSerial { serial: String },
+    #[error("signing with {algorithm:?} key & hash {hash:?} is not supported")]
+    UnsupportedSignature {
+        algorithm: asymmetric::Algorithm,
+        hash: Option<Hash>,
+    },
+    #[error("unsupported signature algorithm: {oid}")]
+    UnsupportedSignatureAlgorithm { oid: ObjectIdentifier },
+    #[error("CSR signature verification failed")]
+    BadCsrSignature,
+    #[error("signature on cert for \"{subject}\" isn't from its issuer")]
+    BadCertSignature { subject: String },
+    #[error("cert for \"{subject}\" wasn't issued by \"{issuer}\"")]
+    IssuerMismatch { subject: String, issuer: String },
+    #[error("CSR subject is missing required attribute: {attr}")]
+    MissingSubjectAttribute { attr: &'static str },
+    #[error("CSR subject attribute {attr} doesn't match the CA subject")]
+    SubjectMismatch { attr: &'static str },
+    #[error("no cert with serial number {serial} in CA database")]
+    NoCert { serial: String },
+    #[error(
+        "cert with serial number {serial} was issued but isn't in the \
+         database for CA {name}"
+    )]
+    Unrecorded { name: String, serial: String },
+    #[error("cert with serial number {serial} is already revoked")]
+    AlreadyRevoked { serial: String },
+    #[error(
+        "certs with profile \"{profile}\" may be valid for at most {max} days"
+    )]
+    ValidityTooLong { profile: String, max: u32 },
+    #[error("cert would expire at {not_after}, after its issuer: {issuer}")]
+    OutlivesIssuer {
+        not_after: DateTime,
+        issuer: DateTime,
+    },
+    #[error("cert would expire at {not_after}, before it's valid")]
+    EmptyValidity { not_after: DateTime },
+    #[error("CA {name} can't issue certs till its CSR has been signed")]
+    NoCaCert { name: String },
+    #[error("CA {name} has no issuance database: run `oks ca import-index`")]
+    NoIssuanceDb { name: String },
+    #[error("CA {name} already has a cert")]
+    HasCert { name: String },
+    #[error("cert isn't for the key of CA {name}")]
+    KeyMismatch { name: String },
+    #[error("cert for \"{subject}\" isn't a CA cert")]
+    NotCaCert { subject: String },
+    #[error("cert for \"{subject}\" expired at {not_after}")]
+    CertExpired {
+        subject: String,
+        not_after: DateTime,
+    },
+    #[error("no issuer cert provided")]
+    NoIssuerCert,
+    #[error(
+        "no CA in the state directory has the cert of \"{issuer}\", the \
+        issuer of \"{subject}\""
+    )]
+    MissingIssuerCert { subject: String, issuer: String },
+    #[error("the lpc55 root key table holds 1 to 4 keys, got {count}")]
+    BadRootKeyCount { count: usize },
+    #[error("root key \"{label}\" is in the lpc55 root key table twice")]
+    DuplicateRootKey { label: String },
+    #[error("bad debug credential: {reason}")]
+    BadDebugCredential { reason: String },
+    #[error("debug credential isn't signed by the key of CA {name}")]
+    DebugCredentialSigner { name: String },
+    #[error("root keys don't match the debug credential: {reason}")]
+    RootKeyMismatch { reason: String },
+    #[error("image cert chain is empty")]
+    EmptyImageChain,
+    #[error("image cert chain doesn't start with a cert for a root key")]
+    UntrustedImageChain,
+    #[error("CSR violates rule \"{rule}\": {reason}")]
+    CsrRejected { rule: CsrRule, reason: String },
+    #[error("DCSR violates rule \"{rule}\": {reason}")]
+    DcsrRejected { rule: DcsrRule, reason: String },
+    #[error(
+        "CA {name} has already issued a cert with serial number {serial}: \
+        fix the serial number in its state directory"
+    )]
+    SerialReused { name: String, serial: String },
+    #[error(
+        "CA {name} drew {attempts} random serial numbers that it had \
+        already issued"
+    )]
+    SerialCollision { name: String, attempts: usize },
+    #[error(
+        "CA {name} has a bad serial number prefix: {prefix}, it must leave \
+        the sign bit clear & at least {SERIAL_MIN_RANDOM} bytes for the RNG"
+    )]
+    BadSerialPolicy { name: String, prefix: String },
 }
 
-// This is a template for the openssl config file used for all CAs.
-// In this template we populate 3 fields:
-// - `pkcs11_path`: This is the path to the PKCS#11 module used by `openssl`
-//   to communicate w/ the YubiHSM / connector.
-// - `key`: This is the Id of the key stored in the YubiHSM. It is an integer
-//   and will be prefixed by 0's.
-// - `hash`: This is the default digest function used when signing.
-macro_rules! openssl_cnf_fmt {
-    () => {
-        r#"
-openssl_conf                = default_modules
-
-[default_modules]
-engines                     = engine_section
-oid_section                 = OIDs
-
-[engine_section]
-pkcs11                      = pkcs11_section
-
-[pkcs11_section]
-engine_id                   = pkcs11
-MODULE_PATH                 = {pkcs11_path}
-# add 'debug' to INIT_ARGS
-INIT_ARGS                   = connector=http://127.0.0.1:12345
-init                        = 0
-
-[ ca ]
-default_ca                  = CA_default
-
-[ CA_default ]
-dir                         = ./
-crl_dir                     = $dir/crl
-database                    = $dir/index.txt
-new_certs_dir               = $dir/newcerts
-certificate                 = $dir/ca.cert.pem
-serial                      = $dir/serial
-# key format:   <slot>:<key id>
-private_key                 = 0:{key:04x}
-name_opt                    = ca_default
-cert_opt                    = ca_default
-# certs may be retired, but they won't expire
-default_enddate             = 99991231235959Z
-default_crl_days            = 30
-default_md                  = {hash:?}
-preserve                    = no
-policy                      = policy_match
-email_in_dn                 = no
-# Setting rand_serial to _any_ value, including "no", enables that option
-#rand_serial                = yes
-unique_subject              = no
-
-[ policy_match ]
-countryName                 = supplied
-stateOrProvinceName         = optional
-organizationName            = supplied
-organizationalUnitName      = optional
-commonName                  = supplied
-emailAddress                = optional
-
-[ req ]
-default_md                  = {hash:?}
-string_mask                 = utf8only
-
-[ v3_rot_release_root ]
-subjectKeyIdentifier        = hash
-authorityKeyIdentifier      = keyid:always,issuer
-basicConstraints            = critical,CA:true
-keyUsage                    = critical, keyCertSign, cRLSign
-extendedKeyUsage            = nxpLpc55DebugAuthCredentialSigning
-certificatePolicies         = rotCodeSigningReleasePolicy
-
-[ v3_code_signing_rel ]
-subjectKeyIdentifier        = hash
-authorityKeyIdentifier      = keyid:always,issuer
-basicConstraints            = critical,CA:false
-keyUsage                    = critical, digitalSignature
-extendedKeyUsage            = codeSigning
-certificatePolicies         = rotCodeSigningReleasePolicy
-
-[ v3_rot_development_root ]
-subjectKeyIdentifier        = hash
-authorityKeyIdentifier      = keyid:always,issuer
-basicConstraints            = critical,CA:true
-keyUsage                    = critical, keyCertSign, cRLSign
-extendedKeyUsage            = nxpLpc55DebugAuthCredentialSigning
-certificatePolicies         = rotCodeSigningDevelopmentPolicy
-
-[ v3_code_signing_dev ]
-subjectKeyIdentifier        = hash
-authorityKeyIdentifier      = keyid:always,issuer
-basicConstraints            = critical,CA:false
-keyUsage                    = critical, digitalSignature
-extendedKeyUsage            = codeSigning
-certificatePolicies         = rotCodeSigningDevelopmentPolicy
-
-[ v3_identity ]
-subjectKeyIdentifier        = hash
-authorityKeyIdentifier      = keyid:always,issuer
-basicConstraints            = critical,CA:true
-keyUsage                    = critical, keyCertSign, cRLSign
-certificatePolicies         = critical, deviceIdentityPolicy, tcg-dice-kp-identityInit, tcg-dice-kp-attestInit, tcg-dice-kp-eca
-
-[ OIDs ]
-# https://github.com/oxidecomputer/oana#asn1-object-identifiers
-rotCodeSigningReleasePolicy = 1.3.6.1.4.1.57551.1.1
-rotCodeSigningDevelopmentPolicy = 1.3.6.1.4.1.57551.1.2
-deviceIdentityPolicy = 1.3.6.1.4.1.57551.1.3
-nxpLpc55DebugAuthCredentialSigning = 1.3.6.1.4.1.57551.2.1
-tcg-dice-kp-identityInit = 2.23.133.5.4.100.6
-tcg-dice-kp-attestInit = 2.23.133.5.4.100.8
-tcg-dice-kp-eca = 2.23.133.5.4.100.12
-
-"#
-    };
+impl From<RevokeReason> for CrlReason {
+    fn from(reason: RevokeReason) -> Self {
+        match reason {
+            RevokeReason::Unspecified => CrlReason::Unspecified,
+            RevokeReason::KeyCompromise => CrlReason::KeyCompromise,
+            RevokeReason::CaCompromise => CrlReason::CaCompromise,
+            RevokeReason::AffiliationChanged => CrlReason::AffiliationChanged,
+            RevokeReason::Superseded => CrlReason::Superseded,
+            RevokeReason::CessationOfOperation => {
+                CrlReason::CessationOfOperation
+            }
+            RevokeReason::CertificateHold => CrlReason::CertificateHold,
+            RevokeReason::PrivilegeWithdrawn => CrlReason::PrivilegeWithdrawn,
+        }
+    }
 }
 
-/// Get password for pkcs11 operations to keep the user from having to enter
-/// the password multiple times (once for signing the CSR, one for signing
-/// the cert). We also prefix the password with '0002' so the YubiHSM
-/// PKCS#11 module knows which key to use
-fn passwd_to_env(env_str: &str, password: &Zeroizing<String>) -> Result<()> {
-    use std::ops::Deref;
+/// The private key operations a `Ca` needs from whatever is holding its key:
+/// either the YubiHSM or the sandbox keystore.
+pub trait KeyStore {
+    fn sign_rsa_pkcs1v15_sha256(&self, id: Id, data: &[u8]) -> Result<Vec<u8>>;
 
-    let password = Zeroizing::new(format!("0002:{}", password.deref()));
-    std::env::set_var(env_str, password);
+    /// Sign the provided message digest. The signature is returned DER
+    /// encoded.
+    fn sign_ecdsa_prehash(&self, id: Id, digest: &[u8]) -> Result<Vec<u8>>;
 
-    Ok(())
+    fn sign_ed25519(&self, id: Id, data: &[u8]) -> Result<Vec<u8>>;
+
+    /// Get the DER encoded SubjectPublicKeyInfo for the key.
+    fn public_key_der(&self, id: Id) -> Result<Vec<u8>>;
+
+    /// Get `len` bytes from the RNG of the keystore.
+    fn random_bytes(&self, len: usize) -> Result<Vec<u8>>;
 }
 
-/// Start the yubihsm-connector process.
-/// NOTE: The connector dumps ~10 lines of text for each command.
-/// We can increase verbosity with the `-debug` flag, but the only way
-/// we can dial this down is by sending stderr to /dev/null.
-fn start_connector() -> Result<Child> {
-    debug!("starting connector");
-    let child = Command::new("yubihsm-connector")
-        .stderr(Stdio::null())
-        .stdout(Stdio::null())
-        .spawn()?;
+impl KeyStore for Client {
+    fn sign_rsa_pkcs1v15_sha256(&self, id: Id, data: &[u8]) -> Result<Vec<u8>> {
+        Ok(Client::sign_rsa_pkcs1v15_sha256(self, id, data)?.into_vec())
+    }
+
+    fn sign_ecdsa_prehash(&self, id: Id, digest: &[u8]) -> Result<Vec<u8>> {
+        Ok(Client::sign_ecdsa_prehash_raw(self, id, digest)?)
+    }
+
+    fn sign_ed25519(&self, id: Id, data: &[u8]) -> Result<Vec<u8>> {
+        Ok(Client::sign_ed25519(self, id, data)?.to_bytes().to_vec())
+    }
 
-    // Sleep for a second to allow the connector to start before we start
-    // sending commands to it.
-    std::thread::sleep(std::time::Duration::from_millis(1000));
+    fn public_key_der(&self, id: Id) -> Result<Vec<u8>> {
+        hsm::PublicKey::from_hsm(self, id)?.to_spki_der()
+    }
 
-    Ok(child)
+    // the same RNG `Hsm` uses for its `RngCore` impl, we only have the
+    // `Client` here
+    fn random_bytes(&self, len: usize) -> Result<Vec<u8>> {
+        Ok(self.get_pseudo_random(len)?)
+    }
 }
 
 /// Functions that may return either a PEM encoded cert or CSR do so using
@@ -210,11 +436,303 @@ pub enum CertOrCsr {
     Csr(String),
 }
 
+/// The lpc55 root key table has a slot for each of up to 4 root keys.
+pub const ROOT_KEY_SLOTS: usize = 4;
+
+/// A root key in the lpc55 root key table.
+#[derive(Debug, Serialize)]
+pub struct RootKey {
+    /// The label of the root CA.
+    pub label: String,
+    /// The subject of the root CA cert.
+    pub subject: String,
+    /// The root key hash (RKH) for the key as hex.
+    pub rkh: String,
+}
+
+/// The customer manufacturing programmable area (CMPA) for lpc55 RoTs that
+/// trust the keys of a set of root CAs. The CMPA is left in its erased state
+/// apart from the root key table hash (RKTH).
+#[derive(Debug, Serialize)]
+pub struct Cmpa {
+    /// The root keys in the order of the root key table.
+    pub root_keys: Vec<RootKey>,
+    /// The root key table hash programmed into the CMPA as hex.
+    pub rkth: String,
+    /// The SHA-256 digest of the CMPA as hex.
+    pub sha256: String,
+    /// The CMPA page.
+    #[serde(skip)]
+    pub page: Vec<u8>,
+}
+
+impl Cmpa {
+    /// Compute the root key table hash & the CMPA holding it from the certs
+    /// of the `Ca`s in `root_labels`. The order of the labels is the order
+    /// of the root key table.
+    pub fn new(
+        root_labels: &[String],
+        cas: &HashMap<String, Ca>,
+    ) -> Result<Self> {
+        if root_labels.is_empty() || root_labels.len() > ROOT_KEY_SLOTS {
+            return Err(CaError::BadRootKeyCount {
+                count: root_labels.len(),
+            }
+            .into());
+        }
+
+        let mut certs = Vec::new();
+        let mut root_keys = Vec::new();
+        for (i, label) in root_labels.iter().enumerate() {
+            if root_labels[..i].contains(label) {
+                return Err(CaError::DuplicateRootKey {
+                    label: label.to_string(),
+                }
+                .into());
+            }
+            let ca = cas
+                .get(label)
+                .ok_or(anyhow!("no Ca \"{}\" for root labels", label))?;
+            let cert = ca.cert()?;
+            root_keys.push(RootKey {
+                label: label.to_string(),
+                subject: cert.tbs_certificate.subject.to_string(),
+                rkh: hex::encode(root_key_hash(&cert)?),
+            });
+            certs.push(cert);
+        }
+
+        let rkth = root_key_table_hash(&certs)?;
+        let mut cmpa = lpc55_areas::CMPAPage::default();
+        cmpa.set_rotkh(&rkth);
+        let page = cmpa
+            .to_vec()
+            .map_err(|e| anyhow!("failed to encode CMPA: {:?}", e))?;
+
+        Ok(Self {
+            root_keys,
+            rkth: hex::encode(rkth),
+            sha256: hex::encode(Sha256::digest(&page)),
+            page,
+        })
+    }
+}
+
+/// The offset of the root key table hash (ROTKH) in the lpc55 CMPA.
+pub const CMPA_ROTKH_OFFSET: usize = 0x50;
+
+/// A debug credential for lpc55 RoTs as produced by `Ca::sign_dcsrspec`.
+/// Integers are little endian, RSA keys are the big endian modulus followed
+/// by a 4 byte exponent.
+#[derive(Debug)]
+pub struct DebugCredential {
+    /// The major & minor version: 1.0 for RSA 2048 keys, 1.1 for RSA 4096.
+    pub version: (u16, u16),
+    pub soc_class: u32,
+    pub uuid: [u8; 16],
+    /// The root key hash (RKH) of each key in the root key table.
+    pub root_key_hashes: Vec<[u8; 32]>,
+    /// The key that debug authentication challenges are signed with.
+    pub debug_key: RsaPublicKey,
+    pub cc_socu: u32,
+    pub cc_vu: u32,
+    pub cc_beacon: u32,
+    /// The root key the credential is signed with.
+    pub rot_key: RsaPublicKey,
+    /// The signature, empty for a to-be-signed credential.
+    pub signature: Vec<u8>,
+    /// The length of the signed part of the credential.
+    tbs_len: usize,
+    data: Vec<u8>,
+}
+
+impl DebugCredential {
+    /// Parse the debug credential in `data`.
+    pub fn from_bytes(data: &[u8]) -> Result<Self> {
+        Self::parse(data, true)
+    }
+
+    /// Parse the to-be-signed debug credential in `data`: the credential
+    /// without its signature.
+    pub fn from_tbs(data: &[u8]) -> Result<Self> {
+        Self::parse(data, false)
+    }
+
+    fn parse(data: &[u8], signed: bool) -> Result<Self> {
+        let mut rest = data;
+        let major = u16::from_le_bytes(take(&mut rest)?);
+        let minor = u16::from_le_bytes(take(&mut rest)?);
+        let key_len = match (major, minor) {
+            (1, 0) => 256,
+            (1, 1) => 512,
+            _ => {
+                return Err(CaError::BadDebugCredential {
+                    reason: format!("unsupported version {major}.{minor}"),
+                }
+                .into())
+            }
+        };
+
+        // everything but the root key table has a fixed size
+        let sig_len = if signed { key_len } else { 0 };
+        let fixed = 24 + 2 * (key_len + 4) + 12 + sig_len;
+        let table_len = data.len().checked_sub(fixed).unwrap_or(usize::MAX);
+        let count = table_len / 32;
+        if table_len % 32 != 0 || count == 0 || count > ROOT_KEY_SLOTS {
+            return Err(CaError::BadDebugCredential {
+                reason: format!(
+                    "{} bytes isn't a version {major}.{minor} {}credential",
+                    data.len(),
+                    if signed { "" } else { "to-be-signed " },
+                ),
+            }
+            .into());
+        }
+
+        let soc_class = u32::from_le_bytes(take(&mut rest)?);
+        let uuid = take(&mut rest)?;
+        let mut root_key_hashes = Vec::new();
+        for _ in 0..count {
+            root_key_hashes.push(take(&mut rest)?);
+        }
+        let debug_key = take_rsa_key(&mut rest, key_len)?;
+        let cc_socu = u32::from_le_bytes(take(&mut rest)?);
+        let cc_vu = u32::from_le_bytes(take(&mut rest)?);
+        let cc_beacon = u32::from_le_bytes(take(&mut rest)?);
+        let rot_key = take_rsa_key(&mut rest, key_len)?;
+
+        Ok(Self {
+            version: (major, minor),
+            soc_class,
+            uuid,
+            root_key_hashes,
+            debug_key,
+            cc_socu,
+            cc_vu,
+            cc_beacon,
+            rot_key,
+            signature: rest.to_vec(),
+            tbs_len: data.len() - rest.len(),
+            data: data.to_vec(),
+        })
+    }
+
+    /// The root key table hash (RKTH) for the root key table.
+    pub fn root_key_table_hash(&self) -> [u8; 32] {
+        key_table_hash(&self.root_key_hashes)
+    }
+
+    /// The index of the signing key in the root key table.
+    pub fn rot_key_index(&self) -> Option<usize> {
+        let hash = rsa_key_hash(&self.rot_key);
+        self.root_key_hashes.iter().position(|h| *h == hash)
+    }
+
+    /// Verify that the credential is signed by the key of the `Ca` with
+    /// `cert` & that this key is in the root key table.
+    pub fn verify(&self, name: &str, cert: &Certificate) -> Result<()> {
+        let spki = &cert.tbs_certificate.subject_public_key_info;
+        let key = RsaPublicKey::from_public_key_der(&spki.to_der()?)
+            .context("debug credentials are signed by RSA keys")?;
+        let signer = || CaError::DebugCredentialSigner {
+            name: name.to_string(),
+        };
+        if key != self.rot_key {
+            return Err(signer().into());
+        }
+        let sig = pkcs1v15::Signature::try_from(self.signature.as_slice())?;
+        pkcs1v15::VerifyingKey::<Sha256>::new(key)
+            .verify(&self.data[..self.tbs_len], &sig)
+            .map_err(|_| signer())?;
+
+        if self.rot_key_index().is_none() {
+            return Err(CaError::RootKeyMismatch {
+                reason: "signing key isn't in the root key table".to_string(),
+            }
+            .into());
+        }
+
+        Ok(())
+    }
+
+    /// Check that the root key table holds the keys from the root `certs`
+    /// in the same order.
+    pub fn check_roots(&self, certs: &[Certificate]) -> Result<()> {
+        let mut hashes = Vec::new();
+        for cert in certs {
+            hashes.push(root_key_hash(cert)?);
+        }
+        if hashes != self.root_key_hashes {
+            return Err(CaError::RootKeyMismatch {
+                reason: "root key table differs from the root CA keys"
+                    .to_string(),
+            }
+            .into());
+        }
+
+        Ok(())
+    }
+
+    /// Check that the root key table hash matches the one in `cmpa`.
+    pub fn check_cmpa(&self, cmpa: &[u8]) -> Result<()> {
+        let rotkh = cmpa
+            .get(CMPA_ROTKH_OFFSET..CMPA_ROTKH_OFFSET + 32)
+            .ok_or(anyhow!("CMPA is too short: {} bytes", cmpa.len()))?;
+        if rotkh != self.root_key_table_hash() {
+            return Err(CaError::RootKeyMismatch {
+                reason: "root key table hash differs from the CMPA".to_string(),
+            }
+            .into());
+        }
+
+        Ok(())
+    }
+}
+
+/// Take the next `N` bytes from `data`.
+fn take<const N: usize>(data: &mut &[u8]) -> Result<[u8; N]> {
+    if data.len() < N {
+        return Err(CaError::BadDebugCredential {
+            reason: "truncated".to_string(),
+        }
+        .into());
+    }
+    let (head, tail) = mem::take(data).split_at(N);
+    *data = tail;
+
+    Ok(head.try_into()?)
+}
+
+/// Take the next RSA key with a `len` byte modulus from `data`.
+fn take_rsa_key(data: &mut &[u8], len: usize) -> Result<RsaPublicKey> {
+    if data.len() < len {
+        return Err(CaError::BadDebugCredential {
+            reason: "truncated".to_string(),
+        }
+        .into());
+    }
+    let (n, tail) = mem::take(data).split_at(len);
+    *data = tail;
+    let e: [u8; 4] = take(data)?;
+
+    RsaPublicKey::new(
+        rsa::BigUint::from_bytes_be(n),
+        rsa::BigUint::from_bytes_be(&e),
+    )
+    .map_err(|e| {
+        CaError::BadDebugCredential {
+            reason: format!("bad RSA key: {e}"),
+        }
+        .into()
+    })
+}
+
 /// The `Ca` type represents the collection of files / metadata that is a
 /// certificate authority.
 pub struct Ca {
     root: PathBuf,
     spec: KeySpec,
+    sandbox: bool,
 }
 
 impl Ca {
@@ -230,8 +748,13 @@ impl Ca {
 
         let spec = fs::read_to_string(spec)?;
         let spec = KeySpec::from_str(spec.as_ref())?;
+        let sandbox = root.join(CA_SANDBOX).exists();
 
-        Ok(Self { root, spec })
+        Ok(Self {
+            root,
+            spec,
+            sandbox,
+        })
     }
 
     /// Get the name of the CA in `String` form. A `Ca`s name comes from the
@@ -241,6 +764,22 @@ impl Ca {
         self.spec.label.to_string()
     }
 
+    /// The key spec for the `Ca`s key.
+    pub fn spec(&self) -> &KeySpec {
+        &self.spec
+    }
+
+    /// Returns true if the `Ca`s key is held by the sandbox keystore.
+    pub fn is_sandbox(&self) -> bool {
+        self.sandbox
+    }
+
+    /// Returns true if the `Ca` has a certificate. Intermediate CAs don't
+    /// have one until their CSR has been signed.
+    pub fn has_cert(&self) -> bool {
+        self.root.join(CA_CERT).exists()
+    }
+
     /// Get an `x509_cert::certificate::Certificate` for the `Ca`s
     /// certificate.
     pub fn cert(&self) -> Result<Certificate> {
@@ -248,250 +787,461 @@ impl Ca {
         Ok(Certificate::from_pem(bytes)?)
     }
 
+    /// Get the chain of certs from this `Ca`s cert to the self signed root.
+    /// The cert of each issuer is found among `cas` by its subject & key
+    /// identifier. The chain starts with this `Ca`s cert.
+    pub fn chain(&self, cas: &HashMap<String, Ca>) -> Result<Vec<Certificate>> {
+        let mut chain = vec![self.cert()?];
+        loop {
+            let cert = &chain[chain.len() - 1];
+            let tbs = &cert.tbs_certificate;
+            if tbs.issuer == tbs.subject {
+                return Ok(chain);
+            }
+
+            let key_id = issuer_key_id(cert)?;
+            let mut issuer = None;
+            for ca in cas.values().filter(|ca| ca.has_cert()) {
+                let ca_cert = ca.cert()?;
+                if ca_cert.tbs_certificate.subject == tbs.issuer
+                    && match &key_id {
+                        Some(id) => subject_key_id(&ca_cert)? == *id,
+                        None => true,
+                    }
+                {
+                    issuer = Some(ca_cert);
+                    break;
+                }
+            }
+
+            match issuer {
+                // a cycle of CAs would never reach a root
+                Some(issuer) if !chain.contains(&issuer) => chain.push(issuer),
+                _ => {
+                    return Err(CaError::MissingIssuerCert {
+                        subject: tbs.subject.to_string(),
+                        issuer: tbs.issuer.to_string(),
+                    }
+                    .into())
+                }
+            }
+        }
+    }
+
+    /// Install the PEM encoded `cert` issued for this intermediate `Ca` by
+    /// an external CA, making it ready to issue certs. The cert must be an
+    /// unexpired CA cert for the key held by `keys` & it must chain through
+    /// the PEM encoded certs in `chain`: the cert of its issuer first,
+    /// followed by any certs above it.
+    pub fn import_cert(
+        &self,
+        keys: &dyn KeyStore,
+        cert: &[u8],
+        chain: &[u8],
+    ) -> Result<()> {
+        if self.has_cert() {
+            return Err(CaError::HasCert { name: self.name() }.into());
+        }
+
+        let cert =
+            Certificate::from_pem(cert).context("Failed to parse cert")?;
+        if chain.trim_ascii().is_empty() {
+            return Err(CaError::NoIssuerCert.into());
+        }
+        let chain = Certificate::load_pem_chain(chain)
+            .context("Failed to parse issuer certs")?;
+
+        let tbs = &cert.tbs_certificate;
+        if tbs.subject_public_key_info.to_der()?
+            != keys.public_key_der(self.spec.id)?
+        {
+            return Err(CaError::KeyMismatch { name: self.name() }.into());
+        }
+        if !is_ca_cert(&cert)? {
+            return Err(CaError::NotCaCert {
+                subject: tbs.subject.to_string(),
+            }
+            .into());
+        }
+        let not_after = tbs.validity.not_after.to_date_time();
+        if not_after <= DateTime::from_system_time(SystemTime::now())? {
+            return Err(CaError::CertExpired {
+                subject: tbs.subject.to_string(),
+                not_after,
+            }
+            .into());
+        }
+
+        let mut subject = &cert;
+        for issuer in &chain {
+            verify_issued_by(subject, issuer)?;
+            subject = issuer;
+        }
+
+        info!(
+            "Importing cert for CA {} issued by \"{}\"",
+            self.name(),
+            tbs.issuer
+        );
+        fs::write(
+            self.root.join(CA_CERT),
+            cert.to_pem(LineEnding::default())?,
+        )?;
+
+        Ok(())
+    }
+
     /// Create a new CA instance under `root` & initialize its metadata
-    /// according to the provided keyspec. The `pkcs11_lib` is inserted into
-    /// the generated openssl.cnf so openssl can find it. If the keyspec
-    /// defines a root / selfsigned CA then the self signed cert will be
-    /// written to the output path w/ name `$label.cert.pem`. If not then we
-    /// create a CSR named `$label.csr.pem` instead.
+    /// according to the provided keyspec. The CA key is held by `keys`. If
+    /// the keyspec defines a root / selfsigned CA then the self signed cert
+    /// is returned. If not then we return a CSR instead. Sandbox CAs are
+    /// marked as such in their state directory. The `provenance` of a self
+    /// signed cert is recorded in the issuance database. The keyspec must
+    /// name a CA profile from `profiles`.
     pub fn initialize<P: AsRef<Path>>(
         spec: &KeySpec,
         root: P,
-        pkcs11_lib: P,
-        password: &Zeroizing<String>,
+        keys: &dyn KeyStore,
+        sandbox: bool,
+        provenance: &Provenance,
+        profiles: &Profiles,
     ) -> Result<CertOrCsr> {
-        match spec.purpose {
-            Purpose::RoTReleaseRoot
-            | Purpose::RoTDevelopmentRoot
-            | Purpose::Identity => (),
-            _ => return Err(CaError::BadPurpose.into()),
-        }
-
-        bootstrap_ca_dir(spec, root.as_ref(), pkcs11_lib.as_ref())?;
-
-        // save current pwd so we can return to where we started
-        let pwd = env::current_dir()?;
-        // chdir into `Ca` root directory: openssl.cnf has relative paths
-        env::set_current_dir(&root)?;
-
-        // the connector must be running for the PKCS#11 module to work
-        let connector = start_connector()?;
-        // the PKCS#11 module gets the auth value for the YubiHSM from the
-        // environment
-        passwd_to_env(ENV_CA_PASSWORD, password)?;
-
-        let csr = NamedTempFile::new()?;
-
-        let mut cmd = Command::new("openssl");
-        cmd.arg("req")
-            .arg("-config")
-            .arg("openssl.cnf")
-            .arg("-new")
-            .arg("-subj")
-            .arg(format!(
-                "/C=US/O=Oxide Computer Company/CN={}/",
-                spec.common_name
-            ))
-            .arg("-engine")
-            .arg("pkcs11")
-            .arg("-keyform")
-            .arg("engine")
-            .arg("-key")
-            .arg(format!("0:{:04x}", spec.id))
-            .arg("-passin")
-            .arg(format!("env:{}", ENV_CA_PASSWORD))
-            .arg("-out")
-            .arg(csr.path());
-
-        debug!("executing command: \"{:#?}\"", cmd);
-        let output = match cmd.output() {
-            Ok(o) => o,
-            Err(e) => {
-                teardown_warn_only(connector, pwd);
-                return Err(e.into());
-            }
-        };
-
-        if !output.status.success() {
-            warn!("command failed with status: {}", output.status);
-            warn!("stderr: \"{}\"", String::from_utf8_lossy(&output.stderr));
-            teardown_warn_only(connector, pwd);
-            return Err(CaError::SelfCertGenFail.into());
-        }
-
-        // return the path to the artifact created
-        // if the spec defines a self signed / root cert we'll generate the
-        // cert and return a path to it
-        // else we'll get back the path to the CSR so that it can be exported
-        // and eventually certified by some external process
-        let pem = if spec.self_signed {
-            // sleep to let sessions cycle
-            thread::sleep(Duration::from_millis(1500));
+        let profile = profiles.ca_profile(&spec.profile_name())?;
 
-            info!("Generating self-signed cert for CA root");
-            let mut cmd = Command::new("openssl");
-            cmd.arg("ca")
-                .arg("-batch")
-                .arg("-selfsign")
-                .arg("-notext")
-                .arg("-config")
-                .arg("openssl.cnf")
-                .arg("-engine")
-                .arg("pkcs11")
-                .arg("-keyform")
-                .arg("engine")
-                .arg("-keyfile")
-                .arg(format!("0:{:04x}", spec.id))
-                .arg("-extensions")
-                .arg(spec.purpose.to_string())
-                .arg("-passin")
-                .arg("env:OKM_HSM_PKCS11_AUTH")
-                .arg("-in")
-                .arg(csr.path())
-                .arg("-out")
-                .arg(CA_CERT)
-                .output()?;
-
-            debug!("executing command: \"{:#?}\"", cmd);
-            let output = match cmd
-                .output()
-                .context("Failed to self sign cert with `openssl ca`")
-            {
-                Ok(o) => o,
-                Err(e) => {
-                    teardown_warn_only(connector, pwd);
-                    return Err(e);
-                }
-            };
+        bootstrap_ca_dir(spec, root.as_ref(), sandbox)?;
+        let ca = Ca::load(root.as_ref())?;
 
-            if !output.status.success() {
-                warn!("command failed with status: {}", output.status);
-                warn!(
-                    "stderr: \"{}\"",
-                    String::from_utf8_lossy(&output.stderr)
-                );
-                teardown_warn_only(connector, pwd);
-                return Err(CaError::SelfCertGenFail.into());
-            }
+        let spki = SubjectPublicKeyInfoOwned::from_der(
+            &keys.public_key_der(spec.id)?,
+        )?;
+        let subject = ca.subject()?;
 
-            let cert_pem =
-                fs::read_to_string(root.as_ref().to_path_buf().join(CA_CERT))?;
-            CertOrCsr::Cert(cert_pem)
+        // if the spec defines a self signed / root cert we'll generate the
+        // cert and return it
+        // else we'll return the CSR so that it can be exported and
+        // eventually certified by some external process
+        if spec.self_signed {
+            info!("Generating self-signed cert for CA root");
+            let cert = ca.issue(
+                keys,
+                subject,
+                spki,
+                profile,
+                spec.validity.as_ref(),
+                provenance,
+            )?;
+
+            let cert_pem = cert.to_pem(LineEnding::default())?;
+            fs::write(root.as_ref().join(CA_CERT), &cert_pem)?;
+            Ok(CertOrCsr::Cert(cert_pem))
         } else {
-            // self-signed=false in keyspec indicates that the CA being
-            // initialized is an intermediate: someone else has to certify it
-            // so we copy the CSR to output
-            let csr_pem = fs::read_to_string(csr.path())?;
-            CertOrCsr::Csr(csr_pem)
+            info!("Generating CSR for intermediate CA");
+            let csr = ca.csr(keys, subject, spki)?;
+            Ok(CertOrCsr::Csr(csr.to_pem(LineEnding::default())?))
+        }
+    }
+
+    /// Create a CSR for `subject` & the key in `spki`, signed with this
+    /// `Ca`s key held by `keys`.
+    fn csr(
+        &self,
+        keys: &dyn KeyStore,
+        subject: Name,
+        spki: SubjectPublicKeyInfoOwned,
+    ) -> Result<CertReq> {
+        let algorithm = self.signature_algorithm()?;
+        let info = CertReqInfo {
+            version: request::Version::V1,
+            subject,
+            public_key: spki,
+            attributes: SetOfVec::new(),
         };
+        let signature = self.sign(keys, &info.to_der()?)?;
 
-        teardown_warn_only(connector, pwd);
-        Ok(pem)
+        Ok(CertReq {
+            info,
+            algorithm,
+            signature: BitString::from_bytes(&signature)?,
+        })
     }
 
-    /// Sign the CSR from the provided CsrSpec. The cert produced is returned
-    /// as a PEM encoded x509 cert.
+    /// Sign the CSR from the provided CsrSpec with the key held by `keys`.
+    /// The cert produced is returned as a PEM encoded x509 cert and recorded
+    /// in the issuance database along with its `provenance`. The cert gets
+    /// the profile from the CsrSpec, which the issuance rules in `profiles`
+    /// must allow this `Ca` to issue.
     pub fn sign_csrspec(
         &self,
         spec: &CsrSpec,
-        password: &Zeroizing<String>,
+        keys: &dyn KeyStore,
+        provenance: &Provenance,
+        profiles: &Profiles,
+        cas: &HashMap<String, Ca>,
     ) -> Result<Vec<u8>> {
-        // map purpose of CA key to key associated with CSR
-        // this is awkward and should be revisited
-        let purpose = match self.spec.purpose {
-            Purpose::RoTReleaseRoot => Purpose::RoTReleaseCodeSigning,
-            Purpose::RoTDevelopmentRoot => Purpose::RoTDevelopmentCodeSigning,
-            Purpose::Identity => Purpose::Identity,
-            _ => return Err(CaError::BadPurpose.into()),
+        let (csr, subject, profile) =
+            self.check_csrspec(spec, profiles, cas, Some(keys))?;
+
+        info!(
+            "Generating cert from CSR & signing with key: {}",
+            self.name()
+        );
+        let cert = self.issue(
+            keys,
+            subject,
+            csr.info.public_key.clone(),
+            profile,
+            spec.validity.as_ref(),
+            provenance,
+        )?;
+        let serial = serial_hex(&cert.tbs_certificate.serial_number);
+        debug!(
+            "Successfully signed CsrSpec producing cert with serial {serial}"
+        );
+        let pem = cert.to_pem(LineEnding::default())?;
+
+        // As in `issue` the cert *MUST* be returned to the caller even if it
+        // isn't what we expected: problems are reported loudly so that the
+        // cert can be revoked but they aren't errors.
+        match self.verify_issued(&pem, &csr.info.public_key, profile) {
+            Ok(problems) if problems.is_empty() => {
+                debug!("verified cert with serial {serial}")
+            }
+            Ok(problems) => {
+                error!(
+                    "VERIFICATION FAILED for cert with serial {serial} \
+                    issued by CA {}",
+                    self.name()
+                );
+                for problem in problems {
+                    error!("  {problem}");
+                }
+            }
+            Err(e) => error!(
+                "Failed to verify cert with serial {serial} issued by CA {}: \
+                {:#}",
+                self.name(),
+                e
+            ),
+        }
+
+        Ok(pem.into_bytes())
+    }
+
+    /// Check the PEM encoded `cert` this `Ca` issued for the key in `spki`
+    /// with `profile`. The cert must be signed by this `Ca`, name it as the
+    /// issuer, be for `spki` & have exactly the extensions from `profile`.
+    /// Returns a description of each problem found.
+    fn verify_issued(
+        &self,
+        cert: &str,
+        spki: &SubjectPublicKeyInfoOwned,
+        profile: &Profile,
+    ) -> Result<Vec<String>> {
+        let cert = Certificate::from_pem(cert.as_bytes())?;
+        let ca_cert = self.cert()?;
+        let tbs = &cert.tbs_certificate;
+        let mut problems = Vec::new();
+
+        if let Err(e) = verify_issued_by(&cert, &ca_cert) {
+            problems.push(format!("{:#}", e));
+        }
+        if tbs.subject_public_key_info != *spki {
+            problems
+                .push("subject public key isn't the key from the CSR".into());
+        }
+
+        let issuer_key_id = subject_key_id(&ca_cert)?;
+        match self::issuer_key_id(&cert)? {
+            Some(id) if id == issuer_key_id => (),
+            _ => problems.push(
+                "authority key identifier isn't the CA's key identifier".into(),
+            ),
+        }
+
+        // The extensions are checked against the profile rather than the
+        // output of `extensions` so that a mistake there is caught here.
+        let actual = tbs.extensions.as_deref().unwrap_or_default();
+        let mut problem = |ext: &str, problem: &str| {
+            problems.push(format!(
+                "{ext} {problem} for profile \"{}\"",
+                profile.name
+            ))
         };
+        let mut allowed = vec![
+            AuthorityKeyIdentifier::OID,
+            SubjectKeyIdentifier::OID,
+            BasicConstraints::OID,
+            KeyUsage::OID,
+        ];
+        if !profile.extended_key_usage.is_empty() {
+            allowed.push(ExtendedKeyUsage::OID);
+        }
+        if !profile.policies.is_empty() {
+            allowed.push(CertificatePolicies::OID);
+        }
+        if profile.name_constraints.is_some() {
+            allowed.push(NameConstraints::OID);
+        }
+
+        // RFC 5280 section 4.2.1.2 method 1
+        let key_id = Sha1::digest(spki.subject_public_key.raw_bytes());
+        match find_extension::<SubjectKeyIdentifier>(actual)? {
+            Some((false, ski)) if ski.0.as_bytes() == &key_id[..] => (),
+            Some(_) => problem("subject key identifier", "isn't the key hash"),
+            None => problem("subject key identifier", "is missing"),
+        }
 
-        // chdir to CA state directory as required to run `openssl ca`
-        let pwd = std::env::current_dir()?;
-        std::env::set_current_dir(&self.root)?;
+        match find_extension::<BasicConstraints>(actual)? {
+            Some((true, bc))
+                if bc.ca == profile.ca
+                    && bc.path_len_constraint == profile.path_len => {}
+            Some(_) => problem("basic constraints", "are wrong"),
+            None => problem("basic constraints", "are missing"),
+        }
 
-        // create a tempdir & write CSR there for openssl: AFAIK the `ca` command
-        // won't take the CSR over stdin
-        let csr = NamedTempFile::new()?;
-        debug!("writing CSR to: {}", csr.path().display());
-        fs::write(&csr, &spec.csr)?;
+        let usage = find_extension::<KeyUsage>(actual)?
+            .map(|(critical, usage)| (critical, key_usage_bits(&usage)));
+        match usage {
+            Some((true, bits)) if same_items(&bits, &profile.key_usage) => (),
+            Some(_) => problem("key usage", "is wrong"),
+            None => problem("key usage", "is missing"),
+        }
 
-        // sleep to let sessions cycle
-        thread::sleep(Duration::from_millis(2500));
+        match find_extension::<ExtendedKeyUsage>(actual)? {
+            None if profile.extended_key_usage.is_empty() => (),
+            Some((false, eku))
+                if same_items(&eku.0, &profile.extended_key_usage) => {}
+            Some(_) => problem("extended key usage", "is wrong"),
+            None => problem("extended key usage", "is missing"),
+        }
 
-        info!(
-            "Generating cert from CSR & signing with key: {}",
-            self.name()
+        let policies = find_extension::<CertificatePolicies>(actual)?.map(
+            |(critical, policies)| {
+                let oids: Vec<ObjectIdentifier> =
+                    policies.0.iter().map(|p| p.policy_identifier).collect();
+                (critical, oids)
+            },
         );
+        match policies {
+            None if profile.policies.is_empty() => (),
+            Some((critical, oids))
+                if critical == profile.policies_critical
+                    && same_items(&oids, &profile.policies) => {}
+            Some(_) => problem("certificate policies", "are wrong"),
+            None => problem("certificate policies", "are missing"),
+        }
 
-        let cert = NamedTempFile::new()?;
-
-        let connector = start_connector()?;
-        passwd_to_env(ENV_CA_PASSWORD, password)?;
-
-        let mut cmd = Command::new("openssl");
-        cmd.arg("ca")
-            .arg("-batch")
-            .arg("-notext")
-            .arg("-config")
-            .arg("openssl.cnf")
-            .arg("-engine")
-            .arg("pkcs11")
-            .arg("-keyform")
-            .arg("engine")
-            .arg("-keyfile")
-            .arg(format!("0:{:04x}", self.spec.id))
-            .arg("-extensions")
-            .arg(purpose.to_string())
-            .arg("-passin")
-            .arg(format!("env:{}", ENV_CA_PASSWORD))
-            .arg("-in")
-            .arg(csr.path())
-            .arg("-out")
-            .arg(cert.path());
-
-        debug!("executing command: \"{:#?}\"", cmd);
-        let output = match cmd.output() {
-            Ok(o) => o,
-            Err(e) => {
-                teardown_warn_only(connector, pwd);
-                return Err(e.into());
-            }
-        };
-
-        if !output.status.success() {
-            warn!("command failed with status: {}", output.status);
-            warn!("stderr: \"{}\"", String::from_utf8_lossy(&output.stderr));
-            teardown_warn_only(connector, pwd);
-            return Err(CaError::CertGenFail.into());
-        } else {
-            debug!(
-                "Successfully signed CsrSpec \"{}\" producing cert \"{}\"",
-                csr.path().display(),
-                cert.path().display()
-            );
+        match find_extension::<NameConstraints>(actual)? {
+            None if profile.name_constraints.is_none() => (),
+            Some((true, _)) if profile.name_constraints.is_some() => (),
+            Some(_) => problem("name constraints", "are wrong"),
+            None => problem("name constraints", "are missing"),
         }
 
-        teardown_warn_only(connector, pwd);
-        fs::read(cert.path()).with_context(|| {
-            format!("failed to read file {}", cert.as_ref().display())
-        })
+        for custom in &profile.extensions {
+            allowed.push(custom.oid);
+            match actual.iter().find(|e| e.extn_id == custom.oid) {
+                Some(e)
+                    if e.critical == custom.critical
+                        && e.extn_value.as_bytes() == custom.value => {}
+                Some(_) => {
+                    problem(&format!("extension {}", custom.oid), "is wrong")
+                }
+                None => {
+                    problem(&format!("extension {}", custom.oid), "is missing")
+                }
+            }
+        }
+
+        for ext in actual {
+            if !allowed.contains(&ext.extn_id) {
+                problem(&format!("extension {}", ext.extn_id), "isn't allowed");
+            }
+        }
+
+        Ok(problems)
+    }
+
+    /// Check the CSR from `spec` against the rules for CSRs signed by this
+    /// `Ca`, see `check_csr`. Returns the CSR, the subject for the cert & the
+    /// profile from `profiles` it would be issued with.
+    pub fn check_csrspec<'a>(
+        &self,
+        spec: &CsrSpec,
+        profiles: &'a Profiles,
+        cas: &HashMap<String, Ca>,
+        keys: Option<&dyn KeyStore>,
+    ) -> Result<(CertReq, Name, &'a Profile)> {
+        let profile = profiles.issued_profile(
+            &self.spec.profile_name(),
+            spec.profile.as_deref(),
+        )?;
+
+        let csr = CertReq::from_pem(spec.csr.as_bytes())
+            .context("Failed to parse CSR from CsrSpec")?;
+        // without a cert `issue` would produce a self signed cert
+        if !self.has_cert() {
+            return Err(CaError::NoCaCert { name: self.name() }.into());
+        }
+        let subject = check_csr(
+            &csr,
+            profile,
+            &self.spec,
+            &self.cert()?.tbs_certificate.subject,
+            cas,
+            keys,
+        )?;
+
+        Ok((csr, subject, profile))
     }
 
     /// Sign the debug credential signing request from the provided DcsrSpec.
     /// This function uses the provided HashMap to find the `Ca`s whose public
-    /// keys are to be included in the debug credential.
+    /// keys are to be included in the debug credential. The signature comes
+    /// from the `KeyStore` holding this `Ca`s key. The credential is checked
+    /// against the debug credential policy from this `Ca`s profile in
+    /// `profiles` before it's signed.
     pub fn sign_dcsrspec(
         &self,
         spec: DcsrSpec,
         cas: &HashMap<String, Ca>,
-        client: &Client,
+        keys: &dyn KeyStore,
+        profiles: &Profiles,
     ) -> Result<Vec<u8>> {
         debug!("signing DcsrSpec: {:?}", spec);
+        let dc_tbs = self.dcsr_tbs(spec, cas, profiles)?;
+
+        // Sign it using the private key for this `Ca`.
+        let dc_sig = keys.sign_rsa_pkcs1v15_sha256(self.spec.id, &dc_tbs)?;
+
+        // Append the signature to the TBS debug credential to make a complete debug
+        // credential
+        let mut dc = Vec::new();
+        dc.extend_from_slice(&dc_tbs);
+        dc.extend_from_slice(&dc_sig);
+
+        Ok(dc)
+    }
+
+    /// Construct the to-be-signed debug credential for the provided DcsrSpec
+    /// & check it against the debug credential policy, see `sign_dcsrspec`.
+    pub fn dcsr_tbs(
+        &self,
+        spec: DcsrSpec,
+        cas: &HashMap<String, Ca>,
+        profiles: &Profiles,
+    ) -> Result<Vec<u8>> {
+        let mut labels = Vec::new();
+        for label in &spec.root_labels {
+            labels.push(label.try_as_str()?.to_string());
+        }
+        check_dcsr_roots(&self.name(), &labels)?;
+
         // Collect certs for the 4 trust anchors listed in the `root_labels`.
         // These are the 4 trust anchors trusted by the lpc55 verified boot.
         let mut certs: Vec<Certificate> = Vec::new();
-        for label in spec.root_labels {
-            let ca = cas.get(label.try_as_str()?).ok_or(anyhow!(
+        for label in &labels {
+            let ca = cas.get(label).ok_or(anyhow!(
                 "no Ca \"{}\" for DcsrSpec root labels",
                 label
             ))?;
@@ -509,98 +1259,2800 @@ impl Ca {
             signer_public_key,
             spec.dcsr,
         )?;
+        self.check_dcsr(&dc_tbs, profiles)?;
 
-        // Sign it using the private key stored in the HSM.
-        let dc_sig = client.sign_rsa_pkcs1v15_sha256(self.spec.id, &dc_tbs)?;
+        Ok(dc_tbs)
+    }
 
-        // Append the signature to the TBS debug credential to make a complete debug
-        // credential
-        let mut dc = Vec::new();
-        dc.extend_from_slice(&dc_tbs);
-        dc.extend_from_slice(&dc_sig.into_vec());
+    /// Check the to-be-signed debug credential `tbs` against the debug
+    /// credential policy from this `Ca`s profile. The first rule broken is
+    /// reported.
+    fn check_dcsr(&self, tbs: &[u8], profiles: &Profiles) -> Result<()> {
+        let reject = |rule, reason| CaError::DcsrRejected { rule, reason };
+
+        let profile = profiles.ca_profile(&self.spec.profile_name())?;
+        let policy = profile.debug_credentials.as_ref().ok_or_else(|| {
+            reject(
+                DcsrRule::Signer,
+                format!(
+                    "profile \"{}\" doesn't allow debug credentials",
+                    profile.name
+                ),
+            )
+        })?;
+
+        let dc = DebugCredential::from_tbs(tbs)?;
+        let bits = dc.debug_key.size() * 8;
+        let signer_bits = dc.rot_key.size() * 8;
+        if bits != signer_bits {
+            return Err(reject(
+                DcsrRule::KeySize,
+                format!(
+                    "{} bit debug key, the signing key is {} bits",
+                    bits, signer_bits
+                ),
+            )
+            .into());
+        }
+        if let Some(min) = policy.min_key_bits {
+            if bits < min {
+                return Err(reject(
+                    DcsrRule::KeySize,
+                    format!(
+                        "{} bit debug key, at least {} required",
+                        bits, min
+                    ),
+                )
+                .into());
+            }
+        }
 
-        Ok(dc)
+        if let Some(allowed) = policy.permissions {
+            let denied = dc.cc_socu & !allowed;
+            if denied != 0 {
+                return Err(reject(
+                    DcsrRule::Permissions,
+                    format!("permissions {:#010x} aren't allowed", denied),
+                )
+                .into());
+            }
+        }
+
+        if let Some(uuids) = &policy.uuids {
+            if !uuids.contains(&DeviceUuid(dc.uuid)) {
+                return Err(reject(
+                    DcsrRule::Uuid,
+                    format!("device {} isn't allowed", DeviceUuid(dc.uuid)),
+                )
+                .into());
+            }
+        }
+
+        Ok(())
     }
-}
 
-/// This utility function is used to create the directory structure required
-/// for the CA.
-fn bootstrap_ca_dir<P: AsRef<Path>>(
-    spec: &KeySpec,
-    root: P,
-    pkcs11_lib: P,
-) -> Result<()> {
-    fs::create_dir_all(&root).with_context(|| {
-        format!("Failed to create directory \"{}\"", root.as_ref().display())
-    })?;
+    /// Sign the lpc55 `image` described by the `ImageSpec`. The PEM encoded
+    /// `cert_chain` must start with the cert for one of the root keys from
+    /// the `ImageSpec` & end with the cert for this `Ca`s key. Returns the
+    /// signed image.
+    pub fn sign_imagespec(
+        &self,
+        spec: &ImageSpec,
+        image: Vec<u8>,
+        cert_chain: &[u8],
+        cas: &HashMap<String, Ca>,
+        keys: &dyn KeyStore,
+    ) -> Result<Vec<u8>> {
+        debug!("signing ImageSpec: {:?}", spec);
+        let stamped = self.image_tbs(spec, image, cert_chain, cas)?;
 
-    // save current pwd so we can return to where we started
-    let pwd = env::current_dir()?;
-    env::set_current_dir(&root)?;
+        // Sign it using the private key for this `Ca`.
+        let sig = keys.sign_rsa_pkcs1v15_sha256(self.spec.id, &stamped)?;
 
-    // copy the key spec file to the ca state dir
-    let spec_json = spec
-        .to_json()
-        .context("Failed to serialize KeySpec to json")?;
-    fs::write(CA_KEY_SPEC, spec_json)?;
+        // Append the signature to the stamped image to make the signed image
+        let mut signed = stamped;
+        signed.extend_from_slice(&sig);
 
-    // create directories expected by `openssl ca`
-    for dir in ["crl", "newcerts", "csr", "private"] {
-        fs::create_dir(dir)
-            .with_context(|| format!("Failed to create directory: {}", dir))?;
-        if dir == "private" {
-            let perms = Permissions::from_mode(0o700);
-            debug!("setting permissions on directory {} to {:#?}", dir, perms);
-            fs::set_permissions(dir, perms)?;
-        }
+        Ok(signed)
     }
 
-    // touch 'index.txt' file
-    let index = "index.txt";
-    OpenOptions::new()
-        .create(true)
-        .truncate(true)
-        .write(true)
-        .open(index)?;
-
-    // write initial serial number to 'serial' (echo 1000 > serial)
-    let serial = "serial";
-    let init_serial_hex = format!("{:020x}", spec.initial_serial_number);
+    /// Check the `cert_chain` for the lpc55 `image` described by the
+    /// `ImageSpec` & add it to the image, see `sign_imagespec`. Returns the
+    /// image to be signed.
+    pub fn image_tbs(
+        &self,
+        spec: &ImageSpec,
+        image: Vec<u8>,
+        cert_chain: &[u8],
+        cas: &HashMap<String, Ca>,
+    ) -> Result<Vec<u8>> {
+        // Collect certs for the trust anchors listed in the `root_labels`.
+        // These are the trust anchors trusted by the lpc55 verified boot.
+        let mut roots: Vec<Certificate> = Vec::new();
+        for label in &spec.root_labels {
+            let ca = cas.get(label.try_as_str()?).ok_or(anyhow!(
+                "no Ca \"{}\" for ImageSpec root labels",
+                label
+            ))?;
+            roots.push(ca.cert()?);
+        }
+        let roots = roots;
+
+        // The lpc55 ROM checks that the first cert is for a key in the root
+        // key table & that the image is signed by the key in the last.
+        if cert_chain.trim_ascii().is_empty() {
+            return Err(CaError::EmptyImageChain.into());
+        }
+        let certs = Certificate::load_pem_chain(cert_chain)?;
+        let (first, last) = match (certs.first(), certs.last()) {
+            (Some(first), Some(last)) => (first, last),
+            _ => return Err(CaError::EmptyImageChain.into()),
+        };
+        if !roots.iter().any(|root| {
+            root.tbs_certificate.subject_public_key_info
+                == first.tbs_certificate.subject_public_key_info
+        }) {
+            return Err(CaError::UntrustedImageChain.into());
+        }
+        if last.tbs_certificate.subject_public_key_info
+            != self.cert()?.tbs_certificate.subject_public_key_info
+        {
+            return Err(CaError::KeyMismatch { name: self.name() }.into());
+        }
+
+        // Add the cert block to the image & fix up its header.
+        Ok(lpc55_sign::signed_image::stamp_image(
+            image,
+            certs,
+            roots,
+            spec.execution_address,
+        )?)
+    }
+
+    /// The subject name used in the cert / CSR for this `Ca`s key, see
+    /// `ca_subject`.
+    fn subject(&self) -> Result<Name> {
+        ca_subject(&self.spec, self.sandbox)
+    }
+
+    /// The algorithm identifier for signatures made by this `Ca`s key.
+    fn signature_algorithm(&self) -> Result<AlgorithmIdentifierOwned> {
+        use asymmetric::Algorithm::*;
+
+        let (oid, parameters) = match (self.spec.algorithm, self.spec.hash) {
+            (Rsa2048 | Rsa3072 | Rsa4096, Some(Hash::Sha256)) => {
+                (OID_SHA256_WITH_RSA, Some(Any::null()))
+            }
+            (EcP256 | EcP384 | EcP521, Some(Hash::Sha256)) => {
+                (OID_ECDSA_WITH_SHA256, None)
+            }
+            (EcP256 | EcP384 | EcP521, Some(Hash::Sha384)) => {
+                (OID_ECDSA_WITH_SHA384, None)
+            }
+            (EcP256 | EcP384 | EcP521, Some(Hash::Sha512)) => {
+                (OID_ECDSA_WITH_SHA512, None)
+            }
+            (Ed25519, None) => (OID_ED25519, None),
+            (algorithm, hash) => {
+                return Err(
+                    CaError::UnsupportedSignature { algorithm, hash }.into()
+                )
+            }
+        };
+
+        Ok(AlgorithmIdentifierOwned { oid, parameters })
+    }
+
+    /// Sign `data` with this `Ca`s key using the algorithm from
+    /// `signature_algorithm`.
+    fn sign(&self, keys: &dyn KeyStore, data: &[u8]) -> Result<Vec<u8>> {
+        use asymmetric::Algorithm::*;
+
+        match (self.spec.algorithm, self.spec.hash) {
+            (Rsa2048 | Rsa3072 | Rsa4096, Some(Hash::Sha256)) => {
+                keys.sign_rsa_pkcs1v15_sha256(self.spec.id, data)
+            }
+            (EcP256 | EcP384 | EcP521, Some(hash)) => {
+                keys.sign_ecdsa_prehash(self.spec.id, &digest(hash, data))
+            }
+            (Ed25519, None) => keys.sign_ed25519(self.spec.id, data),
+            (algorithm, hash) => {
+                Err(CaError::UnsupportedSignature { algorithm, hash }.into())
+            }
+        }
+    }
+
+    /// Issue a cert for `spki` with the extensions from `profile` & sign it
+    /// with this `Ca`s key. A `Ca` without a cert issues its own self signed
+    /// cert. The serial number is taken from the CA state before signing so
+    /// that it's never reused, even if something goes wrong later on.
+    fn issue(
+        &self,
+        keys: &dyn KeyStore,
+        subject: Name,
+        spki: SubjectPublicKeyInfoOwned,
+        profile: &Profile,
+        validity: Option<&ValiditySpec>,
+        provenance: &Provenance,
+    ) -> Result<Certificate> {
+        // the issuance database in the YubiHSM has room for so many certs &
+        // we have to find out before signing, not when storing the CA state
+        if !self.sandbox {
+            let certs = self.issued()?.len();
+            if certs >= OPAQUE_ISSUED_MAX {
+                return Err(CaError::IssuedFull {
+                    name: self.name(),
+                    certs,
+                }
+                .into());
+            }
+        }
+
+        let (issuer, issuer_key_id, issuer_not_after) = if self.has_cert() {
+            let ca_cert = self.cert()?;
+            let key_id = subject_key_id(&ca_cert)?;
+            let tbs = ca_cert.tbs_certificate;
+            (
+                tbs.subject,
+                key_id,
+                Some(tbs.validity.not_after.to_date_time()),
+            )
+        } else {
+            (subject.clone(), key_identifier(&spki), None)
+        };
+        let validity = cert_validity(profile, validity, issuer_not_after)?;
+        let algorithm = self.signature_algorithm()?;
+        let extensions = extensions(profile, &spki, &issuer_key_id)?;
+        let serial_number = self.next_serial(keys)?;
+
+        let tbs_certificate = TbsCertificate {
+            version: Version::V3,
+            serial_number,
+            signature: algorithm.clone(),
+            issuer,
+            validity,
+            subject,
+            subject_public_key_info: spki,
+            issuer_unique_id: None,
+            subject_unique_id: None,
+            extensions: Some(extensions),
+        };
+
+        let signature = self.sign(keys, &tbs_certificate.to_der()?)?;
+        let cert = Certificate {
+            tbs_certificate,
+            signature_algorithm: algorithm,
+            signature: BitString::from_bytes(&signature)?,
+        };
+
+        // Once we've signed something it *MUST* be returned to the caller:
+        // anything we sign must be accounted for and an error here would
+        // lose the cert. Failures recording the cert are logged here & the
+        // caller finds them with `check_recorded` once the cert is safe.
+        if let Err(e) = self.record_issued(&cert, &profile.name, provenance) {
+            error!(
+                "Failed to record cert with serial {} in CA database: {}",
+                serial_hex(&cert.tbs_certificate.serial_number),
+                e
+            );
+        }
+
+        Ok(cert)
+    }
+
+    /// Get the sequential serial number for the next cert issued by this
+    /// `Ca` without advancing it.
+    pub fn peek_serial(&self) -> Result<BigUint> {
+        let serial = fs::read_to_string(self.root.join(CA_SERIAL))?;
+        Ok(parse_serial(&serial).map_err(|_| CaError::BadSerial {
+            serial: serial.trim().to_string(),
+        })?)
+    }
+
+    /// Get the serial number for the next cert issued by this `Ca` according
+    /// to its serial policy. Sequential serial numbers are advanced in the
+    /// CA state & it's an error if the issuance database already has one.
+    /// Random serial numbers come from the RNG of `keys` & are drawn again
+    /// if the issuance database already has one.
+    fn next_serial(&self, keys: &dyn KeyStore) -> Result<SerialNumber> {
+        let prefix = match &self.spec.serial_policy {
+            None | Some(SerialPolicy::Sequential) => {
+                let path = self.root.join(CA_SERIAL);
+                let serial = self.peek_serial()?;
+                if self.has_issued(&format!("{:X}", serial))? {
+                    return Err(CaError::SerialReused {
+                        name: self.name(),
+                        serial: format!("{:X}", serial),
+                    }
+                    .into());
+                }
+                let serial_number = SerialNumber::new(&serial.to_bytes_be())?;
+
+                let next = format!("{:020x}", serial + 1u32);
+                debug!("advancing serial number to \"{next}\"");
+                fs::write(path, next)?;
+
+                return Ok(serial_number);
+            }
+            Some(SerialPolicy::Random) => &[][..],
+            Some(SerialPolicy::Prefix(prefix)) => prefix.as_slice(),
+        };
+
+        // serial numbers are positive so the first byte of the DER integer
+        // must not have the sign bit set: `SerialPolicy::from_str` rejects
+        // prefixes that do, or that are too long, but the policy may not
+        // have come from a string. We clear the sign bit when the first
+        // byte is random.
+        if prefix.first().copied().unwrap_or(0) >= 0x80
+            || prefix.len() > SERIAL_LEN - SERIAL_MIN_RANDOM
+        {
+            return Err(CaError::BadSerialPolicy {
+                name: self.name(),
+                prefix: hex::encode(prefix),
+            }
+            .into());
+        }
+        for _ in 0..SERIAL_ATTEMPTS {
+            let mut bytes = prefix.to_vec();
+            bytes.extend(keys.random_bytes(SERIAL_LEN - prefix.len())?);
+            if prefix.is_empty() {
+                bytes[0] &= 0x7f;
+            }
+            let serial = BigUint::from_bytes_be(&bytes);
+            let hex = format!("{:X}", serial);
+            if serial.bits() == 0 || self.has_issued(&hex)? {
+                warn!("drew serial number {} already issued by CA", hex);
+                continue;
+            }
+            debug!("drew serial number \"{hex}\"");
+
+            return Ok(SerialNumber::new(&serial.to_bytes_be())?);
+        }
+
+        Err(CaError::SerialCollision {
+            name: self.name(),
+            attempts: SERIAL_ATTEMPTS,
+        }
+        .into())
+    }
+
+    /// Get the number for the next CRL issued by this `Ca` and advance the
+    /// CRL number in the CA state. If the CA state has no CRL number we
+    /// start at 1.
+    fn next_crl_number(&self) -> Result<BigUint> {
+        let path = self.root.join(CA_CRL_NUMBER);
+        let number = if path.exists() {
+            let number = fs::read_to_string(&path)?;
+            parse_serial(&number).map_err(|_| CaError::BadSerial {
+                serial: number.trim().to_string(),
+            })?
+        } else {
+            BigUint::from(1u32)
+        };
+
+        let next = format!("{:02x}", &number + 1u32);
+        debug!("advancing CRL number to \"{next}\"");
+        fs::write(path, next)?;
+
+        Ok(number)
+    }
+
+    /// Record a cert issued by this `Ca` in the issuance database & keep a
+    /// copy in the `newcerts` directory.
+    fn record_issued(
+        &self,
+        cert: &Certificate,
+        profile: &str,
+        provenance: &Provenance,
+    ) -> Result<()> {
+        let tbs = &cert.tbs_certificate;
+        let serial = serial_hex(&tbs.serial_number);
+
+        let path = self.root.join(NEWCERTS_DIR).join(format!("{serial}.pem"));
+        debug!("writing issued cert to: {}", path.display());
+        fs::write(path, cert.to_pem(LineEnding::default())?)?;
+
+        self.issuance_db()?
+            .add(IssuedCert::new(cert, profile, provenance)?)
+    }
+
+    /// The database of certs issued by this `Ca`. CA state directories from
+    /// before the issuance database must be imported with
+    /// `IssuanceDb::import_index` first.
+    fn issuance_db(&self) -> Result<IssuanceDb> {
+        let db = IssuanceDb::new(&self.root);
+        if !db.exists() {
+            return Err(CaError::NoIssuanceDb { name: self.name() }.into());
+        }
+
+        Ok(db)
+    }
+
+    /// Create the issuance database for this `Ca` from the `openssl ca`
+    /// database in its state directory. Returns false if the `Ca` already
+    /// has an issuance database.
+    pub fn import_index(&self) -> Result<bool> {
+        if IssuanceDb::new(&self.root).exists() {
+            return Ok(false);
+        }
+
+        IssuanceDb::import_index(&self.root)?;
+        Ok(true)
+    }
+
+    /// Get the records of every cert issued by this `Ca` in the order they
+    /// were issued.
+    pub fn issued(&self) -> Result<Vec<IssuedCert>> {
+        self.issuance_db()?.read()
+    }
+
+    /// Get the record for the cert issued by this `Ca` with the given serial
+    /// number. The serial number is hex encoded.
+    pub fn issued_cert(&self, serial: &str) -> Result<Option<IssuedCert>> {
+        self.issuance_db()?.get(serial)
+    }
+
+    /// Returns true if this `Ca` has issued a cert with the given serial
+    /// number. The serial number is hex encoded.
+    pub fn has_issued(&self, serial: &str) -> Result<bool> {
+        Ok(self.issued_cert(serial)?.is_some())
+    }
+
+    /// Check that the PEM encoded `cert` issued by this `Ca` is in its
+    /// issuance database. Certs that `issue` failed to record are still
+    /// returned so this is how the caller learns about them.
+    pub fn check_recorded(&self, cert: &[u8]) -> Result<()> {
+        let cert = Certificate::from_pem(cert)?;
+        let serial = serial_hex(&cert.tbs_certificate.serial_number);
+        if !self.has_issued(&serial)? {
+            return Err(CaError::Unrecorded {
+                name: self.name(),
+                serial,
+            }
+            .into());
+        }
+
+        Ok(())
+    }
+
+    /// Mark the cert with the given serial number as revoked in the
+    /// issuance database. The serial number is hex encoded. The cert is
+    /// listed in every CRL issued by this `Ca` from then on.
+    pub fn revoke(&self, serial: &str, reason: RevokeReason) -> Result<()> {
+        let db = self.issuance_db()?;
+        let mut certs = db.read()?;
+
+        let cert = match IssuanceDb::position(&certs, serial)? {
+            Some(i) => &mut certs[i],
+            None => {
+                return Err(CaError::NoCert {
+                    serial: serial.to_string(),
+                }
+                .into())
+            }
+        };
+        if cert.is_revoked() {
+            return Err(CaError::AlreadyRevoked {
+                serial: cert.serial.clone(),
+            }
+            .into());
+        }
+
+        info!(
+            "Revoking cert with serial {} issued by {}: {:?}",
+            cert.serial,
+            self.name(),
+            reason
+        );
+        cert.revocation = Some(Revocation {
+            revoked_at: DateTime::from_system_time(SystemTime::now())?
+                .to_string(),
+            reason,
+        });
+
+        db.write(&certs)
+    }
+
+    /// Issue a CRL listing every cert revoked by this `Ca` & sign it with
+    /// the key held by `keys`. The next CRL is expected within
+    /// `next_update` days. A copy of the CRL is kept in the `crl` directory
+    /// and the PEM encoded CRL is returned.
+    pub fn crl(&self, keys: &dyn KeyStore, next_update: u32) -> Result<String> {
+        let ca_cert = self.cert()?;
+        let key_id = subject_key_id(&ca_cert)?;
+        let algorithm = self.signature_algorithm()?;
+
+        let mut revoked = Vec::new();
+        for cert in self.issued()? {
+            let revocation = match cert.revocation {
+                Some(revocation) => revocation,
+                None => continue,
+            };
+
+            // the reason code is left out when it's unspecified
+            let crl_entry_extensions = match revocation.reason {
+                RevokeReason::Unspecified => None,
+                reason => {
+                    Some(vec![extension(&CrlReason::from(reason), false)?])
+                }
+            };
+            revoked.push(RevokedCert {
+                serial_number: SerialNumber::new(
+                    &parse_serial(&cert.serial)?.to_bytes_be(),
+                )?,
+                revocation_date: Time::UtcTime(UtcTime::from_date_time(
+                    DateTime::from_str(&revocation.revoked_at)?,
+                )?),
+                crl_entry_extensions,
+            });
+        }
+        info!(
+            "Generating CRL for {} with {} revoked certs",
+            self.name(),
+            revoked.len()
+        );
+
+        let number = self.next_crl_number()?;
+        let now = SystemTime::now();
+        let next = now + Duration::from_secs(u64::from(next_update) * 86400);
+        let tbs_cert_list = TbsCertList {
+            version: Version::V2,
+            signature: algorithm.clone(),
+            issuer: ca_cert.tbs_certificate.subject,
+            this_update: Time::UtcTime(UtcTime::from_system_time(now)?),
+            next_update: Some(Time::UtcTime(UtcTime::from_system_time(next)?)),
+            // an empty list of revoked certs must be omitted
+            revoked_certificates: if revoked.is_empty() {
+                None
+            } else {
+                Some(revoked)
+            },
+            crl_extensions: Some(vec![
+                authority_key_id(&key_id)?,
+                extension(
+                    &CrlNumber(Uint::new(&number.to_bytes_be())?),
+                    false,
+                )?,
+            ]),
+        };
+
+        let signature = self.sign(keys, &tbs_cert_list.to_der()?)?;
+        let crl = CertificateList {
+            tbs_cert_list,
+            signature_algorithm: algorithm,
+            signature: BitString::from_bytes(&signature)?,
+        };
+        let pem = pem_rfc7468::encode_string(
+            "X509 CRL",
+            LineEnding::default(),
+            &crl.to_der()?,
+        )?;
+
+        // like certs, a CRL must be returned to the caller once it's signed
+        let path = self
+            .root
+            .join(CA_CRL_DIR)
+            .join(format!("{:02X}.crl.pem", number));
+        if let Err(e) = fs::write(&path, &pem) {
+            warn!("Failed to write CRL to {}: {}", path.display(), e);
+        }
+
+        Ok(pem)
+    }
+}
+
+impl Ca {
+    /// Store a copy of this `Ca`s certificate (if it has one), key spec,
+    /// current serial number, CRL number (if it has issued a CRL) and
+    /// issuance database in the YubiHSM as opaque objects next to the CA
+    /// key. Objects from a previous call are replaced. Each object is backed
+    /// up under wrap to `backup_dir`.
+    pub fn store_state(
+        &self,
+        client: &Client,
+        backup_dir: &Path,
+    ) -> Result<()> {
+        if self.sandbox {
+            return Err(CaError::SandboxState.into());
+        }
+
+        let cert = self.root.join(CA_CERT);
+        if cert.exists() {
+            let cert = Certificate::from_pem(fs::read(cert)?)?.to_der()?;
+            self.put_state_object(
+                client,
+                OPAQUE_CERT_OFFSET,
+                opaque::Algorithm::X509Certificate,
+                cert,
+                backup_dir,
+            )?;
+        }
+
+        self.put_state_object(
+            client,
+            OPAQUE_KEY_SPEC_OFFSET,
+            opaque::Algorithm::Data,
+            fs::read(self.root.join(CA_KEY_SPEC))?,
+            backup_dir,
+        )?;
+
+        self.put_state_object(
+            client,
+            OPAQUE_SERIAL_OFFSET,
+            opaque::Algorithm::Data,
+            fs::read(self.root.join(CA_SERIAL))?,
+            backup_dir,
+        )?;
+
+        let crl_number = self.root.join(CA_CRL_NUMBER);
+        if crl_number.exists() {
+            self.put_state_object(
+                client,
+                OPAQUE_CRL_NUMBER_OFFSET,
+                opaque::Algorithm::Data,
+                fs::read(crl_number)?,
+                backup_dir,
+            )?;
+        }
+
+        let db = IssuanceDb::new(&self.root);
+        if !db.exists() {
+            warn!("CA {} has no issuance database to store", self.name());
+            return Ok(());
+        }
+
+        // `issue` won't sign more certs than fit but an imported database
+        // may have too many
+        let certs = db.read()?;
+        if certs.len() > OPAQUE_ISSUED_MAX {
+            return Err(CaError::IssuedFull {
+                name: self.name(),
+                certs: certs.len(),
+            }
+            .into());
+        }
+        let issued = compact_db(&certs)?;
+        let chunks: Vec<&[u8]> = issued.chunks(OPAQUE_MAX_LEN).collect();
+        for n in 0..OPAQUE_ISSUED_OBJECTS {
+            let offset = OPAQUE_ISSUED_OFFSET + n * OPAQUE_CERT_OFFSET;
+            match chunks.get(usize::from(n)) {
+                Some(chunk) => self.put_state_object(
+                    client,
+                    offset,
+                    opaque::Algorithm::Data,
+                    chunk.to_vec(),
+                    backup_dir,
+                )?,
+                // drop what's left of a larger database from a previous call
+                None => self.delete_state_object(client, offset, backup_dir)?,
+            }
+        }
+
+        Ok(())
+    }
+
+    fn put_state_object(
+        &self,
+        client: &Client,
+        offset: Id,
+        algorithm: opaque::Algorithm,
+        data: Vec<u8>,
+        backup_dir: &Path,
+    ) -> Result<()> {
+        let id = state_id(self.spec.id, offset)?;
+        let label = self.spec.label.clone();
+
+        // opaque objects can't be modified, only replaced
+        if has_opaque(client, id)? {
+            debug!("replacing opaque object {:#06x}", id);
+            client.delete_object(id, Type::Opaque)?;
+        }
+
+        info!(
+            "Storing CA state for {} in opaque object {:#06x}",
+            label, id
+        );
+        client.put_opaque(
+            id,
+            label,
+            self.spec.domain,
+            Capability::EXPORTABLE_UNDER_WRAP,
+            algorithm,
+            data,
+        )?;
+
+        let backup = self.state_backup(id, backup_dir);
+        if backup.exists() {
+            fs::remove_file(&backup)?;
+        }
+        hsm::backup_object(client, id, Type::Opaque, backup)
+    }
+
+    /// Delete a state object & its backup if this `Ca` has one.
+    fn delete_state_object(
+        &self,
+        client: &Client,
+        offset: Id,
+        backup_dir: &Path,
+    ) -> Result<()> {
+        let id = state_id(self.spec.id, offset)?;
+        if has_opaque(client, id)? {
+            debug!("deleting opaque object {:#06x}", id);
+            client.delete_object(id, Type::Opaque)?;
+        }
+
+        let backup = self.state_backup(id, backup_dir);
+        if backup.exists() {
+            fs::remove_file(&backup)?;
+        }
+
+        Ok(())
+    }
+
+    fn state_backup(&self, id: Id, backup_dir: &Path) -> PathBuf {
+        // the key backup uses the label for its name so we add the id
+        backup_dir.join(format!("{}.{:04x}{}", self.spec.label, id, BACKUP_EXT))
+    }
+
+    /// Recreate the state directory for every CA with state stored in the
+    /// YubiHSM by `store_state`. The state directory for each CA is created
+    /// under `state`. CAs with an existing state directory are skipped.
+    pub fn rebuild<P: AsRef<Path>>(
+        client: &Client,
+        state: P,
+    ) -> Result<Vec<Ca>> {
+        let mut cas = Vec::new();
+        for entry in client.list_objects(&[Filter::Type(Type::Opaque)])? {
+            // only the key spec objects, the key id is < OPAQUE_CERT_OFFSET
+            let id = match entry.object_id.checked_sub(OPAQUE_KEY_SPEC_OFFSET) {
+                Some(id) if id < OPAQUE_CERT_OFFSET => id,
+                _ => continue,
+            };
+
+            let spec = client.get_opaque(entry.object_id)?;
+            let spec = KeySpec::from_str(&String::from_utf8(spec)?)?;
+            let root = state.as_ref().join(spec.label.to_string());
+            if root.exists() {
+                warn!(
+                    "state for CA {} exists, skipping: {}",
+                    spec.label,
+                    root.display()
+                );
+                continue;
+            }
+
+            info!(
+                "Rebuilding state for CA {} in {}",
+                spec.label,
+                root.display()
+            );
+            bootstrap_ca_dir(&spec, root.as_path(), false)?;
+
+            let serial = state_id(id, OPAQUE_SERIAL_OFFSET)?;
+            if has_opaque(client, serial)? {
+                fs::write(root.join(CA_SERIAL), client.get_opaque(serial)?)?;
+            } else {
+                warn!("no serial number for CA {} in YubiHSM", spec.label);
+            }
+
+            let crl_number = state_id(id, OPAQUE_CRL_NUMBER_OFFSET)?;
+            if has_opaque(client, crl_number)? {
+                fs::write(
+                    root.join(CA_CRL_NUMBER),
+                    client.get_opaque(crl_number)?,
+                )?;
+            }
+
+            // intermediate CAs won't have a cert till it's been signed
+            let cert = state_id(id, OPAQUE_CERT_OFFSET)?;
+            if has_opaque(client, cert)? {
+                let cert = Certificate::from_der(&client.get_opaque(cert)?)?;
+                fs::write(
+                    root.join(CA_CERT),
+                    cert.to_pem(LineEnding::default())?,
+                )?;
+            }
+
+            let mut issued = Vec::new();
+            for n in 0..OPAQUE_ISSUED_OBJECTS {
+                let offset = OPAQUE_ISSUED_OFFSET + n * OPAQUE_CERT_OFFSET;
+                let id = state_id(id, offset)?;
+                if !has_opaque(client, id)? {
+                    break;
+                }
+                issued.extend(client.get_opaque(id)?);
+            }
+            if issued.is_empty() {
+                // an empty database would drop revoked certs from the CRL
+                // & let serial numbers and keys be reused
+                warn!(
+                    "no issuance database for CA {} in YubiHSM, restore {} \
+                     from a backup of the CA state",
+                    spec.label, ISSUED_DB
+                );
+                fs::remove_file(root.join(ISSUED_DB))?;
+            } else {
+                // only the details needed to issue certs & CRLs are kept
+                IssuanceDb::new(&root).write(&expand_db(&issued)?)?;
+            }
+
+            cas.push(Ca::load(root)?);
+        }
+
+        Ok(cas)
+    }
+}
+
+fn state_id(key: Id, offset: Id) -> Result<Id> {
+    match key.checked_add(offset) {
+        Some(id) if key < OPAQUE_CERT_OFFSET => Ok(id),
+        _ => Err(CaError::BadStateId { id: key }.into()),
+    }
+}
+
+fn has_opaque(client: &Client, id: Id) -> Result<bool> {
+    Ok(!client
+        .list_objects(&[Filter::Id(id), Filter::Type(Type::Opaque)])?
+        .is_empty())
+}
+
+fn digest(hash: Hash, data: &[u8]) -> Vec<u8> {
+    match hash {
+        Hash::Sha256 => Sha256::digest(data).to_vec(),
+        Hash::Sha384 => Sha384::digest(data).to_vec(),
+        Hash::Sha512 => Sha512::digest(data).to_vec(),
+    }
+}
+
+/// The serial number as upper case hex. This is how serial numbers appear
+/// in the CA database and the names of files in `newcerts`.
+fn serial_hex(serial: &SerialNumber) -> String {
+    hex::encode_upper(serial.as_bytes())
+}
+
+/// The end of the validity period for certs that don't expire (RFC 5280
+/// section 4.1.2.5).
+fn no_expiry() -> Result<DateTime> {
+    Ok(DateTime::new(9999, 12, 31, 23, 59, 59)?)
+}
+
+/// Encode a time for a cert or CRL: UTCTime through 2049 & GeneralizedTime
+/// from 2050 on (RFC 5280 section 4.1.2.5).
+fn x509_time(time: DateTime) -> Result<Time> {
+    Ok(if time.year() < 2050 {
+        Time::UtcTime(UtcTime::from_date_time(time)?)
+    } else {
+        Time::GeneralTime(GeneralizedTime::from_date_time(time))
+    })
+}
+
+/// Get the validity period for a cert issued with `profile` from the one
+/// `requested` in the spec. The cert may not be valid for longer than the
+/// max for `profile` or outlive its issuer. Without a requested end to the
+/// validity period we use the latest one allowed.
+fn cert_validity(
+    profile: &Profile,
+    requested: Option<&ValiditySpec>,
+    issuer_not_after: Option<DateTime>,
+) -> Result<Validity> {
+    let requested = requested.cloned().unwrap_or_default();
+    let not_before = match requested.not_before {
+        Some(not_before) => not_before,
+        None => DateTime::from_system_time(SystemTime::now())?,
+    };
+    let after_days = |days: u32| {
+        DateTime::from_unix_duration(
+            not_before.unix_duration()
+                + Duration::from_secs(u64::from(days) * 86400),
+        )
+    };
+
+    let max = match profile.max_validity_days {
+        Some(days) => Some(after_days(days)?),
+        None => None,
+    };
+    let latest = [max, issuer_not_after]
+        .into_iter()
+        .flatten()
+        .min()
+        .unwrap_or(no_expiry()?);
+
+    let not_after = match (requested.not_after, requested.days) {
+        (Some(not_after), _) => not_after,
+        (None, Some(days)) => after_days(days)?,
+        (None, None) => latest,
+    };
+
+    if not_after <= not_before {
+        return Err(CaError::EmptyValidity { not_after }.into());
+    }
+    if let (Some(max), Some(days)) = (max, profile.max_validity_days) {
+        if not_after > max {
+            return Err(CaError::ValidityTooLong {
+                profile: profile.name.clone(),
+                max: days,
+            }
+            .into());
+        }
+    }
+    if let Some(issuer) = issuer_not_after {
+        if not_after > issuer {
+            return Err(CaError::OutlivesIssuer { not_after, issuer }.into());
+        }
+    }
+
+    Ok(Validity {
+        not_before: x509_time(not_before)?,
+        not_after: x509_time(not_after)?,
+    })
+}
+
+/// The key identifier for a public key: the SHA-1 digest of the public key
+/// bits (RFC 5280 section 4.2.1.2 method 1).
+fn key_identifier(spki: &SubjectPublicKeyInfoOwned) -> Vec<u8> {
+    Sha1::digest(spki.subject_public_key.raw_bytes()).to_vec()
+}
+
+/// Get the key identifier for the key in `cert`. We use the subject key
+/// identifier from the cert if it has one so that it always matches the
+/// authority key identifier in the certs issued under it.
+fn subject_key_id(cert: &Certificate) -> Result<Vec<u8>> {
+    for ext in cert.tbs_certificate.extensions.iter().flatten() {
+        if ext.extn_id == SubjectKeyIdentifier::OID {
+            let ski =
+                SubjectKeyIdentifier::from_der(ext.extn_value.as_bytes())?;
+            return Ok(ski.0.as_bytes().to_vec());
+        }
+    }
+
+    Ok(key_identifier(
+        &cert.tbs_certificate.subject_public_key_info,
+    ))
+}
+
+/// Check `csr` against the rules for CSRs signed with `profile` by the CA
+/// for the key from `spec` with the subject `issuer`. The key in the CSR
+/// must not be the key of any of the `cas` or the key in a cert any of
+/// them has issued. The keys of the `cas` are held by `keys`, without it
+/// the key in the cert of each is used. Returns the subject for the cert.
+pub fn check_csr(
+    csr: &CertReq,
+    profile: &Profile,
+    spec: &KeySpec,
+    issuer: &Name,
+    cas: &HashMap<String, Ca>,
+    keys: Option<&dyn KeyStore>,
+) -> Result<Name> {
+    let reject = |rule, reason: String| CaError::CsrRejected { rule, reason };
+
+    verify_csr(csr)
+        .map_err(|e| reject(CsrRule::ProofOfPossession, format!("{:#}", e)))?;
+
+    let spki = &csr.info.public_key;
+    let algorithm = key_algorithm(spki)
+        .map_err(|e| reject(CsrRule::KeyAlgorithm, format!("{:#}", e)))?;
+    if !profile.key_algorithms.is_empty()
+        && !profile.key_algorithms.contains(&algorithm)
+    {
+        return Err(reject(
+            CsrRule::KeyAlgorithm,
+            format!(
+                "{:?} keys aren't allowed by profile \"{}\"",
+                algorithm, profile.name
+            ),
+        )
+        .into());
+    }
+
+    let policy = spec.subject_policy.as_deref().unwrap_or(&SUBJECT_POLICY);
+    let subject = policy_subject(&csr.info.subject, policy, issuer)
+        .map_err(|e| reject(CsrRule::SubjectPolicy, format!("{:#}", e)))?;
+
+    let spki_der = spki.to_der()?;
+    let spki_sha256 = hex::encode(Sha256::digest(&spki_der));
+    for ca in cas.values() {
+        let ca_key = match keys {
+            Some(keys) => Some(keys.public_key_der(ca.spec.id)?),
+            None if ca.has_cert() => Some(
+                ca.cert()?
+                    .tbs_certificate
+                    .subject_public_key_info
+                    .to_der()?,
+            ),
+            None => None,
+        };
+        if ca_key.as_ref() == Some(&spki_der) {
+            return Err(reject(
+                CsrRule::KeyReuse,
+                format!("key is the key of CA {}", ca.name()),
+            )
+            .into());
+        }
+        let issued = ca.issued()?;
+        let reused = issued
+            .iter()
+            .find(|c| c.spki_sha256.as_ref() == Some(&spki_sha256));
+        if let Some(cert) = reused {
+            return Err(reject(
+                CsrRule::KeyReuse,
+                format!(
+                    "key was certified by CA {} in cert with serial {}",
+                    ca.name(),
+                    cert.serial
+                ),
+            )
+            .into());
+        }
+    }
+
+    for attr in csr.info.attributes.iter() {
+        if attr.oid != ExtensionReq::OID {
+            continue;
+        }
+        for value in attr.values.iter() {
+            let req: ExtensionReq = value.decode_as()?;
+            if let Some(ext) = req.0.first() {
+                return Err(reject(
+                    CsrRule::RequestedExtensions,
+                    format!(
+                        "CSR requests extension {}, extensions come from \
+                        profile \"{}\"",
+                        ext.extn_id, profile.name
+                    ),
+                )
+                .into());
+            }
+        }
+    }
+
+    Ok(subject)
+}
+
+/// Check that the root key table of a debug credential signed by the CA
+/// `signer` will hold 1 to 4 distinct keys, the signer's key among them.
+pub fn check_dcsr_roots(signer: &str, root_labels: &[String]) -> Result<()> {
+    if root_labels.is_empty() || root_labels.len() > ROOT_KEY_SLOTS {
+        return Err(CaError::DcsrRejected {
+            rule: DcsrRule::RootKeys,
+            reason: format!(
+                "{} root keys, the root key table holds 1 to {}",
+                root_labels.len(),
+                ROOT_KEY_SLOTS
+            ),
+        }
+        .into());
+    }
+    for (i, label) in root_labels.iter().enumerate() {
+        if root_labels[..i].contains(label) {
+            return Err(CaError::DcsrRejected {
+                rule: DcsrRule::RootKeys,
+                reason: format!("root key \"{}\" is listed twice", label),
+            }
+            .into());
+        }
+    }
+    if !root_labels.iter().any(|label| label == signer) {
+        return Err(CaError::DcsrRejected {
+            rule: DcsrRule::Signer,
+            reason: format!("CA {} isn't one of the root keys", signer),
+        }
+        .into());
+    }
+
+    Ok(())
+}
+
+/// The subject name used in the cert / CSR for the key from `spec`. This
+/// is the subject from the key spec, or the Oxide defaults if it has none,
+/// with the `common_name` as the CN if the subject doesn't include one.
+pub fn ca_subject(spec: &KeySpec, sandbox: bool) -> Result<Name> {
+    let mut attrs: Vec<(NameAttribute, &str)> = match &spec.subject {
+        Some(subject) => {
+            subject.iter().map(|a| (a.attr, a.value.as_str())).collect()
+        }
+        None => vec![
+            (NameAttribute::C, CA_COUNTRY),
+            (NameAttribute::O, CA_ORGANIZATION),
+        ],
+    };
+    if !attrs.iter().any(|(attr, _)| *attr == NameAttribute::CN) {
+        attrs.push((NameAttribute::CN, &spec.common_name));
+    }
+    // sandbox CAs are marked as such just before the CN
+    if sandbox {
+        let cn = attrs
+            .iter()
+            .position(|(attr, _)| *attr == NameAttribute::CN)
+            .unwrap_or(attrs.len());
+        attrs.insert(cn, (NameAttribute::OU, SANDBOX_OU));
+    }
+
+    let mut rdns = Vec::new();
+    for (attr, value) in attrs {
+        // the country is always a PrintableString, everything else is
+        // UTF8
+        let tag = if attr == NameAttribute::C {
+            Tag::PrintableString
+        } else {
+            Tag::Utf8String
+        };
+        let atv = AttributeTypeAndValue {
+            oid: name_attribute_oid(attr),
+            value: Any::new(tag, value.as_bytes())?,
+        };
+        rdns.push(RelativeDistinguishedName(SetOfVec::try_from(vec![atv])?));
+    }
+
+    Ok(RdnSequence(rdns))
+}
+
+/// Get the lpc55 root key hash (RKH) for the RSA key in `cert`: the SHA-256
+/// digest of the modulus & exponent.
+pub fn root_key_hash(cert: &Certificate) -> Result<[u8; 32]> {
+    let spki = &cert.tbs_certificate.subject_public_key_info;
+    let key = RsaPublicKey::from_public_key_der(&spki.to_der()?)
+        .context("lpc55 root keys must be RSA keys")?;
+
+    Ok(rsa_key_hash(&key))
+}
+
+/// Get the lpc55 root key hash for an RSA `key`.
+fn rsa_key_hash(key: &RsaPublicKey) -> [u8; 32] {
+    let mut digest = Sha256::new();
+    digest.update(key.n().to_bytes_be());
+    digest.update(key.e().to_bytes_be());
+
+    digest.finalize().into()
+}
+
+/// Get the lpc55 root key table hash (RKTH) for the keys in the root
+/// `certs`: the SHA-256 digest of the RKH for each key with unused slots in
+/// the table zeroed.
+pub fn root_key_table_hash(certs: &[Certificate]) -> Result<[u8; 32]> {
+    if certs.is_empty() || certs.len() > ROOT_KEY_SLOTS {
+        return Err(CaError::BadRootKeyCount { count: certs.len() }.into());
+    }
+
+    let mut hashes = Vec::new();
+    for cert in certs {
+        hashes.push(root_key_hash(cert)?);
+    }
+
+    Ok(key_table_hash(&hashes))
+}
+
+/// Get the lpc55 root key table hash for the root key `hashes`.
+fn key_table_hash(hashes: &[[u8; 32]]) -> [u8; 32] {
+    let mut digest = Sha256::new();
+    for hash in hashes {
+        digest.update(hash);
+    }
+    for _ in hashes.len()..ROOT_KEY_SLOTS {
+        digest.update([0u8; 32]);
+    }
+
+    digest.finalize().into()
+}
+
+/// Get the key identifier of the issuer of `cert` from its authority key
+/// identifier, `None` if it doesn't have one.
+fn issuer_key_id(cert: &Certificate) -> Result<Option<Vec<u8>>> {
+    for ext in cert.tbs_certificate.extensions.iter().flatten() {
+        if ext.extn_id == AuthorityKeyIdentifier::OID {
+            let aki =
+                AuthorityKeyIdentifier::from_der(ext.extn_value.as_bytes())?;
+            return Ok(aki.key_identifier.map(|id| id.as_bytes().to_vec()));
+        }
+    }
+
+    Ok(None)
+}
+
+/// The authority key identifier extension for certs / CRLs issued by the
+/// key with the given key identifier.
+fn authority_key_id(issuer_key_id: &[u8]) -> Result<Extension> {
+    extension(
+        &AuthorityKeyIdentifier {
+            key_identifier: Some(OctetString::new(issuer_key_id)?),
+            authority_cert_issuer: None,
+            authority_cert_serial_number: None,
+        },
+        false,
+    )
+}
+
+fn extension<T: AssociatedOid + Encode>(
+    value: &T,
+    critical: bool,
+) -> Result<Extension> {
+    Ok(Extension {
+        extn_id: T::OID,
+        critical,
+        extn_value: OctetString::new(value.to_der()?)?,
+    })
+}
+
+/// Find & decode the extension of type `T` in `extensions`. Returns whether
+/// it's critical along with its value.
+fn find_extension<T: AssociatedOid + for<'a> Decode<'a>>(
+    extensions: &[Extension],
+) -> Result<Option<(bool, T)>> {
+    match extensions.iter().find(|e| e.extn_id == T::OID) {
+        Some(ext) => Ok(Some((
+            ext.critical,
+            T::from_der(ext.extn_value.as_bytes())?,
+        ))),
+        None => Ok(None),
+    }
+}
+
+/// The bits set in a key usage extension.
+fn key_usage_bits(usage: &KeyUsage) -> Vec<KeyUsageBit> {
+    [
+        (usage.digital_signature(), KeyUsageBit::DigitalSignature),
+        (usage.non_repudiation(), KeyUsageBit::NonRepudiation),
+        (usage.key_encipherment(), KeyUsageBit::KeyEncipherment),
+        (usage.data_encipherment(), KeyUsageBit::DataEncipherment),
+        (usage.key_agreement(), KeyUsageBit::KeyAgreement),
+        (usage.key_cert_sign(), KeyUsageBit::KeyCertSign),
+        (usage.crl_sign(), KeyUsageBit::CrlSign),
+        (usage.encipher_only(), KeyUsageBit::EncipherOnly),
+        (usage.decipher_only(), KeyUsageBit::DecipherOnly),
+    ]
+    .into_iter()
+    .filter_map(|(set, bit)| set.then_some(bit))
+    .collect()
+}
+
+/// Returns true if `a` & `b` hold the same items, in any order.
+fn same_items<T: PartialEq>(a: &[T], b: &[T]) -> bool {
+    a.iter().all(|i| b.contains(i)) && b.iter().all(|i| a.contains(i))
+}
+
+/// The v3 extensions for a cert issued to a key with the given profile.
+fn extensions(
+    profile: &Profile,
+    spki: &SubjectPublicKeyInfoOwned,
+    issuer_key_id: &[u8],
+) -> Result<Vec<Extension>> {
+    let usage = profile
+        .key_usage
+        .iter()
+        .fold(FlagSet::default(), |usage, bit| usage | key_usage(*bit));
+
+    let mut extensions = vec![
+        extension(
+            &SubjectKeyIdentifier(OctetString::new(key_identifier(spki))?),
+            false,
+        )?,
+        authority_key_id(issuer_key_id)?,
+        extension(
+            &BasicConstraints {
+                ca: profile.ca,
+                path_len_constraint: profile.path_len,
+            },
+            true,
+        )?,
+        extension(&KeyUsage(usage), true)?,
+    ];
+    if !profile.extended_key_usage.is_empty() {
+        extensions.push(extension(
+            &ExtendedKeyUsage(profile.extended_key_usage.clone()),
+            false,
+        )?);
+    }
+    if !profile.policies.is_empty() {
+        let policies = profile
+            .policies
+            .iter()
+            .map(|policy_identifier| PolicyInformation {
+                policy_identifier: *policy_identifier,
+                policy_qualifiers: None,
+            })
+            .collect();
+        extensions.push(extension(
+            &CertificatePolicies(policies),
+            profile.policies_critical,
+        )?);
+    }
+    if let Some(constraints) = &profile.name_constraints {
+        // RFC 5280 requires that name constraints be critical
+        extensions.push(extension(
+            &NameConstraints {
+                permitted_subtrees: general_subtrees(&constraints.permitted)?,
+                excluded_subtrees: general_subtrees(&constraints.excluded)?,
+            },
+            true,
+        )?);
+    }
+    for ext in &profile.extensions {
+        extensions.push(Extension {
+            extn_id: ext.oid,
+            critical: ext.critical,
+            extn_value: OctetString::new(ext.value.clone())?,
+        });
+    }
+
+    Ok(extensions)
+}
+
+fn key_usage(bit: KeyUsageBit) -> KeyUsages {
+    match bit {
+        KeyUsageBit::DigitalSignature => KeyUsages::DigitalSignature,
+        KeyUsageBit::NonRepudiation => KeyUsages::NonRepudiation,
+        KeyUsageBit::KeyEncipherment => KeyUsages::KeyEncipherment,
+        KeyUsageBit::DataEncipherment => KeyUsages::DataEncipherment,
+        KeyUsageBit::KeyAgreement => KeyUsages::KeyAgreement,
+        KeyUsageBit::KeyCertSign => KeyUsages::KeyCertSign,
+        KeyUsageBit::CrlSign => KeyUsages::CRLSign,
+        KeyUsageBit::EncipherOnly => KeyUsages::EncipherOnly,
+        KeyUsageBit::DecipherOnly => KeyUsages::DecipherOnly,
+    }
+}
+
+/// The subtrees for the names in a name constraint, `None` if there are no
+/// names.
+fn general_subtrees(
+    names: &[ConstraintName],
+) -> Result<Option<Vec<GeneralSubtree>>> {
+    if names.is_empty() {
+        return Ok(None);
+    }
+
+    let mut subtrees = Vec::new();
+    for name in names {
+        let base = match name {
+            ConstraintName::Dns(name) => {
+                GeneralName::DnsName(Ia5String::new(name)?)
+            }
+            ConstraintName::Email(name) => {
+                GeneralName::Rfc822Name(Ia5String::new(name)?)
+            }
+            ConstraintName::Uri(name) => {
+                GeneralName::UniformResourceIdentifier(Ia5String::new(name)?)
+            }
+            ConstraintName::DirName(name) => {
+                GeneralName::DirectoryName(Name::from_str(name)?)
+            }
+            // IP address constraints are the address followed by the mask
+            // (RFC 5280 section 4.2.1.10)
+            ConstraintName::Ip { addr, prefix } => {
+                let mut bytes = match addr {
+                    IpAddr::V4(addr) => addr.octets().to_vec(),
+                    IpAddr::V6(addr) => addr.octets().to_vec(),
+                };
+                let mask: Vec<u8> = (0..bytes.len())
+                    .map(|i| {
+                        let bits = usize::from(*prefix).saturating_sub(i * 8);
+                        (0xff00u16 >> bits.min(8)) as u8
+                    })
+                    .collect();
+                bytes.extend(mask);
+                GeneralName::IpAddress(OctetString::new(bytes)?)
+            }
+        };
+        subtrees.push(GeneralSubtree {
+            base,
+            minimum: 0,
+            maximum: None,
+        });
+    }
+
+    Ok(Some(subtrees))
+}
+
+fn name_attribute_oid(attr: NameAttribute) -> ObjectIdentifier {
+    match attr {
+        NameAttribute::C => OID_COUNTRY_NAME,
+        NameAttribute::ST => OID_STATE_OR_PROVINCE_NAME,
+        NameAttribute::L => OID_LOCALITY_NAME,
+        NameAttribute::O => OID_ORGANIZATION_NAME,
+        NameAttribute::OU => OID_ORGANIZATIONAL_UNIT_NAME,
+        NameAttribute::CN => OID_COMMON_NAME,
+    }
+}
+
+/// The values of the attributes of type `oid` in `name`.
+fn name_attributes(
+    name: &Name,
+    oid: ObjectIdentifier,
+) -> Vec<&AttributeTypeAndValue> {
+    name.0
+        .iter()
+        .flat_map(|rdn| rdn.0.iter())
+        .filter(|atv| atv.oid == oid)
+        .collect()
+}
+
+/// Build the subject for a cert from the subject in a CSR according to the
+/// `policy` of the CA with subject `ca_subject`. Attributes are put in the
+/// cert in the order of the policy and attributes without a rule are
+/// dropped.
+fn policy_subject(
+    subject: &Name,
+    policy: &[PolicyRule],
+    ca_subject: &Name,
+) -> Result<Name> {
+    let mut rdns = Vec::new();
+    for rule in policy {
+        let oid = name_attribute_oid(rule.attr);
+        let attr = rule.attr.long_name();
+        let atvs = name_attributes(subject, oid);
+        if rule.rule != PolicyMatch::Optional && atvs.is_empty() {
+            return Err(CaError::MissingSubjectAttribute { attr }.into());
+        }
+        if rule.rule == PolicyMatch::Match {
+            // compare the values, not the string types used to encode them
+            let value =
+                |atv: &&AttributeTypeAndValue| atv.value.value().to_vec();
+            let expected: Vec<_> =
+                name_attributes(ca_subject, oid).iter().map(value).collect();
+            let actual: Vec<_> = atvs.iter().map(value).collect();
+            if actual != expected {
+                return Err(CaError::SubjectMismatch { attr }.into());
+            }
+        }
+        for atv in atvs {
+            rdns.push(RelativeDistinguishedName(SetOfVec::try_from(vec![
+                atv.clone(),
+            ])?));
+        }
+    }
+
+    Ok(RdnSequence(rdns))
+}
+
+/// Verify the signature on a CSR. This is proof that whoever sent us the
+/// CSR holds the private key for the public key we're certifying.
+fn verify_csr(csr: &CertReq) -> Result<()> {
+    let sig = csr.signature.as_bytes().ok_or(CaError::BadCsrSignature)?;
+    let msg = csr.info.to_der()?;
+
+    if verify_signature(csr.algorithm.oid, &csr.info.public_key, &msg, sig)? {
+        Ok(())
+    } else {
+        Err(CaError::BadCsrSignature.into())
+    }
+}
+
+/// Check that `cert` was issued by the holder of the `issuer` cert: the
+/// names must match & the signature on `cert` must be from the issuer's key.
+fn verify_issued_by(cert: &Certificate, issuer: &Certificate) -> Result<()> {
+    let subject = cert.tbs_certificate.subject.to_string();
+    if cert.tbs_certificate.issuer != issuer.tbs_certificate.subject {
+        return Err(CaError::IssuerMismatch {
+            subject,
+            issuer: issuer.tbs_certificate.subject.to_string(),
+        }
+        .into());
+    }
+
+    let sig = cert.signature.as_bytes().ok_or(CaError::BadCertSignature {
+        subject: subject.clone(),
+    })?;
+    if verify_signature(
+        cert.signature_algorithm.oid,
+        &issuer.tbs_certificate.subject_public_key_info,
+        &cert.tbs_certificate.to_der()?,
+        sig,
+    )? {
+        Ok(())
+    } else {
+        Err(CaError::BadCertSignature { subject }.into())
+    }
+}
+
+/// The type of the key in `spki`.
+fn key_algorithm(spki: &SubjectPublicKeyInfoOwned) -> Result<KeyAlgorithm> {
+    match spki.algorithm.oid {
+        OID_RSA_ENCRYPTION => {
+            let key = RsaPublicKey::from_public_key_der(&spki.to_der()?)?;
+            match key.size() * 8 {
+                2048 => Ok(KeyAlgorithm::Rsa2048),
+                3072 => Ok(KeyAlgorithm::Rsa3072),
+                4096 => Ok(KeyAlgorithm::Rsa4096),
+                bits => Err(anyhow!("unsupported RSA key size: {}", bits)),
+            }
+        }
+        OID_EC_PUBLIC_KEY => {
+            let curve: ObjectIdentifier = spki
+                .algorithm
+                .parameters
+                .as_ref()
+                .ok_or(anyhow!("EC public key has no curve"))?
+                .decode_as()?;
+            match curve {
+                OID_SECP256R1 => Ok(KeyAlgorithm::Ecp256),
+                OID_SECP384R1 => Ok(KeyAlgorithm::Ecp384),
+                OID_SECP521R1 => Ok(KeyAlgorithm::Ecp521),
+                oid => Err(anyhow!("unsupported EC curve: {}", oid)),
+            }
+        }
+        OID_ED25519 => Ok(KeyAlgorithm::Ed25519),
+        oid => Err(anyhow!("unsupported key algorithm: {}", oid)),
+    }
+}
+
+/// Returns true if the basic constraints in `cert` say it's a CA cert.
+fn is_ca_cert(cert: &Certificate) -> Result<bool> {
+    for ext in cert.tbs_certificate.extensions.iter().flatten() {
+        if ext.extn_id == BasicConstraints::OID {
+            return Ok(
+                BasicConstraints::from_der(ext.extn_value.as_bytes())?.ca
+            );
+        }
+    }
+
+    Ok(false)
+}
+
+/// Verify the signature `sig` over `msg` made using `algorithm` with the key
+/// in `spki`. Signatures from a different type of key than `algorithm` uses
+/// are never valid.
+fn verify_signature(
+    algorithm: ObjectIdentifier,
+    spki: &SubjectPublicKeyInfoOwned,
+    msg: &[u8],
+    sig: &[u8],
+) -> Result<bool> {
+    Ok(match algorithm {
+        OID_SHA256_WITH_RSA | OID_SHA384_WITH_RSA | OID_SHA512_WITH_RSA => {
+            if spki.algorithm.oid != OID_RSA_ENCRYPTION {
+                return Ok(false);
+            }
+            let key = RsaPublicKey::from_public_key_der(&spki.to_der()?)?;
+            let sig = pkcs1v15::Signature::try_from(sig)?;
+            match algorithm {
+                OID_SHA256_WITH_RSA => {
+                    pkcs1v15::VerifyingKey::<Sha256>::new(key)
+                        .verify(msg, &sig)
+                        .is_ok()
+                }
+                OID_SHA384_WITH_RSA => {
+                    pkcs1v15::VerifyingKey::<Sha384>::new(key)
+                        .verify(msg, &sig)
+                        .is_ok()
+                }
+                _ => pkcs1v15::VerifyingKey::<Sha512>::new(key)
+                    .verify(msg, &sig)
+                    .is_ok(),
+            }
+        }
+        OID_ECDSA_WITH_SHA256 => verify_ecdsa(spki, &Sha256::digest(msg), sig)?,
+        OID_ECDSA_WITH_SHA384 => verify_ecdsa(spki, &Sha384::digest(msg), sig)?,
+        OID_ECDSA_WITH_SHA512 => verify_ecdsa(spki, &Sha512::digest(msg), sig)?,
+        OID_ED25519 => {
+            if spki.algorithm.oid != OID_ED25519 {
+                return Ok(false);
+            }
+            let key = ed25519_dalek::VerifyingKey::from_public_key_der(
+                &spki.to_der()?,
+            )?;
+            let sig = ed25519_dalek::Signature::from_slice(sig)?;
+            key.verify(msg, &sig).is_ok()
+        }
+        oid => {
+            return Err(CaError::UnsupportedSignatureAlgorithm { oid }.into())
+        }
+    })
+}
+
+/// Verify a DER encoded ECDSA signature over `digest` with the EC public key
+/// in `spki`.
+fn verify_ecdsa(
+    spki: &SubjectPublicKeyInfoOwned,
+    digest: &[u8],
+    sig: &[u8],
+) -> Result<bool> {
+    if spki.algorithm.oid != OID_EC_PUBLIC_KEY {
+        return Ok(false);
+    }
+    let curve: ObjectIdentifier = spki
+        .algorithm
+        .parameters
+        .as_ref()
+        .ok_or(anyhow!("EC public key has no curve"))?
+        .decode_as()?;
+    let point = spki.subject_public_key.raw_bytes();
+
+    Ok(match curve {
+        OID_SECP256R1 => {
+            let key = p256::ecdsa::VerifyingKey::from_sec1_bytes(point)?;
+            let sig = p256::ecdsa::Signature::from_der(sig)?;
+            key.verify_prehash(digest, &sig).is_ok()
+        }
+        OID_SECP384R1 => {
+            let key = p384::ecdsa::VerifyingKey::from_sec1_bytes(point)?;
+            let sig = p384::ecdsa::Signature::from_der(sig)?;
+            key.verify_prehash(digest, &sig).is_ok()
+        }
+        OID_SECP521R1 => {
+            let key = p521::ecdsa::VerifyingKey::from_sec1_bytes(point)?;
+            let sig = p521::ecdsa::Signature::from_der(sig)?;
+            key.verify_prehash(digest, &sig).is_ok()
+        }
+        oid => return Err(anyhow!("unsupported EC curve: {}", oid)),
+    })
+}
+
+/// This utility function is used to create the directory structure required
+/// for the CA.
+fn bootstrap_ca_dir<P: AsRef<Path>>(
+    spec: &KeySpec,
+    root: P,
+    sandbox: bool,
+) -> Result<()> {
+    let root = root.as_ref();
+    fs::create_dir_all(root).with_context(|| {
+        format!("Failed to create directory \"{}\"", root.display())
+    })?;
+
+    // copy the key spec file to the ca state dir
+    let spec_json = spec
+        .to_json()
+        .context("Failed to serialize KeySpec to json")?;
+    fs::write(root.join(CA_KEY_SPEC), spec_json)?;
+
+    for dir in [CA_CRL_DIR, NEWCERTS_DIR] {
+        fs::create_dir(root.join(dir))
+            .with_context(|| format!("Failed to create directory: {}", dir))?;
+    }
+
+    IssuanceDb::new(root).create()?;
+
+    // write initial serial number to 'serial' (echo 1000 > serial)
+    let init_serial_hex = format!("{:020x}", spec.initial_serial_number);
     debug!(
-        "setting initial serial number to \"{init_serial_hex}\" in file \"{serial}\""
+        "setting initial serial number to \"{init_serial_hex}\" in file \"{CA_SERIAL}\""
     );
-    fs::write(serial, init_serial_hex)?;
-
-    // create & write out an openssl.cnf
-    fs::write(
-        "openssl.cnf",
-        format!(
-            openssl_cnf_fmt!(),
-            key = spec.id,
-            hash = spec.hash,
-            pkcs11_path = pkcs11_lib.as_ref().display(),
-        ),
-    )?;
-
-    Ok(env::set_current_dir(pwd)?)
-}
-
-/// If we've already executed an openssl command successfully it probably
-/// signed something and created an artifact that *MUST* be returned to the
-/// caller. We say *MUST* here because anything we sign must be accounted for
-/// and if we run into an error cleaning up stuff and the error is propagated
-/// to the caller an artifact may be lost. This would be bad so instead, this
-/// function wraps up some operations that we try to do before returning some
-/// data to the caller. Errors are logged as warnings but ignored otherwise.
-fn teardown_warn_only<P: AsRef<Path>>(mut conn: Child, ret_path: P) {
-    if let Err(e) = conn.kill() {
-        warn!("Failed to kill the YubiHSM connector: {}", e);
-    }
-    if let Err(e) = env::set_current_dir(&ret_path) {
-        warn!(
-            "Failed to restore directory to {}: {}",
-            ret_path.as_ref().display(),
-            e
+    fs::write(root.join(CA_SERIAL), init_serial_hex)?;
+
+    // the first CRL issued is number 1 (echo 01 > crlnumber)
+    fs::write(root.join(CA_CRL_NUMBER), "01")?;
+
+    // sandbox CAs get their key from the sandbox keystore
+    if sandbox {
+        fs::write(root.join(CA_SANDBOX), "")?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{profile::DebugPolicy, sandbox::Sandbox};
+    use p384::{
+        ecdsa::{signature::Verifier, Signature, VerifyingKey},
+        pkcs8::EncodePublicKey,
+    };
+    use tempfile::TempDir;
+    use yubihsm::object::Label;
+    use zeroize::Zeroizing;
+
+    const JSON_ROOT: &str = r#"{
+        "common_name": "RoT Identity Root",
+        "id": 1,
+        "algorithm":"Ecp384",
+        "capabilities":"All",
+        "domain":"DOM1",
+        "hash":"Sha384",
+        "label":"identity-root",
+        "purpose":"Identity",
+        "initial_serial_number":"3cc3000000000000000000000000000000000000",
+        "self_signed":true
+    }"#;
+
+    const JSON_INTERMEDIATE: &str = r#"{
+        "common_name": "RoT Identity Intermediate",
+        "id": 2,
+        "algorithm":"Ecp384",
+        "capabilities":"All",
+        "domain":"DOM1",
+        "hash":"Sha384",
+        "label":"identity-intermediate",
+        "purpose":"Identity",
+        "initial_serial_number":"3cc4000000000000000000000000000000000000",
+        "self_signed":false
+    }"#;
+
+    const JSON_RSA_ROOT: &str = r#"{
+        "common_name": "RoT Release Root",
+        "id": 3,
+        "algorithm":"Rsa2048",
+        "capabilities":"All",
+        "domain":"DOM1",
+        "hash":"Sha256",
+        "label":"rot-release-root",
+        "purpose":"RoTReleaseRoot",
+        "initial_serial_number":"3cc5000000000000000000000000000000000000",
+        "self_signed":true
+    }"#;
+
+    fn extension_value<T: AssociatedOid + for<'a> Decode<'a>>(
+        cert: &Certificate,
+    ) -> T {
+        let ext = cert
+            .tbs_certificate
+            .extensions
+            .iter()
+            .flatten()
+            .find(|e| e.extn_id == T::OID)
+            .expect("missing extension");
+        T::from_der(ext.extn_value.as_bytes()).expect("bad extension")
+    }
+
+    /// Create a sandbox keystore in a new temporary directory.
+    fn sandbox() -> Result<(TempDir, Sandbox)> {
+        let dir = TempDir::new()?;
+        let password = Zeroizing::new("password".to_string());
+        let sandbox = Sandbox::new(dir.path(), &password)?;
+        Ok((dir, sandbox))
+    }
+
+    /// Create a sandbox root CA & use it to sign the cert for a sandbox
+    /// intermediate CA. The root CA state is in `root` & the intermediate
+    /// CA state in `intermediate` under the returned directory.
+    fn sandbox_root_and_intermediate(
+    ) -> Result<(TempDir, Sandbox, Ca, Certificate)> {
+        let (dir, sandbox) = sandbox()?;
+        let root_spec = KeySpec::from_str(JSON_ROOT)?;
+        let int_spec = KeySpec::from_str(JSON_INTERMEDIATE)?;
+        sandbox.generate_keyspec(&root_spec)?;
+        sandbox.generate_keyspec(&int_spec)?;
+
+        let profiles = Profiles::default();
+        let root_dir = dir.path().join("root");
+        let root = match Ca::initialize(
+            &root_spec,
+            &root_dir,
+            &sandbox,
+            true,
+            &Provenance::default(),
+            &profiles,
+        )? {
+            CertOrCsr::Cert(_) => Ca::load(&root_dir)?,
+            CertOrCsr::Csr(_) => panic!("root CA produced a CSR"),
+        };
+        let csr = match Ca::initialize(
+            &int_spec,
+            dir.path().join("intermediate"),
+            &sandbox,
+            true,
+            &Provenance::default(),
+            &profiles,
+        )? {
+            CertOrCsr::Csr(csr) => csr,
+            CertOrCsr::Cert(_) => panic!("intermediate CA is self signed"),
+        };
+
+        let spec = CsrSpec {
+            label: Label::from_bytes(b"identity-root")?,
+            csr,
+            validity: None,
+            profile: None,
+        };
+        let provenance = Provenance {
+            csr_spec: None,
+            auth_id: Some(2),
+        };
+        let cert = root.sign_csrspec(
+            &spec,
+            &sandbox,
+            &provenance,
+            &profiles,
+            &HashMap::new(),
+        )?;
+
+        Ok((dir, sandbox, root, Certificate::from_pem(&cert)?))
+    }
+
+    fn sandbox_verifying_key(cert: &Certificate) -> Result<VerifyingKey> {
+        Ok(VerifyingKey::from_sec1_bytes(
+            cert.tbs_certificate
+                .subject_public_key_info
+                .subject_public_key
+                .raw_bytes(),
+        )?)
+    }
+
+    #[test]
+    fn sandbox_root_signs_intermediate() -> Result<()> {
+        let (dir, _, root, cert) = sandbox_root_and_intermediate()?;
+        let root_dir = dir.path().join("root");
+        let root_cert = root.cert()?;
+
+        // the root issued its own cert with the initial serial number
+        assert_eq!(
+            serial_hex(&root_cert.tbs_certificate.serial_number),
+            "3CC3000000000000000000000000000000000000"
+        );
+        assert_eq!(
+            serial_hex(&cert.tbs_certificate.serial_number),
+            "3CC3000000000000000000000000000000000001"
+        );
+        assert_eq!(
+            cert.tbs_certificate.issuer,
+            root_cert.tbs_certificate.subject
+        );
+        assert_eq!(
+            cert.tbs_certificate.subject.to_string(),
+            "CN=RoT Identity Intermediate,OU=SANDBOX,O=Oxide Computer Company,C=US"
+        );
+
+        // the signature on the intermediate cert is from the root key
+        let key = sandbox_verifying_key(&root_cert)?;
+        let sig = Signature::from_der(cert.signature.raw_bytes())?;
+        key.verify(&cert.tbs_certificate.to_der()?, &sig)?;
+
+        let ski: SubjectKeyIdentifier = extension_value(&root_cert);
+        let aki: AuthorityKeyIdentifier = extension_value(&cert);
+        assert_eq!(aki.key_identifier, Some(ski.0));
+        let bc: BasicConstraints = extension_value(&cert);
+        assert!(bc.ca);
+
+        // both certs are recorded in the issuance database
+        let issued = root.issued()?;
+        assert_eq!(issued.len(), 2);
+        assert_eq!(issued[0].auth_id, None);
+        assert_eq!(
+            issued[1].serial,
+            serial_hex(&cert.tbs_certificate.serial_number)
+        );
+        assert_eq!(
+            issued[1].subject,
+            Some(cert.tbs_certificate.subject.to_string())
+        );
+        assert_eq!(issued[1].profile.as_deref(), Some("v3_identity"));
+        assert_eq!(issued[1].auth_id, Some(2));
+        assert_eq!(
+            issued[1].not_after.as_deref(),
+            Some("9999-12-31T23:59:59Z")
+        );
+        assert!(root_dir
+            .join(NEWCERTS_DIR)
+            .join("3CC3000000000000000000000000000000000001.pem")
+            .exists());
+
+        Ok(())
+    }
+
+    #[test]
+    fn sandbox_intermediate_imports_cert() -> Result<()> {
+        let (dir, sandbox, root, cert) = sandbox_root_and_intermediate()?;
+        let intermediate = Ca::load(dir.path().join("intermediate"))?;
+        let cert = cert.to_pem(LineEnding::default())?;
+        let root_cert = root.cert()?.to_pem(LineEnding::default())?;
+
+        // the root cert is for a different key
+        assert!(intermediate
+            .import_cert(&sandbox, root_cert.as_bytes(), root_cert.as_bytes())
+            .is_err());
+        // the intermediate cert wasn't issued by itself
+        assert!(intermediate
+            .import_cert(&sandbox, cert.as_bytes(), cert.as_bytes())
+            .is_err());
+        assert!(intermediate
+            .import_cert(&sandbox, cert.as_bytes(), b"")
+            .is_err());
+        assert!(!intermediate.has_cert());
+
+        intermediate.import_cert(
+            &sandbox,
+            cert.as_bytes(),
+            root_cert.as_bytes(),
+        )?;
+        assert_eq!(intermediate.cert()?.to_pem(LineEnding::default())?, cert);
+        assert!(intermediate
+            .import_cert(&sandbox, cert.as_bytes(), root_cert.as_bytes())
+            .is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn post_issuance_verification() -> Result<()> {
+        let (_dir, sandbox, root, cert) = sandbox_root_and_intermediate()?;
+        let profiles = Profiles::default();
+        let profile = profiles.get("v3_identity")?;
+        let spki = cert.tbs_certificate.subject_public_key_info.clone();
+        let pem = cert.to_pem(LineEnding::default())?;
+
+        assert!(root.verify_issued(&pem, &spki, profile)?.is_empty());
+
+        // the cert doesn't have the extensions from another profile
+        let other = profiles.get("v3_code_signing_rel")?;
+        assert!(!root.verify_issued(&pem, &spki, other)?.is_empty());
+
+        // or the root's key
+        let root_spki = root.cert()?.tbs_certificate.subject_public_key_info;
+        assert!(!root.verify_issued(&pem, &root_spki, profile)?.is_empty());
+
+        // changing the cert breaks the signature
+        let mut forged = cert.clone();
+        forged.tbs_certificate.subject = Name::from_str("CN=forged")?;
+        let forged = forged.to_pem(LineEnding::default())?;
+        assert_eq!(root.verify_issued(&forged, &spki, profile)?.len(), 1);
+
+        // a cert signed by the CA with a wrong extension is caught
+        let wrong = |ext: Extension| -> Result<Vec<String>> {
+            let mut tbs = cert.tbs_certificate.clone();
+            for e in tbs.extensions.iter_mut().flatten() {
+                if e.extn_id == ext.extn_id {
+                    *e = ext.clone();
+                }
+            }
+            let signature = root.sign(&sandbox, &tbs.to_der()?)?;
+            let cert = Certificate {
+                tbs_certificate: tbs,
+                signature_algorithm: cert.signature_algorithm.clone(),
+                signature: BitString::from_bytes(&signature)?,
+            };
+            let pem = cert.to_pem(LineEnding::default())?;
+            root.verify_issued(&pem, &spki, profile)
+        };
+        let problems = wrong(extension(
+            &BasicConstraints {
+                ca: true,
+                path_len_constraint: Some(0),
+            },
+            true,
+        )?)?;
+        assert_eq!(problems.len(), 1);
+        assert!(problems[0].starts_with("basic constraints"));
+
+        let problems = wrong(extension(
+            &KeyUsage(KeyUsages::KeyCertSign | KeyUsages::DigitalSignature),
+            true,
+        )?)?;
+        assert_eq!(problems.len(), 1);
+        assert!(problems[0].starts_with("key usage"));
+
+        let problems = wrong(extension(
+            &SubjectKeyIdentifier(OctetString::new([0u8; 20])?),
+            false,
+        )?)?;
+        assert_eq!(problems.len(), 1);
+        assert!(problems[0].starts_with("subject key identifier"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn unrecorded_cert() -> Result<()> {
+        let (_dir, sandbox, root, cert) = sandbox_root_and_intermediate()?;
+        let profiles = Profiles::default();
+        let pem = cert.to_pem(LineEnding::default())?;
+        root.check_recorded(pem.as_bytes())?;
+
+        // a cert that can't be recorded is still returned
+        fs::remove_dir_all(root.root.join(NEWCERTS_DIR))?;
+        let cert = root.issue(
+            &sandbox,
+            Name::from_str("CN=unrecorded")?,
+            cert.tbs_certificate.subject_public_key_info,
+            profiles.get("v3_identity")?,
+            None,
+            &Provenance::default(),
+        )?;
+        let pem = cert.to_pem(LineEnding::default())?;
+        assert!(matches!(
+            root.check_recorded(pem.as_bytes())
+                .unwrap_err()
+                .downcast_ref(),
+            Some(CaError::Unrecorded { .. })
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn issued_db_limit() -> Result<()> {
+        let (_dir, sandbox, mut root, cert) = sandbox_root_and_intermediate()?;
+        let profiles = Profiles::default();
+        let spki = cert.tbs_certificate.subject_public_key_info;
+        // the limit only applies to CAs with their state in the YubiHSM
+        root.sandbox = false;
+
+        // fill the issuance database to one short of the limit
+        let db = IssuanceDb::new(&root.root);
+        let mut certs = db.read()?;
+        let template = certs[1].clone();
+        while certs.len() < OPAQUE_ISSUED_MAX - 1 {
+            let mut cert = template.clone();
+            cert.serial = format!("{:X}", 0x1000 + certs.len());
+            certs.push(cert);
+        }
+        db.write(&certs)?;
+
+        let issue = || {
+            root.issue(
+                &sandbox,
+                Name::from_str("CN=limit")?,
+                spki.clone(),
+                profiles.get("v3_identity")?,
+                None,
+                &Provenance::default(),
+            )
+        };
+
+        // the last cert that fits is issued & the database fits in the
+        // YubiHSM, even once certs are revoked
+        issue()?;
+        let issued = root.issued()?;
+        assert_eq!(issued.len(), OPAQUE_ISSUED_MAX);
+        let len = compact_db(&issued)?.len();
+        assert!(len <= usize::from(OPAQUE_ISSUED_OBJECTS) * OPAQUE_MAX_LEN);
+        root.revoke(&issued[2].serial, RevokeReason::Superseded)?;
+        assert_eq!(compact_db(&root.issued()?)?.len(), len);
+
+        // the next cert is refused before anything is signed
+        let serial = root.peek_serial()?;
+        assert!(matches!(
+            issue().unwrap_err().downcast_ref(),
+            Some(CaError::IssuedFull { .. })
+        ));
+        assert_eq!(root.peek_serial()?, serial);
+        assert_eq!(root.issued()?.len(), OPAQUE_ISSUED_MAX);
+
+        Ok(())
+    }
+
+    #[test]
+    fn sandbox_chain() -> Result<()> {
+        let (dir, sandbox, root, cert) = sandbox_root_and_intermediate()?;
+        let intermediate = Ca::load(dir.path().join("intermediate"))?;
+        intermediate.import_cert(
+            &sandbox,
+            cert.to_pem(LineEnding::default())?.as_bytes(),
+            root.cert()?.to_pem(LineEnding::default())?.as_bytes(),
+        )?;
+
+        // without the root in the state dir the chain is incomplete
+        let mut cas = HashMap::new();
+        cas.insert(intermediate.name(), Ca::load(&intermediate.root)?);
+        assert!(intermediate.chain(&cas).is_err());
+
+        cas.insert(root.name(), Ca::load(&root.root)?);
+        assert_eq!(intermediate.chain(&cas)?, [cert, root.cert()?]);
+        assert_eq!(root.chain(&cas)?, [root.cert()?]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn cmpa_root_key_table() -> Result<()> {
+        let (dir, sandbox, root, _) = sandbox_root_and_intermediate()?;
+
+        let rsa_spec = KeySpec::from_str(JSON_RSA_ROOT)?;
+        sandbox.generate_keyspec(&rsa_spec)?;
+        let rsa_dir = dir.path().join("rsa-root");
+        Ca::initialize(
+            &rsa_spec,
+            &rsa_dir,
+            &sandbox,
+            true,
+            &Provenance::default(),
+            &Profiles::default(),
+        )?;
+
+        let mut cas = HashMap::new();
+        cas.insert(root.name(), Ca::load(&root.root)?);
+        cas.insert("rot-release-root".to_string(), Ca::load(&rsa_dir)?);
+
+        // the unused slots in the root key table are zeroed
+        let labels = ["rot-release-root".to_string()];
+        let cmpa = Cmpa::new(&labels, &cas)?;
+        let rkh = root_key_hash(&cas["rot-release-root"].cert()?)?;
+        let mut table = rkh.to_vec();
+        table.extend_from_slice(&[0u8; 32 * (ROOT_KEY_SLOTS - 1)]);
+        assert_eq!(cmpa.root_keys.len(), 1);
+        assert_eq!(cmpa.root_keys[0].rkh, hex::encode(rkh));
+        assert_eq!(cmpa.rkth, hex::encode(Sha256::digest(&table)));
+
+        // lpc55 root keys are RSA keys
+        assert!(Cmpa::new(&[root.name()], &cas).is_err());
+        assert!(Cmpa::new(&[], &cas).is_err());
+        assert!(
+            Cmpa::new(&[labels[0].clone(), labels[0].clone()], &cas).is_err()
+        );
+        assert!(Cmpa::new(&["no-such-root".to_string()], &cas).is_err());
+
+        Ok(())
+    }
+
+    /// Create a sandbox RSA 2048 release root CA in `dir`. Returns the CA &
+    /// its key.
+    fn sandbox_rsa_root(
+        dir: &Path,
+        sandbox: &Sandbox,
+    ) -> Result<(Ca, RsaPublicKey)> {
+        let spec = KeySpec::from_str(JSON_RSA_ROOT)?;
+        sandbox.generate_keyspec(&spec)?;
+        let root_dir = dir.join("rsa-root");
+        Ca::initialize(
+            &spec,
+            &root_dir,
+            sandbox,
+            true,
+            &Provenance::default(),
+            &Profiles::default(),
+        )?;
+        let root = Ca::load(&root_dir)?;
+        let spki = &root.cert()?.tbs_certificate.subject_public_key_info;
+        let key = RsaPublicKey::from_public_key_der(&spki.to_der()?)?;
+
+        Ok((root, key))
+    }
+
+    /// A version 1.0 to-be-signed debug credential for the device `uuid`
+    /// granting the `cc_socu` permissions. `key` is both the debug key &
+    /// the signing key.
+    fn debug_credential_tbs(
+        key: &RsaPublicKey,
+        uuid: [u8; 16],
+        cc_socu: u32,
+        root_key_hashes: &[[u8; 32]],
+    ) -> Vec<u8> {
+        let mut rsa_key = vec![0u8; 256 - key.n().to_bytes_be().len()];
+        rsa_key.extend_from_slice(&key.n().to_bytes_be());
+        rsa_key.extend_from_slice(&[0, 1, 0, 1]);
+
+        let mut tbs = vec![1, 0, 0, 0];
+        tbs.extend_from_slice(&0x4u32.to_le_bytes());
+        tbs.extend_from_slice(&uuid);
+        for hash in root_key_hashes {
+            tbs.extend_from_slice(hash);
+        }
+        tbs.extend_from_slice(&rsa_key);
+        tbs.extend_from_slice(&cc_socu.to_le_bytes());
+        tbs.extend_from_slice(&0u32.to_le_bytes());
+        tbs.extend_from_slice(&0x1234u32.to_le_bytes());
+        tbs.extend_from_slice(&rsa_key);
+
+        tbs
+    }
+
+    #[test]
+    fn debug_credential_verify() -> Result<()> {
+        let (dir, sandbox) = sandbox()?;
+        let (root, key) = sandbox_rsa_root(dir.path(), &sandbox)?;
+        let cert = root.cert()?;
+
+        let rkh = root_key_hash(&cert)?;
+        let tbs =
+            debug_credential_tbs(&key, [0xaa; 16], 0x3ff, &[rkh, [0xbb; 32]]);
+        let sig = sandbox.sign_rsa_pkcs1v15_sha256(root.spec.id, &tbs)?;
+        let data = [tbs, sig].concat();
+
+        let dc = DebugCredential::from_bytes(&data)?;
+        assert_eq!(dc.version, (1, 0));
+        assert_eq!(dc.soc_class, 0x4);
+        assert_eq!(dc.uuid, [0xaa; 16]);
+        assert_eq!(dc.root_key_hashes, [rkh, [0xbb; 32]]);
+        assert_eq!(dc.debug_key, key);
+        assert_eq!(dc.cc_socu, 0x3ff);
+        assert_eq!(dc.cc_beacon, 0x1234);
+        assert_eq!(dc.rot_key_index(), Some(0));
+        dc.verify(&root.name(), &cert)?;
+
+        // the second root key is unknown
+        assert!(dc.check_roots(std::slice::from_ref(&cert)).is_err());
+        let mut cmpa = vec![0u8; 512];
+        cmpa[CMPA_ROTKH_OFFSET..CMPA_ROTKH_OFFSET + 32]
+            .copy_from_slice(&dc.root_key_table_hash());
+        dc.check_cmpa(&cmpa)?;
+        assert!(dc.check_cmpa(&[0u8; 512]).is_err());
+
+        // tampering breaks the signature
+        let mut bad = data.clone();
+        bad[4] ^= 1;
+        assert!(DebugCredential::from_bytes(&bad)?
+            .verify(&root.name(), &cert)
+            .is_err());
+        assert!(DebugCredential::from_bytes(&data[..data.len() - 1]).is_err());
+
+        Ok(())
+    }
+
+    fn dcsr_rule(result: Result<()>) -> Option<DcsrRule> {
+        match result.err()?.downcast_ref::<CaError>()? {
+            CaError::DcsrRejected { rule, .. } => Some(*rule),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn dcsr_policy() -> Result<()> {
+        let (dir, sandbox) = sandbox()?;
+        let (root, key) = sandbox_rsa_root(dir.path(), &sandbox)?;
+        let name = root.name();
+
+        let labels = |labels: &[&str]| -> Vec<String> {
+            labels.iter().map(|l| l.to_string()).collect()
+        };
+        assert_eq!(
+            dcsr_rule(check_dcsr_roots(&name, &[])),
+            Some(DcsrRule::RootKeys)
+        );
+        assert_eq!(
+            dcsr_rule(check_dcsr_roots(
+                &name,
+                &labels(&["a", "b", "c", "d", &name])
+            )),
+            Some(DcsrRule::RootKeys)
+        );
+        assert_eq!(
+            dcsr_rule(check_dcsr_roots(&name, &labels(&[&name, "a", &name]))),
+            Some(DcsrRule::RootKeys)
+        );
+        assert_eq!(
+            dcsr_rule(check_dcsr_roots(&name, &labels(&["a", "b"]))),
+            Some(DcsrRule::Signer)
+        );
+        check_dcsr_roots(&name, &labels(&["a", &name]))?;
+
+        // release roots may only sign for allowed devices & permissions
+        let uuid = [0x11; 16];
+        let tbs = debug_credential_tbs(&key, uuid, 0x3, &[[0; 32]]);
+        let mut profiles = Profiles::default();
+        assert_eq!(
+            dcsr_rule(root.check_dcsr(&tbs, &profiles)),
+            Some(DcsrRule::Permissions)
+        );
+        fn policy(profiles: &mut Profiles) -> &mut DebugPolicy {
+            profiles
+                .profiles
+                .get_mut("v3_rot_release_root")
+                .and_then(|p| p.debug_credentials.as_mut())
+                .expect("release roots have a debug credential policy")
+        }
+        policy(&mut profiles).permissions = Some(0x7);
+        assert_eq!(
+            dcsr_rule(root.check_dcsr(&tbs, &profiles)),
+            Some(DcsrRule::Uuid)
+        );
+        policy(&mut profiles).uuids = Some(vec![DeviceUuid(uuid)]);
+        policy(&mut profiles).permissions = Some(0x1);
+        assert_eq!(
+            dcsr_rule(root.check_dcsr(&tbs, &profiles)),
+            Some(DcsrRule::Permissions)
+        );
+        policy(&mut profiles).permissions = Some(0x7);
+        root.check_dcsr(&tbs, &profiles)?;
+
+        policy(&mut profiles).min_key_bits = Some(4096);
+        assert_eq!(
+            dcsr_rule(root.check_dcsr(&tbs, &profiles)),
+            Some(DcsrRule::KeySize)
+        );
+
+        // CAs without a debug credential policy can't sign them
+        let root_profile = profiles.profiles.get_mut("v3_rot_release_root");
+        root_profile.unwrap().debug_credentials = None;
+        assert_eq!(
+            dcsr_rule(root.check_dcsr(&tbs, &profiles)),
+            Some(DcsrRule::Signer)
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn sandbox_root_revokes_intermediate() -> Result<()> {
+        let (dir, sandbox, root, cert) = sandbox_root_and_intermediate()?;
+        let serial = serial_hex(&cert.tbs_certificate.serial_number);
+
+        assert!(root.has_issued(&serial)?);
+        assert!(!root.has_issued("3cc4000000000000000000000000000000000000")?);
+        assert!(root.revoke("1234", RevokeReason::Superseded).is_err());
+        root.revoke(&serial, RevokeReason::KeyCompromise)?;
+        assert!(root.revoke(&serial, RevokeReason::Superseded).is_err());
+
+        // revoking a cert is recorded in the issuance database
+        let revocation = root.issued_cert(&serial)?.unwrap().revocation;
+        assert_eq!(
+            revocation.map(|r| r.reason),
+            Some(RevokeReason::KeyCompromise)
+        );
+
+        let pem = root.crl(&sandbox, 30)?;
+        let (label, der) = pem_rfc7468::decode_vec(pem.as_bytes())?;
+        assert_eq!(label, "X509 CRL");
+        let crl = CertificateList::from_der(&der)?;
+        let tbs = &crl.tbs_cert_list;
+
+        let root_cert = root.cert()?;
+        assert_eq!(tbs.issuer, root_cert.tbs_certificate.subject);
+        let key = sandbox_verifying_key(&root_cert)?;
+        let sig = Signature::from_der(crl.signature.raw_bytes())?;
+        key.verify(&tbs.to_der()?, &sig)?;
+
+        let revoked = tbs.revoked_certificates.as_ref().unwrap();
+        assert_eq!(revoked.len(), 1);
+        assert_eq!(
+            revoked[0].serial_number,
+            cert.tbs_certificate.serial_number
+        );
+        let reason = revoked[0].crl_entry_extensions.as_ref().unwrap();
+        assert_eq!(
+            CrlReason::from_der(reason[0].extn_value.as_bytes())?,
+            CrlReason::KeyCompromise
+        );
+
+        // each CRL gets the next CRL number
+        let number = tbs
+            .crl_extensions
+            .iter()
+            .flatten()
+            .find(|e| e.extn_id == CrlNumber::OID)
+            .unwrap();
+        let number = CrlNumber::from_der(number.extn_value.as_bytes())?;
+        assert_eq!(number.0.as_bytes(), [1]);
+        root.crl(&sandbox, 30)?;
+        assert_eq!(
+            fs::read_to_string(dir.path().join("root").join(CA_CRL_NUMBER))?,
+            "03"
+        );
+        assert!(dir.path().join("root/crl/02.crl.pem").exists());
+
+        Ok(())
+    }
+
+    // A keystore whose RNG only produces zeros: no key is held by it.
+    struct ZeroRng;
+
+    impl KeyStore for ZeroRng {
+        fn sign_rsa_pkcs1v15_sha256(&self, _: Id, _: &[u8]) -> Result<Vec<u8>> {
+            Err(anyhow!("no keys"))
+        }
+
+        fn sign_ecdsa_prehash(&self, _: Id, _: &[u8]) -> Result<Vec<u8>> {
+            Err(anyhow!("no keys"))
+        }
+
+        fn sign_ed25519(&self, _: Id, _: &[u8]) -> Result<Vec<u8>> {
+            Err(anyhow!("no keys"))
+        }
+
+        fn public_key_der(&self, _: Id) -> Result<Vec<u8>> {
+            Err(anyhow!("no keys"))
+        }
+
+        fn random_bytes(&self, len: usize) -> Result<Vec<u8>> {
+            Ok(vec![0; len])
+        }
+    }
+
+    #[test]
+    fn serial_policy() -> Result<()> {
+        let (dir, sandbox) = sandbox()?;
+        let profiles = Profiles::default();
+        sandbox.generate_keyspec(&KeySpec::from_str(JSON_ROOT)?)?;
+
+        // each CA gets the same key, it's the serial numbers we're after
+        let init = |name: &str, policy: &str| -> Result<Ca> {
+            let spec = KeySpec::from_str(&JSON_ROOT.replace(
+                r#""self_signed":true"#,
+                &format!(r#""self_signed":true,"serial_policy":"{}""#, policy),
+            ))?;
+            let root = dir.path().join(name);
+            Ca::initialize(
+                &spec,
+                &root,
+                &sandbox,
+                true,
+                &Provenance::default(),
+                &profiles,
+            )?;
+            Ca::load(&root)
+        };
+
+        // a sequential serial number that's already been issued is never
+        // reused
+        let ca = init("sequential", "sequential")?;
+        let serial = ca.cert()?.tbs_certificate.serial_number;
+        assert_eq!(
+            serial_hex(&serial),
+            "3CC3000000000000000000000000000000000000"
+        );
+        fs::write(ca.root.join(CA_SERIAL), serial_hex(&serial))?;
+        assert!(matches!(
+            ca.next_serial(&sandbox).unwrap_err().downcast_ref(),
+            Some(CaError::SerialReused { .. })
+        ));
+
+        // random serial numbers are positive, at most 20 bytes & don't
+        // touch the sequential serial number
+        let ca = init("random", "random")?;
+        let serial = ca.cert()?.tbs_certificate.serial_number;
+        assert_ne!(
+            serial_hex(&serial),
+            "3CC3000000000000000000000000000000000000"
+        );
+        for _ in 0..16 {
+            let serial = ca.next_serial(&sandbox)?;
+            let bytes = serial.as_bytes();
+            assert!(bytes.len() <= SERIAL_LEN);
+            assert!(bytes[0] < 0x80);
+        }
+        assert_eq!(
+            ca.peek_serial()?,
+            BigUint::parse_bytes(
+                b"3cc3000000000000000000000000000000000000",
+                16
+            )
+            .unwrap()
+        );
+
+        // we give up if every serial number drawn has been issued
+        assert!(matches!(
+            ca.next_serial(&ZeroRng).unwrap_err().downcast_ref(),
+            Some(CaError::SerialCollision { .. })
+        ));
+
+        // prefixed serial numbers keep the prefix
+        let mut ca = init("prefix", "prefix:3cc5")?;
+        assert!(serial_hex(&ca.cert()?.tbs_certificate.serial_number)
+            .starts_with("3CC5"));
+        assert_eq!(ca.next_serial(&sandbox)?.as_bytes()[..2], [0x3c, 0xc5]);
+
+        // prefixes that set the sign bit or leave too little room for the
+        // random bytes are an error, not a panic
+        for prefix in
+            [vec![0x80], vec![0x3c; SERIAL_LEN - SERIAL_MIN_RANDOM + 1]]
+        {
+            ca.spec.serial_policy = Some(SerialPolicy::Prefix(prefix));
+            assert!(matches!(
+                ca.next_serial(&sandbox).unwrap_err().downcast_ref(),
+                Some(CaError::BadSerialPolicy { .. })
+            ));
+        }
+
+        Ok(())
+    }
+
+    fn csr_rule(result: Result<Vec<u8>>) -> Option<CsrRule> {
+        match result.err()?.downcast_ref::<CaError>()? {
+            CaError::CsrRejected { rule, .. } => Some(*rule),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn csr_preflight() -> Result<()> {
+        let (dir, sandbox, root, _) = sandbox_root_and_intermediate()?;
+        let intermediate = Ca::load(dir.path().join("intermediate"))?;
+        let spki = SubjectPublicKeyInfoOwned::from_der(
+            &sandbox.public_key_der(intermediate.spec.id)?,
+        )?;
+        let csr = intermediate.csr(&sandbox, intermediate.subject()?, spki)?;
+        let spec = |csr: &CertReq| -> Result<CsrSpec> {
+            Ok(CsrSpec {
+                label: Label::from_bytes(b"identity-root")?,
+                csr: csr.to_pem(LineEnding::default())?,
+                validity: None,
+                profile: None,
+            })
+        };
+        let provenance = Provenance::default();
+        let mut profiles = Profiles::default();
+        let sign = |csr: &CertReq, profiles: &Profiles, cas: &[&str]| {
+            let cas = cas
+                .iter()
+                .map(|name| {
+                    let ca = Ca::load(dir.path().join(name)).unwrap();
+                    (ca.name(), ca)
+                })
+                .collect();
+            root.sign_csrspec(
+                &spec(csr)?,
+                &sandbox,
+                &provenance,
+                profiles,
+                &cas,
+            )
+        };
+
+        // the intermediate's key is a CA key & it's been certified
+        assert_eq!(
+            csr_rule(sign(&csr, &profiles, &["intermediate"])),
+            Some(CsrRule::KeyReuse)
+        );
+        assert_eq!(
+            csr_rule(sign(&csr, &profiles, &["root"])),
+            Some(CsrRule::KeyReuse)
+        );
+
+        let mut forged = csr.clone();
+        forged.info.subject = Name::from_str("CN=forged,O=Oxide,C=US")?;
+        assert_eq!(
+            csr_rule(sign(&forged, &profiles, &[])),
+            Some(CsrRule::ProofOfPossession)
+        );
+
+        let no_cn = intermediate.csr(
+            &sandbox,
+            Name::from_str("O=Oxide,C=US")?,
+            csr.info.public_key.clone(),
+        )?;
+        assert_eq!(
+            csr_rule(sign(&no_cn, &profiles, &[])),
+            Some(CsrRule::SubjectPolicy)
+        );
+
+        let mut with_ext = intermediate.csr(
+            &sandbox,
+            csr.info.subject.clone(),
+            csr.info.public_key.clone(),
+        )?;
+        with_ext.info.attributes.insert(
+            ExtensionReq(vec![extension(
+                &BasicConstraints {
+                    ca: true,
+                    path_len_constraint: None,
+                },
+                true,
+            )?])
+            .try_into()?,
+        )?;
+        let signature =
+            intermediate.sign(&sandbox, &with_ext.info.to_der()?)?;
+        with_ext.signature = BitString::from_bytes(&signature)?;
+        assert_eq!(
+            csr_rule(sign(&with_ext, &profiles, &[])),
+            Some(CsrRule::RequestedExtensions)
+        );
+
+        profiles
+            .profiles
+            .get_mut("v3_identity")
+            .unwrap()
+            .key_algorithms = vec![KeyAlgorithm::Ed25519];
+        assert_eq!(
+            csr_rule(sign(&csr, &profiles, &[])),
+            Some(CsrRule::KeyAlgorithm)
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn validity_period() -> Result<()> {
+        let not_before = DateTime::new(2025, 1, 1, 0, 0, 0)?;
+        let issuer = DateTime::new(2035, 1, 1, 0, 0, 0)?;
+        let profiles = Profiles::default();
+        let profile = profiles.get("v3_identity")?;
+        let requested = ValiditySpec {
+            not_before: Some(not_before),
+            not_after: None,
+            days: Some(365),
+        };
+
+        let validity = cert_validity(profile, Some(&requested), Some(issuer))?;
+        assert_eq!(validity.not_before, x509_time(not_before)?);
+        assert_eq!(
+            validity.not_after,
+            Time::UtcTime(UtcTime::from_date_time(DateTime::new(
+                2026, 1, 1, 0, 0, 0
+            )?)?)
+        );
+
+        // without an end to the validity period certs expire with their
+        // issuer, or never if they're self signed
+        let validity = cert_validity(profile, None, Some(issuer))?;
+        assert_eq!(validity.not_after.to_date_time(), issuer);
+        let validity = cert_validity(profile, None, None)?;
+        assert_eq!(
+            validity.not_after,
+            Time::GeneralTime(GeneralizedTime::from_date_time(no_expiry()?))
+        );
+
+        let requested = ValiditySpec {
+            days: Some(20 * 365),
+            ..requested
+        };
+        assert!(cert_validity(profile, Some(&requested), Some(issuer)).is_err());
+        let requested = ValiditySpec {
+            not_before: None,
+            not_after: Some(not_before),
+            days: None,
+        };
+        assert!(cert_validity(profile, Some(&requested), None).is_err());
+
+        // profiles may limit the validity period
+        let limited = Profile {
+            max_validity_days: Some(365),
+            ..profile.clone()
+        };
+        let requested = ValiditySpec {
+            not_before: Some(not_before),
+            not_after: None,
+            days: None,
+        };
+        let validity = cert_validity(&limited, Some(&requested), Some(issuer))?;
+        assert_eq!(
+            validity.not_after.to_date_time(),
+            DateTime::new(2026, 1, 1, 0, 0, 0)?
+        );
+        let requested = ValiditySpec {
+            days: Some(366),
+            ..requested
+        };
+        assert!(cert_validity(&limited, Some(&requested), None).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn csr_subject_policy() -> Result<()> {
+        let ca_subject = Name::from_str("CN=root,O=Oxide,C=US")?;
+        let subject = Name::from_str("CN=foo,L=Emeryville,O=Oxide,C=US")?;
+        assert_eq!(
+            policy_subject(&subject, &SUBJECT_POLICY, &ca_subject)?.to_string(),
+            "CN=foo,O=Oxide,C=US"
+        );
+
+        let subject = Name::from_str("CN=foo,C=US")?;
+        assert!(policy_subject(&subject, &SUBJECT_POLICY, &ca_subject).is_err());
+
+        // attributes are put in the cert in the order of the policy
+        let policy: Vec<PolicyRule> = ["CN=supplied", "L=optional", "O=match"]
+            .iter()
+            .map(|r| r.parse())
+            .collect::<Result<_, _>>()?;
+        let subject = Name::from_str("CN=foo,L=Emeryville,O=Oxide,C=US")?;
+        assert_eq!(
+            policy_subject(&subject, &policy, &ca_subject)?.to_string(),
+            "O=Oxide,L=Emeryville,CN=foo"
+        );
+        let subject = Name::from_str("CN=foo,O=Other")?;
+        assert!(policy_subject(&subject, &policy, &ca_subject).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn signature_algorithms() -> Result<()> {
+        let (dir, sandbox) = sandbox()?;
+
+        // every pairing of key & hash allowed by KeySpec can sign a cert
+        let pairings = [
+            ("Rsa2048", Some("Sha256"), OID_SHA256_WITH_RSA),
+            ("Ecp256", Some("Sha256"), OID_ECDSA_WITH_SHA256),
+            ("Ecp384", Some("Sha384"), OID_ECDSA_WITH_SHA384),
+            ("Ecp521", Some("Sha512"), OID_ECDSA_WITH_SHA512),
+            ("Ed25519", None, OID_ED25519),
+        ];
+        for (i, (algorithm, hash, oid)) in pairings.into_iter().enumerate() {
+            let hash = match hash {
+                Some(hash) => format!(r#""hash":"{}","#, hash),
+                None => String::new(),
+            };
+            let json = JSON_ROOT
+                .replace(r#""id": 1"#, &format!(r#""id": {}"#, i + 1))
+                .replace(
+                    r#""algorithm":"Ecp384""#,
+                    &format!(r#""algorithm":"{}""#, algorithm),
+                )
+                .replace(r#""hash":"Sha384","#, &hash);
+            let spec = KeySpec::from_str(&json)?;
+            sandbox.generate_keyspec(&spec)?;
+
+            let root = dir.path().join(algorithm);
+            Ca::initialize(
+                &spec,
+                &root,
+                &sandbox,
+                true,
+                &Provenance::default(),
+                &Profiles::default(),
+            )?;
+            let cert = Ca::load(&root)?.cert()?;
+            assert_eq!(cert.signature_algorithm.oid, oid, "{}", algorithm);
+            assert!(verify_signature(
+                oid,
+                &cert.tbs_certificate.subject_public_key_info,
+                &cert.tbs_certificate.to_der()?,
+                cert.signature.raw_bytes(),
+            )?);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn ca_subject() -> Result<()> {
+        let mut ca = Ca {
+            root: PathBuf::new(),
+            spec: KeySpec::from_str(JSON_ROOT)?,
+            sandbox: true,
+        };
+        assert_eq!(
+            ca.subject()?.to_string(),
+            "CN=RoT Identity Root,OU=SANDBOX,O=Oxide Computer Company,C=US"
         );
+
+        ca.spec.subject = Some(vec![
+            "C=CA".parse()?,
+            "L=Toronto".parse()?,
+            "O=Example".parse()?,
+        ]);
+        assert_eq!(
+            ca.subject()?.to_string(),
+            "CN=RoT Identity Root,OU=SANDBOX,O=Example,L=Toronto,C=CA"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn profile_extensions() -> Result<()> {
+        let profiles = Profiles::from_str(
+            r#"{
+                "version": 1,
+                "profiles": {
+                    "intermediate": {
+                        "ca": true,
+                        "path_len": 0,
+                        "key_usage": ["keyCertSign", "cRLSign"],
+                        "name_constraints": {
+                            "permitted": ["DNS:example.com", "IP:10.0.0.0/12"]
+                        },
+                        "extensions": [
+                            { "oid": "1.3.6.1.4.1.57551.9", "value": "0500" }
+                        ]
+                    }
+                }
+            }"#,
+        )?;
+        let profile = profiles.get("intermediate")?;
+        let spki = SubjectPublicKeyInfoOwned::from_der(
+            p384::SecretKey::random(&mut rand::rngs::OsRng)
+                .public_key()
+                .to_public_key_der()?
+                .as_bytes(),
+        )?;
+        let extensions = extensions(profile, &spki, &[0u8; 20])?;
+        let value = |oid| {
+            extensions
+                .iter()
+                .find(|e| e.extn_id == oid)
+                .expect("missing extension")
+        };
+
+        let bc = BasicConstraints::from_der(
+            value(BasicConstraints::OID).extn_value.as_bytes(),
+        )?;
+        assert!(bc.ca);
+        assert_eq!(bc.path_len_constraint, Some(0));
+        let usage =
+            KeyUsage::from_der(value(KeyUsage::OID).extn_value.as_bytes())?;
+        assert!(usage.key_cert_sign() && usage.crl_sign());
+        assert!(!usage.digital_signature());
+
+        // no EKU or policies in the profile
+        assert!(
+            !extensions.iter().any(|e| e.extn_id == ExtendedKeyUsage::OID
+                || e.extn_id == CertificatePolicies::OID)
+        );
+
+        let nc = value(NameConstraints::OID);
+        assert!(nc.critical);
+        let nc = NameConstraints::from_der(nc.extn_value.as_bytes())?;
+        let permitted = nc.permitted_subtrees.unwrap();
+        assert_eq!(
+            permitted[1].base,
+            GeneralName::IpAddress(OctetString::new([
+                10, 0, 0, 0, 0xff, 0xf0, 0, 0
+            ])?)
+        );
+        assert!(nc.excluded_subtrees.is_none());
+
+        let custom = value(ObjectIdentifier::new_unwrap("1.3.6.1.4.1.57551.9"));
+        assert!(!custom.critical);
+        assert_eq!(custom.extn_value.as_bytes(), [0x05, 0x00]);
+
+        Ok(())
     }
 }
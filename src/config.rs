@@ -7,6 +7,7 @@ use log::{error, warn};
 use lpc55_sign::debug_auth::DebugCredentialSigningRequest;
 use num_bigint::BigUint;
 use serde::{Deserialize, Serialize};
+use serde_with::{DeserializeFromStr, SerializeDisplay};
 use std::{
     fmt::{self, Display, Formatter},
     fs,
@@ -14,6 +15,7 @@ use std::{
     str::FromStr,
 };
 use thiserror::Error;
+use x509_cert::der::DateTime;
 use yubihsm::{
     asymmetric,
     object::{Id, Label},
@@ -23,6 +25,14 @@ use yubihsm::{
 pub const KEYSPEC_EXT: &str = ".keyspec.json";
 pub const CSRSPEC_EXT: &str = ".csrspec.json";
 pub const DCSRSPEC_EXT: &str = ".dcsrspec.json";
+pub const IMAGESPEC_EXT: &str = ".imagespec.json";
+
+/// The length of a random serial number in bytes: the longest RFC 5280
+/// allows.
+pub const SERIAL_LEN: usize = 20;
+
+/// The least number of random bytes in a serial number with a prefix.
+pub const SERIAL_MIN_RANDOM: usize = 8;
 
 #[derive(Error, Debug)]
 pub enum ConfigError {
@@ -43,21 +53,88 @@ pub enum ConfigError {
 
     #[error("Unsupported Domain")]
     UnsupportedDomain,
+
+    #[error("key spec must include at least one domain")]
+    NoDomain,
+
+    #[error(
+        "keys with purpose {purpose:?} require capability \"{capability}\""
+    )]
+    MissingCapability {
+        purpose: Purpose,
+        capability: String,
+    },
+
+    #[error(
+        "keys with purpose {purpose:?} don't need capability \"{capability}\""
+    )]
+    ExcessCapability {
+        purpose: Purpose,
+        capability: String,
+    },
+
+    #[error("{algorithm:?} keys don't use a separate hash, remove \"hash\"")]
+    UnexpectedHash { algorithm: asymmetric::Algorithm },
+
+    #[error("{algorithm:?} keys require a \"hash\"")]
+    MissingHash { algorithm: asymmetric::Algorithm },
+
+    #[error("hash {hash:?} can't be used with {algorithm:?} keys")]
+    HashMismatch {
+        algorithm: asymmetric::Algorithm,
+        hash: Hash,
+    },
+
+    #[error("validity may include \"not_after\" or \"days\" but not both")]
+    AmbiguousValidity,
+
+    #[error("validity \"not_after\" must be later than \"not_before\"")]
+    EmptyValidity,
+
+    #[error("key spec requires a \"common_name\" or a CN in its \"subject\"")]
+    NoCommonName,
+
+    #[error(
+        "key spec \"common_name\" doesn't match the CN in its \"subject\""
+    )]
+    CommonNameMismatch,
+
+    #[error("invalid subject attribute: \"{attr}\"")]
+    BadNameAttribute { attr: String },
+
+    #[error("subject policy has more than one rule for {attr:?}")]
+    DuplicatePolicyRule { attr: NameAttribute },
+
+    #[error(
+        "invalid serial policy: \"{policy}\", expected \"sequential\", \
+        \"random\" or \"prefix:<hex>\""
+    )]
+    BadSerialPolicy { policy: String },
 }
 
 // These structs duplicate data from the yubihsm crate
 // The Algorithm and Domain types serialize but won't deserialize
 #[derive(Copy, Clone, Debug, Deserialize, PartialEq, Serialize)]
 enum OksAlgorithm {
+    Rsa2048,
+    Rsa3072,
     Rsa4096,
+    Ecp256,
     Ecp384,
+    Ecp521,
+    Ed25519,
 }
 
 impl From<OksAlgorithm> for asymmetric::Algorithm {
     fn from(val: OksAlgorithm) -> Self {
         match val {
+            OksAlgorithm::Rsa2048 => asymmetric::Algorithm::Rsa2048,
+            OksAlgorithm::Rsa3072 => asymmetric::Algorithm::Rsa3072,
             OksAlgorithm::Rsa4096 => asymmetric::Algorithm::Rsa4096,
+            OksAlgorithm::Ecp256 => asymmetric::Algorithm::EcP256,
             OksAlgorithm::Ecp384 => asymmetric::Algorithm::EcP384,
+            OksAlgorithm::Ecp521 => asymmetric::Algorithm::EcP521,
+            OksAlgorithm::Ed25519 => asymmetric::Algorithm::Ed25519,
         }
     }
 }
@@ -67,34 +144,139 @@ impl TryFrom<asymmetric::Algorithm> for OksAlgorithm {
 
     fn try_from(val: asymmetric::Algorithm) -> Result<Self, Self::Error> {
         match val {
+            asymmetric::Algorithm::Rsa2048 => Ok(OksAlgorithm::Rsa2048),
+            asymmetric::Algorithm::Rsa3072 => Ok(OksAlgorithm::Rsa3072),
             asymmetric::Algorithm::Rsa4096 => Ok(OksAlgorithm::Rsa4096),
+            asymmetric::Algorithm::EcP256 => Ok(OksAlgorithm::Ecp256),
             asymmetric::Algorithm::EcP384 => Ok(OksAlgorithm::Ecp384),
+            asymmetric::Algorithm::EcP521 => Ok(OksAlgorithm::Ecp521),
+            asymmetric::Algorithm::Ed25519 => Ok(OksAlgorithm::Ed25519),
             _ => Err(ConfigError::UnsupportedAlgorithm),
         }
     }
 }
 
-#[derive(Debug, Deserialize, PartialEq, Serialize)]
+/// KeySpec fields that describe a set of things (domains, capabilities) may
+/// be either a single value or a list of values in JSON. A single value is
+/// what older key specs contain and it's what we write back out whenever the
+/// set has exactly one member.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(untagged)]
+enum OneOrMany<T> {
+    One(T),
+    Many(Vec<T>),
+}
+
+impl<T> OneOrMany<T> {
+    fn iter(&self) -> std::slice::Iter<'_, T> {
+        match self {
+            OneOrMany::One(t) => std::slice::from_ref(t).iter(),
+            OneOrMany::Many(v) => v.iter(),
+        }
+    }
+}
+
+impl<T> From<Vec<T>> for OneOrMany<T> {
+    fn from(mut val: Vec<T>) -> Self {
+        if val.len() == 1 {
+            OneOrMany::One(val.remove(0))
+        } else {
+            OneOrMany::Many(val)
+        }
+    }
+}
+
+/// A set with a single member is equal to that member.
+impl<T: PartialEq> PartialEq<T> for OneOrMany<T> {
+    fn eq(&self, other: &T) -> bool {
+        let mut iter = self.iter();
+        iter.next() == Some(other) && iter.next().is_none()
+    }
+}
+
+#[derive(Copy, Clone, Debug, Deserialize, PartialEq, Serialize)]
 enum OksDomain {
     DOM1,
+    DOM2,
+    DOM3,
+    DOM4,
+    DOM5,
+    DOM6,
+    DOM7,
+    DOM8,
+    DOM9,
+    DOM10,
+    DOM11,
+    DOM12,
+    DOM13,
+    DOM14,
+    DOM15,
+    DOM16,
 }
 
 impl From<OksDomain> for Domain {
     fn from(val: OksDomain) -> Self {
         match val {
             OksDomain::DOM1 => Domain::DOM1,
+            OksDomain::DOM2 => Domain::DOM2,
+            OksDomain::DOM3 => Domain::DOM3,
+            OksDomain::DOM4 => Domain::DOM4,
+            OksDomain::DOM5 => Domain::DOM5,
+            OksDomain::DOM6 => Domain::DOM6,
+            OksDomain::DOM7 => Domain::DOM7,
+            OksDomain::DOM8 => Domain::DOM8,
+            OksDomain::DOM9 => Domain::DOM9,
+            OksDomain::DOM10 => Domain::DOM10,
+            OksDomain::DOM11 => Domain::DOM11,
+            OksDomain::DOM12 => Domain::DOM12,
+            OksDomain::DOM13 => Domain::DOM13,
+            OksDomain::DOM14 => Domain::DOM14,
+            OksDomain::DOM15 => Domain::DOM15,
+            OksDomain::DOM16 => Domain::DOM16,
         }
     }
 }
 
-impl TryFrom<Domain> for OksDomain {
+const OKS_DOMAINS: [OksDomain; 16] = [
+    OksDomain::DOM1,
+    OksDomain::DOM2,
+    OksDomain::DOM3,
+    OksDomain::DOM4,
+    OksDomain::DOM5,
+    OksDomain::DOM6,
+    OksDomain::DOM7,
+    OksDomain::DOM8,
+    OksDomain::DOM9,
+    OksDomain::DOM10,
+    OksDomain::DOM11,
+    OksDomain::DOM12,
+    OksDomain::DOM13,
+    OksDomain::DOM14,
+    OksDomain::DOM15,
+    OksDomain::DOM16,
+];
+
+impl From<&OneOrMany<OksDomain>> for Domain {
+    fn from(val: &OneOrMany<OksDomain>) -> Self {
+        val.iter()
+            .fold(Domain::empty(), |acc, d| acc | Domain::from(*d))
+    }
+}
+
+impl TryFrom<Domain> for OneOrMany<OksDomain> {
     type Error = ConfigError;
 
     fn try_from(val: Domain) -> Result<Self, Self::Error> {
-        match val {
-            Domain::DOM1 => Ok(OksDomain::DOM1),
-            _ => Err(ConfigError::UnsupportedDomain),
+        if val.is_empty() {
+            return Err(ConfigError::NoDomain);
         }
+
+        let domains: Vec<OksDomain> = OKS_DOMAINS
+            .into_iter()
+            .filter(|d| val.contains(Domain::from(*d)))
+            .collect();
+
+        Ok(domains.into())
     }
 }
 
@@ -120,39 +302,198 @@ impl TryFrom<Label> for OksLabel {
     }
 }
 
-#[derive(Debug, Deserialize, PartialEq, Serialize)]
+/// Capabilities that may be granted to an asymmetric key. The names are
+/// those used by Yubico's tools. `All` is kept for compatibility with older
+/// key specs and grants every capability the YubiHSM knows about.
+#[derive(Copy, Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
 enum OksCapability {
+    #[serde(rename = "All")]
     All,
+    SignPkcs,
+    SignPss,
+    SignEcdsa,
+    SignEddsa,
+    DecryptPkcs,
+    DecryptOaep,
+    DeriveEcdh,
+    ExportableUnderWrap,
+    SignSshCertificate,
+    SignAttestationCertificate,
 }
 
+/// The named capabilities in the order they're written to a key spec.
+const OKS_CAPABILITIES: [OksCapability; 10] = [
+    OksCapability::SignPkcs,
+    OksCapability::SignPss,
+    OksCapability::SignEcdsa,
+    OksCapability::SignEddsa,
+    OksCapability::DecryptPkcs,
+    OksCapability::DecryptOaep,
+    OksCapability::DeriveEcdh,
+    OksCapability::ExportableUnderWrap,
+    OksCapability::SignSshCertificate,
+    OksCapability::SignAttestationCertificate,
+];
+
 impl From<OksCapability> for Capability {
     fn from(val: OksCapability) -> Self {
         match val {
             OksCapability::All => Capability::all(),
+            OksCapability::SignPkcs => Capability::SIGN_PKCS,
+            OksCapability::SignPss => Capability::SIGN_PSS,
+            OksCapability::SignEcdsa => Capability::SIGN_ECDSA,
+            OksCapability::SignEddsa => Capability::SIGN_EDDSA,
+            OksCapability::DecryptPkcs => Capability::DECRYPT_PKCS,
+            OksCapability::DecryptOaep => Capability::DECRYPT_OAEP,
+            OksCapability::DeriveEcdh => Capability::DERIVE_ECDH,
+            OksCapability::ExportableUnderWrap => {
+                Capability::EXPORTABLE_UNDER_WRAP
+            }
+            OksCapability::SignSshCertificate => {
+                Capability::SIGN_SSH_CERTIFICATE
+            }
+            OksCapability::SignAttestationCertificate => {
+                Capability::SIGN_ATTESTATION_CERTIFICATE
+            }
         }
     }
 }
 
-impl TryFrom<Capability> for OksCapability {
+impl Display for OksCapability {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let str = match self {
+            OksCapability::All => "All",
+            OksCapability::SignPkcs => "sign-pkcs",
+            OksCapability::SignPss => "sign-pss",
+            OksCapability::SignEcdsa => "sign-ecdsa",
+            OksCapability::SignEddsa => "sign-eddsa",
+            OksCapability::DecryptPkcs => "decrypt-pkcs",
+            OksCapability::DecryptOaep => "decrypt-oaep",
+            OksCapability::DeriveEcdh => "derive-ecdh",
+            OksCapability::ExportableUnderWrap => "exportable-under-wrap",
+            OksCapability::SignSshCertificate => "sign-ssh-certificate",
+            OksCapability::SignAttestationCertificate => {
+                "sign-attestation-certificate"
+            }
+        };
+        write!(f, "{}", str)
+    }
+}
+
+impl From<&OneOrMany<OksCapability>> for Capability {
+    fn from(val: &OneOrMany<OksCapability>) -> Self {
+        val.iter()
+            .fold(Capability::empty(), |acc, c| acc | Capability::from(*c))
+    }
+}
+
+impl TryFrom<Capability> for OneOrMany<OksCapability> {
     type Error = ConfigError;
 
     fn try_from(val: Capability) -> Result<Self, Self::Error> {
         if val == Capability::all() {
-            Ok(OksCapability::All)
-        } else {
-            Err(ConfigError::BadCapability)
+            return Ok(OneOrMany::One(OksCapability::All));
+        }
+
+        let caps: Vec<OksCapability> = OKS_CAPABILITIES
+            .into_iter()
+            .filter(|c| val.contains(Capability::from(*c)))
+            .collect();
+
+        // anything left over doesn't have a name we can write out
+        let named = caps
+            .iter()
+            .fold(Capability::empty(), |acc, c| acc | Capability::from(*c));
+        if named != val {
+            return Err(ConfigError::BadCapability);
         }
+
+        Ok(caps.into())
     }
 }
 
+/// Check that the capabilities granted to a key are the ones it needs for
+/// `purpose`. Key specs using the legacy `All` aren't checked.
+fn check_capabilities(
+    algorithm: asymmetric::Algorithm,
+    purpose: Purpose,
+    capabilities: Capability,
+) -> Result<(), ConfigError> {
+    if capabilities == Capability::all() {
+        return Ok(());
+    }
+
+    let required = purpose.required_capabilities(algorithm)?;
+    let allowed = purpose.allowed_capabilities(algorithm)?;
+    let named = |flags: Capability| {
+        OKS_CAPABILITIES
+            .into_iter()
+            .find(|c| flags.contains(Capability::from(*c)))
+            .map(|c| c.to_string())
+            .unwrap_or_else(|| format!("{:?}", flags))
+    };
+
+    let missing = required - capabilities;
+    if !missing.is_empty() {
+        return Err(ConfigError::MissingCapability {
+            purpose,
+            capability: named(missing),
+        });
+    }
+
+    let excess = capabilities - allowed;
+    if !excess.is_empty() {
+        return Err(ConfigError::ExcessCapability {
+            purpose,
+            capability: named(excess),
+        });
+    }
+
+    Ok(())
+}
+
 #[derive(Copy, Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
 pub enum Hash {
     Sha256,
     Sha384,
+    Sha512,
+}
+
+/// Check that `hash` is appropriate for keys of type `algorithm`. Ed25519
+/// hashes the message as part of the signature so it can't be configured.
+/// ECDSA keys must be paired with the hash matching the size of the curve.
+/// RSA keys must use SHA-256: it's the only digest the YubiHSM client signs
+/// with PKCS#1 v1.5 & the only one lpc55 RoTs verify.
+fn check_hash(
+    algorithm: asymmetric::Algorithm,
+    hash: Option<Hash>,
+) -> Result<(), ConfigError> {
+    use asymmetric::Algorithm::*;
+
+    let hash = match (algorithm, hash) {
+        (Ed25519, None) => return Ok(()),
+        (Ed25519, Some(_)) => {
+            return Err(ConfigError::UnexpectedHash { algorithm })
+        }
+        (_, None) => return Err(ConfigError::MissingHash { algorithm }),
+        (_, Some(hash)) => hash,
+    };
+
+    match (algorithm, hash) {
+        (Rsa2048 | Rsa3072 | Rsa4096, Hash::Sha256)
+        | (EcP256, Hash::Sha256)
+        | (EcP384, Hash::Sha384)
+        | (EcP521, Hash::Sha512) => Ok(()),
+        (Rsa2048 | Rsa3072 | Rsa4096 | EcP256 | EcP384 | EcP521, _) => {
+            Err(ConfigError::HashMismatch { algorithm, hash })
+        }
+        _ => Err(ConfigError::UnsupportedAlgorithm),
+    }
 }
 
-/// Values in this enum are mapped to OpenSSL config sections for v3 extensions.
-/// All certs issued by the OKS are assumed to be intermediate CAs.
+/// The role of a key in the OKS. This determines the capabilities the key
+/// requires & the default certificate profile for CAs using the key.
 #[derive(Copy, Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
 pub enum Purpose {
     RoTDevelopmentRoot,
@@ -162,8 +503,9 @@ pub enum Purpose {
     Identity,
 }
 
-/// NOTE: These strings correspond to config sections for v3 extensions in the
-/// openssl.cnf.
+/// NOTE: These strings are the names of the default certificate profiles for
+/// each purpose. They're the names of the v3 extension sections from the
+/// openssl.cnf the profiles were taken from.
 impl Display for Purpose {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
         let str = match self {
@@ -177,19 +519,268 @@ impl Display for Purpose {
     }
 }
 
+impl Purpose {
+    /// Every key created by the OKS backs a CA so it must be able to sign
+    /// certificates. The OKS uses PKCS#1 v1.5 for RSA keys. The RoT roots
+    /// and code signing keys also sign debug credentials & lpc55 images but
+    /// these use the same mechanism. The RoT roots are the trust anchors in
+    /// the root key table of each RoT & can't be replaced if they're lost so
+    /// they must be exportable under wrap to be backed up.
+    fn required_capabilities(
+        &self,
+        algorithm: asymmetric::Algorithm,
+    ) -> Result<Capability, ConfigError> {
+        use asymmetric::Algorithm::*;
+
+        let sign = match algorithm {
+            Rsa2048 | Rsa3072 | Rsa4096 => Capability::SIGN_PKCS,
+            EcP256 | EcP384 | EcP521 => Capability::SIGN_ECDSA,
+            Ed25519 => Capability::SIGN_EDDSA,
+            _ => return Err(ConfigError::UnsupportedAlgorithm),
+        };
+
+        Ok(match self {
+            Purpose::RoTDevelopmentRoot | Purpose::RoTReleaseRoot => {
+                sign | Capability::EXPORTABLE_UNDER_WRAP
+            }
+            Purpose::RoTDevelopmentCodeSigning
+            | Purpose::RoTReleaseCodeSigning
+            | Purpose::Identity => sign,
+        })
+    }
+
+    /// The capabilities a key may be granted: the ones it requires & for
+    /// keys that don't require it, exportable under wrap so that they can
+    /// be backed up.
+    fn allowed_capabilities(
+        &self,
+        algorithm: asymmetric::Algorithm,
+    ) -> Result<Capability, ConfigError> {
+        Ok(self.required_capabilities(algorithm)?
+            | Capability::EXPORTABLE_UNDER_WRAP)
+    }
+}
+
+/// The attribute types that may be used in the subject DN of a cert. These
+/// are the short names used in DN strings.
+#[derive(Copy, Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub enum NameAttribute {
+    C,
+    ST,
+    L,
+    O,
+    OU,
+    CN,
+}
+
+impl NameAttribute {
+    /// The name of the attribute type from X.520.
+    pub fn long_name(&self) -> &'static str {
+        match self {
+            NameAttribute::C => "countryName",
+            NameAttribute::ST => "stateOrProvinceName",
+            NameAttribute::L => "localityName",
+            NameAttribute::O => "organizationName",
+            NameAttribute::OU => "organizationalUnitName",
+            NameAttribute::CN => "commonName",
+        }
+    }
+}
+
+impl FromStr for NameAttribute {
+    type Err = ConfigError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "C" => Ok(NameAttribute::C),
+            "ST" => Ok(NameAttribute::ST),
+            "L" => Ok(NameAttribute::L),
+            "O" => Ok(NameAttribute::O),
+            "OU" => Ok(NameAttribute::OU),
+            "CN" => Ok(NameAttribute::CN),
+            _ => Err(ConfigError::BadNameAttribute {
+                attr: s.to_string(),
+            }),
+        }
+    }
+}
+
+/// Split an "ATTR=value" string on the first '='.
+fn split_attr(s: &str) -> Result<(NameAttribute, &str), ConfigError> {
+    let (attr, value) =
+        s.split_once('=').ok_or(ConfigError::BadNameAttribute {
+            attr: s.to_string(),
+        })?;
+
+    Ok((attr.trim().parse()?, value))
+}
+
+/// A single attribute in a subject DN, written as "ATTR=value" in specs,
+/// e.g. "O=Oxide Computer Company".
+#[derive(Clone, Debug, DeserializeFromStr, PartialEq, SerializeDisplay)]
+pub struct NameAttributeValue {
+    pub attr: NameAttribute,
+    pub value: String,
+}
+
+impl FromStr for NameAttributeValue {
+    type Err = ConfigError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (attr, value) = split_attr(s)?;
+        if value.is_empty() {
+            return Err(ConfigError::BadNameAttribute {
+                attr: s.to_string(),
+            });
+        }
+
+        Ok(Self {
+            attr,
+            value: value.to_string(),
+        })
+    }
+}
+
+impl Display for NameAttributeValue {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}={}", self.attr, self.value)
+    }
+}
+
+/// How an attribute in the subject of a CSR is treated by a CA. These
+/// work like the `policy` sections of an openssl.cnf.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum PolicyMatch {
+    /// The CSR must have the attribute with the same value as the subject
+    /// of the CA.
+    Match,
+    /// The CSR must have the attribute.
+    Supplied,
+    /// The attribute is copied from the CSR if it's there.
+    Optional,
+}
+
+/// The rule for an attribute in a subject policy, written as
+/// "ATTR=rule" in specs, e.g. "O=match".
+#[derive(Clone, Debug, DeserializeFromStr, PartialEq, SerializeDisplay)]
+pub struct PolicyRule {
+    pub attr: NameAttribute,
+    pub rule: PolicyMatch,
+}
+
+impl FromStr for PolicyRule {
+    type Err = ConfigError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let rule = match split_attr(s)? {
+            (attr, "match") => (attr, PolicyMatch::Match),
+            (attr, "supplied") => (attr, PolicyMatch::Supplied),
+            (attr, "optional") => (attr, PolicyMatch::Optional),
+            _ => {
+                return Err(ConfigError::BadNameAttribute {
+                    attr: s.to_string(),
+                })
+            }
+        };
+
+        Ok(Self {
+            attr: rule.0,
+            rule: rule.1,
+        })
+    }
+}
+
+impl Display for PolicyRule {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let rule = match self.rule {
+            PolicyMatch::Match => "match",
+            PolicyMatch::Supplied => "supplied",
+            PolicyMatch::Optional => "optional",
+        };
+        write!(f, "{:?}={}", self.attr, rule)
+    }
+}
+
+/// How a CA picks the serial number for each cert it issues, written as
+/// "sequential", "random" or "prefix:<hex>" in specs.
+#[derive(Clone, Debug, DeserializeFromStr, PartialEq, SerializeDisplay)]
+pub enum SerialPolicy {
+    /// Count up from the `initial_serial_number`.
+    Sequential,
+    /// `SERIAL_LEN` random bytes from the RNG of the keystore.
+    Random,
+    /// The prefix followed by random bytes, `SERIAL_LEN` bytes in all.
+    Prefix(Vec<u8>),
+}
+
+impl FromStr for SerialPolicy {
+    type Err = ConfigError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let bad = || ConfigError::BadSerialPolicy {
+            policy: s.to_string(),
+        };
+
+        match s {
+            "sequential" => Ok(SerialPolicy::Sequential),
+            "random" => Ok(SerialPolicy::Random),
+            _ => {
+                let prefix = s.strip_prefix("prefix:").ok_or_else(bad)?;
+                let prefix = hex::decode(prefix).map_err(|_| bad())?;
+                // serial numbers are positive & the prefix must leave room
+                // for enough random bytes
+                match prefix.first() {
+                    Some(b)
+                        if *b < 0x80
+                            && prefix.len()
+                                <= SERIAL_LEN - SERIAL_MIN_RANDOM =>
+                    {
+                        Ok(SerialPolicy::Prefix(prefix))
+                    }
+                    _ => Err(bad()),
+                }
+            }
+        }
+    }
+}
+
+impl Display for SerialPolicy {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            SerialPolicy::Sequential => write!(f, "sequential"),
+            SerialPolicy::Random => write!(f, "random"),
+            SerialPolicy::Prefix(prefix) => {
+                write!(f, "prefix:{}", hex::encode(prefix))
+            }
+        }
+    }
+}
+
 #[derive(Debug, Deserialize, PartialEq, Serialize)]
 struct OksKeySpec {
-    pub common_name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub common_name: Option<String>,
     pub id: Id,
     pub algorithm: OksAlgorithm,
-    pub capabilities: OksCapability,
-    pub domain: OksDomain,
-    pub hash: Hash,
+    pub capabilities: OneOrMany<OksCapability>,
+    pub domain: OneOrMany<OksDomain>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hash: Option<Hash>,
     pub label: OksLabel,
     pub purpose: Purpose,
     #[serde(with = "hex")]
     pub initial_serial_number: [u8; 20],
     pub self_signed: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub validity: Option<ValiditySpec>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub subject: Option<Vec<NameAttributeValue>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub subject_policy: Option<Vec<PolicyRule>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub profile: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub serial_policy: Option<SerialPolicy>,
 }
 
 impl TryFrom<&KeySpec> for OksKeySpec {
@@ -197,7 +788,7 @@ impl TryFrom<&KeySpec> for OksKeySpec {
 
     fn try_from(spec: &KeySpec) -> Result<Self, Self::Error> {
         Ok(OksKeySpec {
-            common_name: spec.common_name.clone(),
+            common_name: Some(spec.common_name.clone()),
             id: spec.id,
             algorithm: spec.algorithm.try_into()?,
             capabilities: spec.capabilities.try_into()?,
@@ -219,6 +810,11 @@ impl TryFrom<&KeySpec> for OksKeySpec {
                 }
             },
             self_signed: spec.self_signed,
+            validity: spec.validity.clone(),
+            subject: spec.subject.clone(),
+            subject_policy: spec.subject_policy.clone(),
+            profile: spec.profile.clone(),
+            serial_policy: spec.serial_policy.clone(),
         })
     }
 }
@@ -230,11 +826,30 @@ pub struct KeySpec {
     pub algorithm: asymmetric::Algorithm,
     pub capabilities: Capability,
     pub domain: Domain,
-    pub hash: Hash,
+    /// The digest used when signing, `None` for algorithms like Ed25519 that
+    /// don't take one.
+    pub hash: Option<Hash>,
     pub label: Label,
     pub purpose: Purpose,
     pub initial_serial_number: BigUint,
     pub self_signed: bool,
+    /// The validity period for the cert of a self signed CA. This is
+    /// ignored for CAs that produce a CSR.
+    pub validity: Option<ValiditySpec>,
+    /// The subject DN for the CA in the order the attributes appear in the
+    /// cert / CSR. Without one the CA gets the Oxide defaults. The CN is
+    /// `common_name` if the subject doesn't include one.
+    pub subject: Option<Vec<NameAttributeValue>>,
+    /// The policy applied to the subject of CSRs signed by the CA. The
+    /// attributes are put in the cert in this order and attributes without
+    /// a rule are dropped. Without one the CA uses the default policy.
+    pub subject_policy: Option<Vec<PolicyRule>>,
+    /// The name of the certificate profile for the CA's cert. Without one
+    /// the CA uses the default profile for its purpose.
+    pub profile: Option<String>,
+    /// How the CA picks the serial number for each cert it issues. Without
+    /// one the CA counts up from `initial_serial_number`.
+    pub serial_policy: Option<SerialPolicy>,
 }
 
 impl KeySpec {
@@ -242,6 +857,14 @@ impl KeySpec {
         let spec: OksKeySpec = self.try_into()?;
         Ok(serde_json::to_string(&spec)?)
     }
+
+    /// The name of the certificate profile used by the CA.
+    pub fn profile_name(&self) -> String {
+        match &self.profile {
+            Some(profile) => profile.clone(),
+            None => self.purpose.to_string(),
+        }
+    }
 }
 
 impl FromStr for KeySpec {
@@ -258,12 +881,50 @@ impl TryFrom<OksKeySpec> for KeySpec {
     type Error = ConfigError;
 
     fn try_from(spec: OksKeySpec) -> Result<Self, Self::Error> {
+        let algorithm = spec.algorithm.into();
+        check_hash(algorithm, spec.hash)?;
+
+        let capabilities = Capability::from(&spec.capabilities);
+        check_capabilities(algorithm, spec.purpose, capabilities)?;
+
+        let domain = Domain::from(&spec.domain);
+        if domain.is_empty() {
+            return Err(ConfigError::NoDomain);
+        }
+
+        if let Some(validity) = &spec.validity {
+            validity.check()?;
+        }
+
+        let subject_cn = spec
+            .subject
+            .iter()
+            .flatten()
+            .find(|a| a.attr == NameAttribute::CN)
+            .map(|a| a.value.clone());
+        let common_name = match (spec.common_name, subject_cn) {
+            (Some(cn), None) | (None, Some(cn)) => cn,
+            (Some(cn), Some(subject_cn)) if cn == subject_cn => cn,
+            (Some(_), Some(_)) => return Err(ConfigError::CommonNameMismatch),
+            (None, None) => return Err(ConfigError::NoCommonName),
+        };
+
+        if let Some(policy) = &spec.subject_policy {
+            for (i, rule) in policy.iter().enumerate() {
+                if policy[..i].iter().any(|r| r.attr == rule.attr) {
+                    return Err(ConfigError::DuplicatePolicyRule {
+                        attr: rule.attr,
+                    });
+                }
+            }
+        }
+
         Ok(KeySpec {
-            common_name: spec.common_name,
+            common_name,
             id: spec.id,
-            algorithm: spec.algorithm.into(),
-            capabilities: spec.capabilities.into(),
-            domain: spec.domain.into(),
+            algorithm,
+            capabilities,
+            domain,
             hash: spec.hash,
             label: spec.label.try_into()?,
             purpose: spec.purpose,
@@ -271,8 +932,64 @@ impl TryFrom<OksKeySpec> for KeySpec {
                 &spec.initial_serial_number,
             ),
             self_signed: spec.self_signed,
+            validity: spec.validity,
+            subject: spec.subject,
+            subject_policy: spec.subject_policy,
+            profile: spec.profile,
+            serial_policy: spec.serial_policy,
+        })
+    }
+}
+
+serde_with::serde_conv!(
+    DateTimeAsString,
+    DateTime,
+    |time: &DateTime| time.to_string(),
+    |string: String| -> Result<DateTime, anyhow::Error> {
+        DateTime::from_str(&string).map_err(|e| {
+            anyhow::anyhow!(
+                "invalid time \"{}\", expected RFC 3339 UTC: {}",
+                string,
+                e
+            )
         })
     }
+);
+
+/// The validity period requested for a cert. Times are in RFC 3339 form
+/// and must be UTC, e.g. "2025-01-01T00:00:00Z". `not_before` defaults to
+/// the time the cert is issued. The cert expires at `not_after` or `days`
+/// after `not_before`: only one of them may be provided. Without either the
+/// cert gets the longest validity period allowed for its profile.
+#[serde_with::serde_as]
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct ValiditySpec {
+    #[serde_as(as = "Option<DateTimeAsString>")]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub not_before: Option<DateTime>,
+
+    #[serde_as(as = "Option<DateTimeAsString>")]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub not_after: Option<DateTime>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub days: Option<u32>,
+}
+
+impl ValiditySpec {
+    fn check(&self) -> Result<(), ConfigError> {
+        if self.not_after.is_some() && self.days.is_some() {
+            return Err(ConfigError::AmbiguousValidity);
+        }
+
+        match (self.not_before, self.not_after, self.days) {
+            (Some(before), Some(after), _) if after <= before => {
+                Err(ConfigError::EmptyValidity)
+            }
+            (_, _, Some(0)) => Err(ConfigError::EmptyValidity),
+            _ => Ok(()),
+        }
+    }
 }
 
 /// This struct / type is an intermediate state between the CsrSpec struct
@@ -281,14 +998,25 @@ impl TryFrom<OksKeySpec> for KeySpec {
 struct OksCsrSpec {
     pub label: OksLabel,
     pub csr: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub validity: Option<ValiditySpec>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub profile: Option<String>,
 }
 
 impl From<&CsrSpec> for OksCsrSpec {
     fn from(value: &CsrSpec) -> Self {
         let label = OksLabel(value.label.to_string());
         let csr: Vec<_> = value.csr.lines().map(str::to_string).collect();
+        let validity = value.validity.clone();
+        let profile = value.profile.clone();
 
-        Self { label, csr }
+        Self {
+            label,
+            csr,
+            validity,
+            profile,
+        }
     }
 }
 
@@ -302,6 +1030,12 @@ pub struct CsrSpec {
     pub label: Label,
     /// the CSR to be signed
     pub csr: String,
+    /// The validity period for the cert, see `ValiditySpec` for the
+    /// defaults.
+    pub validity: Option<ValiditySpec>,
+    /// The name of the certificate profile for the cert. This may be
+    /// omitted if the CA is only allowed to issue one.
+    pub profile: Option<String>,
 }
 
 impl CsrSpec {
@@ -326,9 +1060,15 @@ impl TryFrom<OksCsrSpec> for CsrSpec {
     type Error = ConfigError;
 
     fn try_from(spec: OksCsrSpec) -> Result<Self, Self::Error> {
+        if let Some(validity) = &spec.validity {
+            validity.check()?;
+        }
+
         Ok(CsrSpec {
             label: spec.label.try_into()?,
             csr: spec.csr.join("\n"),
+            validity: spec.validity,
+            profile: spec.profile,
         })
     }
 }
@@ -377,6 +1117,32 @@ pub struct DcsrSpec {
     pub dcsr: DebugCredentialSigningRequest,
 }
 
+/// An lpc55 firmware image to be signed by a CA key. The image and cert
+/// chain are files, relative paths are relative to the directory holding the
+/// ImageSpec.
+#[serde_with::serde_as]
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ImageSpec {
+    /// The key / CA that should be used to sign the image.
+    #[serde_as(as = "LabelAsString")]
+    pub label: Label,
+
+    /// Root keys matching those registered in target device's CMPA.
+    #[serde_as(as = "Vec<LabelAsString>")]
+    pub root_labels: Vec<Label>,
+
+    /// The lpc55 image to be signed.
+    pub image: PathBuf,
+
+    /// PEM encoded certs from the cert for one of the root keys to the cert
+    /// for the signing key.
+    pub cert_chain: PathBuf,
+
+    /// The address the image executes from.
+    #[serde(default)]
+    pub execution_address: u32,
+}
+
 #[derive(Copy, Clone, Debug, Eq, PartialEq)]
 pub enum Transport {
     Http,
@@ -417,8 +1183,8 @@ mod tests {
     fn test_rsa4k_deserialize() -> Result<()> {
         let key_spec: OksKeySpec = serde_json::from_str(&JSON_RSA4K)?;
         assert_eq!(
-            key_spec.common_name,
-            "Gimlet RoT Stage0 Code Signing Engineering Offline CA A",
+            key_spec.common_name.as_deref(),
+            Some("Gimlet RoT Stage0 Code Signing Engineering Offline CA A"),
         );
         assert_eq!(key_spec.id, 1);
         assert_eq!(key_spec.capabilities, OksCapability::All);
@@ -462,7 +1228,10 @@ mod tests {
     #[test]
     fn test_ecp384_deserialize() -> Result<()> {
         let key_spec: OksKeySpec = serde_json::from_str(&JSON_ECP384)?;
-        assert_eq!(key_spec.common_name, "RoT Identity Signing Offline CA",);
+        assert_eq!(
+            key_spec.common_name.as_deref(),
+            Some("RoT Identity Signing Offline CA"),
+        );
         assert_eq!(key_spec.id, 2);
         assert_eq!(key_spec.capabilities, OksCapability::All);
         assert_eq!(key_spec.domain, OksDomain::DOM1);
@@ -495,6 +1264,307 @@ mod tests {
         Ok(())
     }
 
+    const JSON_SUBJECT: &str = r#"{
+        "id": 2,
+        "algorithm":"Ecp384",
+        "capabilities":"All",
+        "domain":"DOM1",
+        "hash":"Sha384",
+        "label":"rot-identity-signing-ca",
+        "purpose":"Identity",
+        "initial_serial_number":"3cc3000000000000000000000000000000000000",
+        "self_signed":true,
+        "subject":["C=CA","L=Toronto","O=Example, Inc.","CN=Example Root"],
+        "subject_policy":["O=match","OU=optional","CN=supplied"]
+    }"#;
+
+    #[test]
+    fn test_key_spec_subject() -> Result<()> {
+        let key_spec = KeySpec::from_str(JSON_SUBJECT)?;
+        // the common name comes from the subject
+        assert_eq!(key_spec.common_name, "Example Root");
+        let subject: Vec<String> = key_spec
+            .subject
+            .iter()
+            .flatten()
+            .map(|a| a.to_string())
+            .collect();
+        assert_eq!(
+            subject,
+            ["C=CA", "L=Toronto", "O=Example, Inc.", "CN=Example Root"]
+        );
+        assert_eq!(
+            key_spec.subject_policy.as_ref().unwrap()[0],
+            PolicyRule {
+                attr: NameAttribute::O,
+                rule: PolicyMatch::Match,
+            }
+        );
+
+        // the subject & policy survive a round trip through JSON
+        let json = key_spec.to_json()?;
+        assert!(json.contains(
+            r#""subject_policy":["O=match","OU=optional","CN=supplied"]"#
+        ));
+
+        let bad = [
+            JSON_SUBJECT
+                .replace(r#""id": 2"#, r#""common_name":"Other","id": 2"#),
+            JSON_SUBJECT.replace(r#""CN=Example Root""#, r#""XX=foo""#),
+            JSON_SUBJECT.replace(r#","CN=Example Root""#, ""),
+            JSON_SUBJECT.replace(r#""OU=optional""#, r#""O=optional""#),
+            JSON_SUBJECT.replace(r#""OU=optional""#, r#""OU=maybe""#),
+        ];
+        for json in bad {
+            assert!(KeySpec::from_str(&json).is_err(), "{}", json);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_key_spec_serial_policy() -> Result<()> {
+        // without a policy the CA counts up
+        assert_eq!(KeySpec::from_str(JSON_SUBJECT)?.serial_policy, None);
+
+        let with_policy = |policy: &str| {
+            JSON_SUBJECT.replace(
+                r#""self_signed":true,"#,
+                &format!(r#""self_signed":true,"serial_policy":"{}","#, policy),
+            )
+        };
+        for (policy, expected) in [
+            ("sequential", SerialPolicy::Sequential),
+            ("random", SerialPolicy::Random),
+            ("prefix:3cc5", SerialPolicy::Prefix(vec![0x3c, 0xc5])),
+        ] {
+            let key_spec = KeySpec::from_str(&with_policy(policy))?;
+            assert_eq!(key_spec.serial_policy, Some(expected));
+            // the policy survives a round trip through JSON
+            assert!(key_spec
+                .to_json()?
+                .contains(&format!(r#""serial_policy":"{}""#, policy)));
+        }
+
+        // the prefix must be hex, positive & leave room for 8 random bytes
+        for policy in [
+            "counter",
+            "prefix:",
+            "prefix:xyz",
+            "prefix:80",
+            "prefix:01020304050607080910111213",
+        ] {
+            assert!(
+                KeySpec::from_str(&with_policy(policy)).is_err(),
+                "{}",
+                policy
+            );
+        }
+        KeySpec::from_str(&with_policy("prefix:010203040506070809101112"))?;
+        KeySpec::from_str(&with_policy("prefix:7f"))?;
+
+        Ok(())
+    }
+
+    const JSON_ED25519: &str = r#"{
+        "common_name": "Ed25519 Offline CA",
+        "id": 3,
+        "algorithm":"Ed25519",
+        "capabilities":"All",
+        "domain":"DOM1",
+        "label":"ed25519-ca",
+        "purpose":"Identity",
+        "initial_serial_number":"ed00000000000000000000000000000000000000",
+        "self_signed":true
+    }"#;
+
+    #[test]
+    fn test_ed25519_no_hash() -> Result<()> {
+        let key_spec = KeySpec::from_str(JSON_ED25519)?;
+        assert_eq!(key_spec.algorithm, asymmetric::Algorithm::Ed25519);
+        assert_eq!(key_spec.hash, None);
+
+        // round trip leaves `hash` out
+        assert!(!key_spec.to_json()?.contains("hash"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_ed25519_with_hash() {
+        let json =
+            JSON_ED25519.replace(r#""id": 3,"#, r#""id": 3, "hash":"Sha512","#);
+        assert!(matches!(
+            KeySpec::from_str(&json),
+            Err(ConfigError::UnexpectedHash { .. })
+        ));
+    }
+
+    #[test]
+    fn test_missing_hash() {
+        let json = JSON_IDENTITY.replace(r#""hash":"Sha384","#, "");
+        assert!(matches!(
+            KeySpec::from_str(&json),
+            Err(ConfigError::MissingHash { .. })
+        ));
+    }
+
+    #[test]
+    fn test_ecdsa_hash_mismatch() -> Result<()> {
+        let json = JSON_IDENTITY.replace("Ecp384", "Ecp521");
+        assert!(matches!(
+            KeySpec::from_str(&json),
+            Err(ConfigError::HashMismatch { .. })
+        ));
+
+        let json = json.replace("Sha384", "Sha512");
+        let key_spec = KeySpec::from_str(&json)?;
+        assert_eq!(key_spec.algorithm, asymmetric::Algorithm::EcP521);
+        assert_eq!(key_spec.hash, Some(Hash::Sha512));
+        Ok(())
+    }
+
+    #[test]
+    fn test_rsa_sha256_only() -> Result<()> {
+        let json = JSON_RSA4K.replace("Rsa4096", "Rsa3072");
+        let key_spec = KeySpec::from_str(&json)?;
+        assert_eq!(key_spec.algorithm, asymmetric::Algorithm::Rsa3072);
+        assert_eq!(key_spec.hash, Some(Hash::Sha256));
+
+        for hash in ["Sha384", "Sha512"] {
+            assert!(matches!(
+                KeySpec::from_str(&json.replace("Sha256", hash)),
+                Err(ConfigError::HashMismatch { .. })
+            ));
+        }
+        Ok(())
+    }
+
+    const JSON_FINE_GRAINED: &str = r#"{
+        "common_name": "RoT Identity Signing Offline CA",
+        "id": 2,
+        "algorithm":"Ecp384",
+        "capabilities":["sign-ecdsa", "exportable-under-wrap"],
+        "domain":["DOM1", "DOM16"],
+        "hash":"Sha384",
+        "label":"rot-identity-signing-ca",
+        "purpose":"Identity",
+        "initial_serial_number":"3cc3000000000000000000000000000000000000",
+        "self_signed":true
+    }"#;
+
+    #[test]
+    fn test_fine_grained_capabilities() -> Result<()> {
+        let key_spec = KeySpec::from_str(JSON_FINE_GRAINED)?;
+        assert_eq!(
+            key_spec.capabilities,
+            Capability::SIGN_ECDSA | Capability::EXPORTABLE_UNDER_WRAP
+        );
+        assert_eq!(key_spec.domain, Domain::DOM1 | Domain::DOM16);
+
+        // and back again
+        let oks: OksKeySpec = serde_json::from_str(&key_spec.to_json()?)?;
+        assert_eq!(
+            oks.capabilities,
+            OneOrMany::Many(vec![
+                OksCapability::SignEcdsa,
+                OksCapability::ExportableUnderWrap
+            ])
+        );
+        assert_eq!(
+            oks.domain,
+            OneOrMany::Many(vec![OksDomain::DOM1, OksDomain::DOM16])
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_single_capability_round_trip() -> Result<()> {
+        let json = JSON_FINE_GRAINED
+            .replace(
+                r#"["sign-ecdsa", "exportable-under-wrap"]"#,
+                r#""sign-ecdsa""#,
+            )
+            .replace(r#"["DOM1", "DOM16"]"#, r#""DOM3""#);
+        let key_spec = KeySpec::from_str(&json)?;
+        assert_eq!(key_spec.capabilities, Capability::SIGN_ECDSA);
+        assert_eq!(key_spec.domain, Domain::DOM3);
+
+        let oks: OksKeySpec = serde_json::from_str(&key_spec.to_json()?)?;
+        assert_eq!(oks.capabilities, OksCapability::SignEcdsa);
+        assert_eq!(oks.domain, OksDomain::DOM3);
+        Ok(())
+    }
+
+    #[test]
+    fn test_missing_capability() {
+        let json = JSON_FINE_GRAINED.replace("sign-ecdsa", "sign-pkcs");
+        assert!(matches!(
+            KeySpec::from_str(&json),
+            Err(ConfigError::MissingCapability { .. })
+        ));
+    }
+
+    #[test]
+    fn test_purpose_capabilities() -> Result<()> {
+        // without exportable-under-wrap an identity CA key is fine but a
+        // RoT root key can't be backed up
+        let json = JSON_FINE_GRAINED
+            .replace(r#", "exportable-under-wrap""#, "")
+            .replace("Ecp384", "Rsa4096")
+            .replace("sign-ecdsa", "sign-pkcs")
+            .replace("Sha384", "Sha256");
+        KeySpec::from_str(&json)?;
+        let with_purpose = |purpose: &str| {
+            json.replace(
+                r#""purpose":"Identity""#,
+                &format!(r#""purpose":"{}""#, purpose),
+            )
+        };
+        for purpose in ["RoTDevelopmentCodeSigning", "RoTReleaseCodeSigning"] {
+            KeySpec::from_str(&with_purpose(purpose))?;
+        }
+        for purpose in ["RoTDevelopmentRoot", "RoTReleaseRoot"] {
+            assert!(matches!(
+                KeySpec::from_str(&with_purpose(purpose)),
+                Err(ConfigError::MissingCapability { capability, .. })
+                    if capability == "exportable-under-wrap"
+            ));
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_excess_capability() {
+        let json = JSON_FINE_GRAINED
+            .replace("exportable-under-wrap", "sign-attestation-certificate");
+        assert!(matches!(
+            KeySpec::from_str(&json),
+            Err(ConfigError::ExcessCapability { .. })
+        ));
+    }
+
+    #[test]
+    fn test_bad_capability_and_domain() {
+        let json = JSON_FINE_GRAINED.replace("sign-ecdsa", "sign-everything");
+        assert!(matches!(
+            KeySpec::from_str(&json),
+            Err(ConfigError::BadKeySpec { .. })
+        ));
+
+        let json = JSON_FINE_GRAINED.replace("DOM16", "DOM17");
+        assert!(matches!(
+            KeySpec::from_str(&json),
+            Err(ConfigError::BadKeySpec { .. })
+        ));
+
+        let json = JSON_FINE_GRAINED.replace(r#"["DOM1", "DOM16"]"#, "[]");
+        assert!(matches!(
+            KeySpec::from_str(&json),
+            Err(ConfigError::NoDomain)
+        ));
+    }
+
     // NOTE: this CSR in this struct is designed for testing and is not a
     // valid CSR
     const JSON_CSR: &str = r#"{
@@ -506,6 +1576,37 @@ mod tests {
         ]
     }"#;
 
+    #[test]
+    fn test_csr_spec_validity() -> Result<()> {
+        let json = r#"{
+            "label":"rot-identity-signing-ca",
+            "csr":["OQ=="],
+            "validity":{"not_before":"2025-01-01T00:00:00Z","days":365}
+        }"#;
+        let csr_spec = CsrSpec::from_str(json)?;
+        let validity = csr_spec.validity.unwrap();
+        assert_eq!(
+            validity.not_before,
+            Some(DateTime::new(2025, 1, 1, 0, 0, 0)?)
+        );
+        assert_eq!(validity.not_after, None);
+        assert_eq!(validity.days, Some(365));
+
+        for validity in [
+            r#"{"not_after":"2026-01-01T00:00:00Z","days":365}"#,
+            r#"{"not_before":"2026-01-01T00:00:00Z","not_after":"2025-01-01T00:00:00Z"}"#,
+            r#"{"not_after":"2026-01-01 00:00:00"}"#,
+        ] {
+            let json = format!(
+                r#"{{"label":"ca","csr":["OQ=="],"validity":{}}}"#,
+                validity
+            );
+            assert!(CsrSpec::from_str(&json).is_err());
+        }
+
+        Ok(())
+    }
+
     #[test]
     fn test_oks_csr_deserialize() -> Result<()> {
         let csr_spec: OksCsrSpec = serde_json::from_str(&JSON_CSR)?;
@@ -551,6 +1652,11 @@ mod tests {
         let csr_spec = CsrSpec {
             label: Label::from_str(label)?,
             csr,
+            validity: Some(ValiditySpec {
+                days: Some(365),
+                ..Default::default()
+            }),
+            profile: Some("v3_code_signing_dev".to_string()),
         };
         let json = csr_spec.json()?;
 
@@ -563,11 +1669,42 @@ mod tests {
                 "0000000000000000000000000000000000000000000000000000000000000000".to_string(),
                 "1111111111111111111111111111111111111111111111111111111111111111".to_string(),
                 "-----END CERTIFICATE REQUEST-----".to_string(),
-            ]
+            ],
+            validity: Some(ValiditySpec {
+                not_before: None,
+                not_after: None,
+                days: Some(365),
+            }),
+            profile: Some("v3_code_signing_dev".to_string()),
         };
 
         assert_eq!(expected, oks_csr_spec);
 
         Ok(())
     }
+
+    #[test]
+    fn image_spec_deserialize() -> Result<()> {
+        let json = r#"{
+            "label": "rot-stage0-signing-root-eng-a",
+            "root_labels": [
+                "rot-stage0-signing-root-eng-a",
+                "rot-stage0-signing-root-eng-b"
+            ],
+            "image": "stage0.bin",
+            "cert_chain": "../certs/root-eng-a.cert.pem"
+        }"#;
+        let spec: ImageSpec = serde_json::from_str(json)?;
+
+        assert_eq!(spec.label.to_string(), "rot-stage0-signing-root-eng-a");
+        assert_eq!(spec.root_labels.len(), 2);
+        assert_eq!(spec.image, PathBuf::from("stage0.bin"));
+        assert_eq!(
+            spec.cert_chain,
+            PathBuf::from("../certs/root-eng-a.cert.pem")
+        );
+        assert_eq!(spec.execution_address, 0);
+
+        Ok(())
+    }
 }
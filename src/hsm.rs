@@ -3,21 +3,39 @@
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
 use anyhow::{Context, Result};
+use base64ct::{Base64UrlUnpadded, Encoding};
+use clap::Args;
 use log::{debug, error, info};
 use pem_rfc7468::LineEnding;
 use rand_core::{impls, CryptoRng, Error as RngError, RngCore};
+use rsa::{
+    pkcs1::EncodeRsaPublicKey, pkcs8::EncodePublicKey, traits::PublicKeyParts,
+    RsaPublicKey,
+};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
 use std::{
+    fmt::{self, Display, Formatter},
     fs,
     io::{self, Write},
     path::{Path, PathBuf},
     str::FromStr,
 };
 use thiserror::Error;
+use x509_cert::{
+    der::{
+        asn1::{BitString, ObjectIdentifier},
+        Encode,
+    },
+    spki::{AlgorithmIdentifierOwned, SubjectPublicKeyInfoOwned},
+};
 use yubihsm::{
+    asymmetric,
     authentication::{self, Key, DEFAULT_AUTHENTICATION_KEY_ID},
-    object::{Id, Label, Type},
+    device,
+    object::{self, Filter, Id, Label, Type},
     wrap::{self, Message},
-    AuditOption, Capability, Client, Connector, Credentials, Domain,
+    Algorithm, AuditOption, Capability, Client, Connector, Credentials, Domain,
     HttpConfig, UsbConfig,
 };
 use zeroize::Zeroizing;
@@ -36,7 +54,11 @@ const DOMAIN: Domain = Domain::all();
 const ID: Id = 0x1;
 const LABEL: &str = "backup";
 
-const BACKUP_EXT: &str = ".backup.json";
+pub const BACKUP_EXT: &str = ".backup.json";
+
+/// The oldest YubiHSM firmware version the OKS has been validated against.
+/// By default we refuse to work with anything older.
+pub const MIN_FIRMWARE: FirmwareVersion = FirmwareVersion(2, 2, 0);
 const ATTEST_FILE_NAME: &str = "hsm.attest.cert.pem";
 
 #[derive(Error, Debug)]
@@ -57,15 +79,91 @@ pub enum HsmError {
     CombineKeyFailed { e: vsss_rs::Error },
     #[error("Failed to split wrap key into shares.")]
     SplitKeyFailed { e: vsss_rs::Error },
-    #[error("your yubihms is broke")]
-    Version,
+    #[error(
+        "YubiHSM firmware {found} is older than the minimum supported version \
+        {min}"
+    )]
+    Version { found: String, min: String },
+    #[error(
+        "invalid firmware version \"{version}\", expected major.minor.build"
+    )]
+    BadFirmwareVersion { version: String },
     #[error("Not enough shares.")]
     NotEnoughShares,
+    #[error(
+        "key \"{label}\" can't be backed up without \"exportable-under-wrap\""
+    )]
+    NotExportable { label: String },
+    #[error("no asymmetric key found matching \"{key}\"")]
+    NoKey { key: String },
+    #[error("more than one asymmetric key matches \"{key}\"")]
+    AmbiguousKey { key: String },
+    #[error("only RSA keys have a PKCS#1 encoding")]
+    NotRsa,
+    #[error("found {count} difference(s) between the key specs and YubiHSM")]
+    Drift { count: usize },
+    #[error("{count} key spec(s) conflict with objects in the YubiHSM")]
+    KeyConflict { count: usize },
+}
+
+/// A YubiHSM firmware version: major, minor & build. Written as
+/// "major.minor.build".
+#[derive(Clone, Copy, Debug, Eq, Ord, PartialEq, PartialOrd)]
+pub struct FirmwareVersion(pub u8, pub u8, pub u8);
+
+impl FromStr for FirmwareVersion {
+    type Err = HsmError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let bad = || HsmError::BadFirmwareVersion {
+            version: s.to_string(),
+        };
+        let parts = s
+            .split('.')
+            .map(|p| p.parse::<u8>().map_err(|_| bad()))
+            .collect::<Result<Vec<u8>, HsmError>>()?;
+
+        match parts[..] {
+            [major, minor, build] => Ok(Self(major, minor, build)),
+            _ => Err(bad()),
+        }
+    }
+}
+
+impl Display for FirmwareVersion {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}.{}", self.0, self.1, self.2)
+    }
+}
+
+impl From<&device::Info> for FirmwareVersion {
+    fn from(info: &device::Info) -> Self {
+        Self(info.major_version, info.minor_version, info.build_version)
+    }
+}
+
+/// A field that differs between a KeySpec and the key in the HSM.
+struct KeyDiff {
+    field: &'static str,
+    spec: String,
+    hsm: String,
+}
+
+/// What `Hsm::generate` will do for a KeySpec.
+enum KeyPlan {
+    /// There's no key in the HSM with the spec's id so we generate it.
+    Generate,
+    /// The key in the HSM with the spec's id matches the spec.
+    Exists,
+    /// The object in the HSM with the spec's id doesn't match the spec.
+    Conflict(Vec<KeyDiff>),
 }
 
 /// Structure holding common data used by OKS when interacting with the HSM.
 pub struct Hsm {
     pub client: Client,
+    /// The auth key used to open the session with the YubiHSM.
+    pub auth_id: Id,
     pub out_dir: PathBuf,
     pub state_dir: PathBuf,
     pub backup: bool,
@@ -78,6 +176,9 @@ impl Hsm {
     // anywhere from less than 1 minute to over 5 minutes.
     const TIMEOUT_MS: u64 = 300000;
 
+    /// Open a session with the YubiHSM using the auth key `auth_id`. If
+    /// `min_firmware` is provided the firmware on the YubiHSM must be at
+    /// least that version.
     pub fn new(
         auth_id: Id,
         passwd: &str,
@@ -85,6 +186,7 @@ impl Hsm {
         state_dir: &Path,
         backup: bool,
         transport: Transport,
+        min_firmware: Option<FirmwareVersion>,
     ) -> Result<Self> {
         let connector = match transport {
             Transport::Usb => {
@@ -103,9 +205,13 @@ impl Hsm {
         let credentials =
             Credentials::from_password(auth_id, passwd.as_bytes());
         let client = Client::open(connector, credentials, true)?;
+        if let Some(min) = min_firmware {
+            check_firmware(&client, min)?;
+        }
 
         Ok(Hsm {
             client,
+            auth_id,
             out_dir: out_dir.to_path_buf(),
             state_dir: state_dir.to_path_buf(),
             backup,
@@ -236,44 +342,214 @@ impl Hsm {
     }
 
     pub fn generate(&self, key_spec: &Path) -> Result<()> {
-        debug!("canonical KeySpec path: {}", key_spec.display());
+        // work out what needs to be done for every spec before we change
+        // anything
+        let mut plan = Vec::new();
+        for (path, spec) in load_keyspecs(key_spec)? {
+            // catch this before we generate a key we can't back up
+            if self.backup
+                && !spec
+                    .capabilities
+                    .contains(Capability::EXPORTABLE_UNDER_WRAP)
+            {
+                return Err(HsmError::NotExportable {
+                    label: spec.label.to_string(),
+                }
+                .into());
+            }
 
-        let paths = if key_spec.is_file() {
-            vec![key_spec.to_path_buf()]
-        } else {
-            config::files_with_ext(key_spec, KEYSPEC_EXT)?
-        };
+            let action = self.plan_keyspec(&spec)?;
+            plan.push((path, spec, action));
+        }
 
-        if paths.is_empty() {
-            return Err(anyhow::anyhow!(
-                "no files with extension \"{}\" found in dir: {}",
-                KEYSPEC_EXT,
-                &key_spec.display()
-            ));
+        println!("Key generation plan:");
+        for (path, spec, action) in &plan {
+            let action = match action {
+                KeyPlan::Generate => "generate",
+                KeyPlan::Exists => "exists, skip",
+                KeyPlan::Conflict(_) => "CONFLICT",
+            };
+            println!(
+                "  {:#06x} {:<40} {} ({})",
+                spec.id,
+                spec.label.to_string(),
+                action,
+                path.display()
+            );
+        }
+
+        let mut conflicts = 0;
+        for (path, spec, action) in &plan {
+            if let KeyPlan::Conflict(diff) = action {
+                conflicts += 1;
+                error!(
+                    "object {:#06x} in the YubiHSM doesn't match spec {}:",
+                    spec.id,
+                    path.display()
+                );
+                for d in diff {
+                    error!("  {}: spec {}, YubiHSM {}", d.field, d.spec, d.hsm);
+                }
+            }
+        }
+        if conflicts != 0 {
+            return Err(HsmError::KeyConflict { count: conflicts }.into());
         }
 
-        for path in paths {
-            let json = fs::read_to_string(&path)?;
-            debug!("spec as json: {}", json);
-
-            let spec = KeySpec::from_str(&json)?;
-            debug!("KeySpec from {}: {:#?}", path.display(), spec);
-
-            info!("Generating key for spec: {:?}", path);
-            let id = self.generate_keyspec(&spec)?;
-            if self.backup {
-                backup_object(
-                    &self.client,
-                    id,
-                    Type::AsymmetricKey,
-                    &self.out_dir,
-                )?;
+        for (path, spec, action) in plan {
+            match action {
+                KeyPlan::Generate => {
+                    info!("Generating key for spec: {:?}", path);
+                    let id = self.generate_keyspec(&spec)?;
+                    if self.backup {
+                        backup_object(
+                            &self.client,
+                            id,
+                            Type::AsymmetricKey,
+                            &self.out_dir,
+                        )?;
+                    }
+                }
+                KeyPlan::Exists => {
+                    info!("Key for spec {:?} exists, skipping", path);
+                    // a previous run may have failed before the backup was
+                    // written
+                    let backup = self
+                        .out_dir
+                        .join(format!("{}{}", spec.label, BACKUP_EXT));
+                    if self.backup && !backup.exists() {
+                        backup_object(
+                            &self.client,
+                            spec.id,
+                            Type::AsymmetricKey,
+                            &self.out_dir,
+                        )?;
+                    }
+                }
+                KeyPlan::Conflict(_) => unreachable!("conflicts handled above"),
             }
         }
 
         Ok(())
     }
 
+    /// Compare the KeySpec file provided, or all KeySpec files in the
+    /// provided directory, to the asymmetric keys in the HSM. Keys missing
+    /// from the HSM, asymmetric keys in the HSM with no spec, and keys whose
+    /// attributes don't match their spec are reported. An error is returned
+    /// if any drift is found.
+    pub fn diff(&self, key_spec: &Path) -> Result<()> {
+        let specs = load_keyspecs(key_spec)?;
+        let mut drift = 0;
+
+        for (path, spec) in &specs {
+            match self.plan_keyspec(spec)? {
+                KeyPlan::Exists => {
+                    println!("ok:         {:#06x} {}", spec.id, spec.label)
+                }
+                KeyPlan::Generate => {
+                    drift += 1;
+                    println!(
+                        "missing:    {:#06x} {} ({})",
+                        spec.id,
+                        spec.label,
+                        path.display()
+                    );
+                }
+                KeyPlan::Conflict(diff) => {
+                    drift += 1;
+                    println!(
+                        "mismatch:   {:#06x} {} ({})",
+                        spec.id,
+                        spec.label,
+                        path.display()
+                    );
+                    for d in diff {
+                        println!(
+                            "    {}: spec {}, YubiHSM {}",
+                            d.field, d.spec, d.hsm
+                        );
+                    }
+                }
+            }
+        }
+
+        // key specs only describe asymmetric keys, the auth & wrap keys are
+        // managed by `oks` itself
+        let keys = self
+            .client
+            .list_objects(&[Filter::Type(Type::AsymmetricKey)])?;
+        for entry in keys {
+            if specs.iter().any(|(_, spec)| spec.id == entry.object_id) {
+                continue;
+            }
+
+            drift += 1;
+            let info = self
+                .client
+                .get_object_info(entry.object_id, Type::AsymmetricKey)?;
+            println!("unexpected: {:#06x} {}", entry.object_id, info.label);
+        }
+
+        if drift != 0 {
+            Err(HsmError::Drift { count: drift }.into())
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Compare the provided KeySpec to the asymmetric key in the HSM with the
+    /// same id (if any) to determine what `generate` needs to do.
+    fn plan_keyspec(&self, spec: &KeySpec) -> Result<KeyPlan> {
+        let objects = self.client.list_objects(&[
+            Filter::Id(spec.id),
+            Filter::Type(Type::AsymmetricKey),
+        ])?;
+        if objects.is_empty() {
+            return Ok(KeyPlan::Generate);
+        }
+
+        let info = self.client.get_object_info(spec.id, Type::AsymmetricKey)?;
+        debug!("existing object info: {:#?}", info);
+
+        let mut diff = Vec::new();
+        if info.label.to_string() != spec.label.to_string() {
+            diff.push(KeyDiff {
+                field: "label",
+                spec: spec.label.to_string(),
+                hsm: info.label.to_string(),
+            });
+        }
+        match info.algorithm {
+            Algorithm::Asymmetric(alg) if alg == spec.algorithm => (),
+            alg => diff.push(KeyDiff {
+                field: "algorithm",
+                spec: format!("{:?}", spec.algorithm),
+                hsm: format!("{:?}", alg),
+            }),
+        }
+        if info.capabilities != spec.capabilities {
+            diff.push(KeyDiff {
+                field: "capabilities",
+                spec: format!("{:?}", spec.capabilities),
+                hsm: format!("{:?}", info.capabilities),
+            });
+        }
+        if info.domains != spec.domain {
+            diff.push(KeyDiff {
+                field: "domain",
+                spec: format!("{:?}", spec.domain),
+                hsm: format!("{:?}", info.domains),
+            });
+        }
+
+        if diff.is_empty() {
+            Ok(KeyPlan::Exists)
+        } else {
+            Ok(KeyPlan::Conflict(diff))
+        }
+    }
+
     /// Generate an asymmetric key from the provided specification.
     fn generate_keyspec(&self, spec: &KeySpec) -> Result<Id> {
         let id = self.client.generate_asymmetric_key(
@@ -438,6 +714,75 @@ pub fn dump_info(client: &Client) -> Result<()> {
     Ok(())
 }
 
+/// Return an error if the firmware on the YubiHSM is older than `min`.
+fn check_firmware(client: &Client, min: FirmwareVersion) -> Result<()> {
+    let version = FirmwareVersion::from(&client.device_info()?);
+    debug!("YubiHSM firmware version: {}", version);
+    if version < min {
+        return Err(HsmError::Version {
+            found: version.to_string(),
+            min: min.to_string(),
+        }
+        .into());
+    }
+
+    Ok(())
+}
+
+/// Print a report describing the state of the YubiHSM to stdout. Problems,
+/// like firmware older than `min_firmware`, are flagged in the report but
+/// don't cause this function to fail.
+pub fn health(client: &Client, min_firmware: FirmwareVersion) -> Result<()> {
+    let info = client.device_info()?;
+    let version = FirmwareVersion::from(&info);
+    println!("serial number:    {}", info.serial_number);
+    println!(
+        "firmware:         {} (minimum {}){}",
+        version,
+        min_firmware,
+        if version < min_firmware {
+            " TOO OLD"
+        } else {
+            ""
+        }
+    );
+
+    let storage = client.get_storage_info()?;
+    println!(
+        "storage:          {}/{} records free, {}/{} pages free",
+        storage.free_records,
+        storage.total_records,
+        storage.free_pages,
+        storage.total_pages,
+    );
+    println!(
+        "audit log:        {}/{} entries used",
+        info.log_store_used, info.log_store_capacity,
+    );
+    println!("force audit:      {:?}", client.get_force_audit_option()?);
+
+    let default_auth = client.list_objects(&[
+        Filter::Id(DEFAULT_AUTHENTICATION_KEY_ID),
+        Filter::Type(Type::AuthenticationKey),
+    ])?;
+    println!(
+        "default auth key: {}",
+        if default_auth.is_empty() {
+            "absent"
+        } else {
+            "PRESENT"
+        }
+    );
+
+    let objects = list_objects(client, &ListArgs::default())?;
+    println!("objects:          {}", objects.len());
+    for s in objects {
+        println!("  {:#06x} {:<20} {}", s.id, s.kind, s.label);
+    }
+
+    Ok(())
+}
+
 pub fn dump_sn(client: &Client) -> Result<()> {
     let info = client.device_info()?;
     println!("{}", info.serial_number);
@@ -445,6 +790,362 @@ pub fn dump_sn(client: &Client) -> Result<()> {
     Ok(())
 }
 
+/// Arguments shared by the `list` commands in `oks` and `yhsm`.
+#[derive(Args, Clone, Debug, Default, PartialEq)]
+pub struct ListArgs {
+    /// Only list objects of this type, e.g. `asymmetric-key`.
+    #[clap(long)]
+    kind: Option<String>,
+
+    /// Only list the object with this label.
+    #[clap(long)]
+    label: Option<String>,
+
+    /// Only list objects in this domain (1-16).
+    #[clap(long, value_parser = clap::value_parser!(u8).range(1..=16))]
+    domain: Option<u8>,
+
+    /// Print the listing as JSON instead of a table.
+    #[clap(long)]
+    json: bool,
+}
+
+/// A description of an object in the YubiHSM suitable for humans.
+#[derive(Debug, Serialize)]
+pub struct ObjectSummary {
+    pub id: Id,
+    pub kind: String,
+    pub label: String,
+    pub algorithm: String,
+    pub domains: Vec<u8>,
+    pub capabilities: Vec<String>,
+    pub origin: String,
+}
+
+impl From<&object::Info> for ObjectSummary {
+    fn from(info: &object::Info) -> Self {
+        let domains = (0..16)
+            .filter(|i| info.domains.bits() & (1 << i) != 0)
+            .map(|i| i + 1)
+            .collect();
+        let capabilities = if info.capabilities == Capability::all() {
+            vec!["all".to_string()]
+        } else {
+            info.capabilities
+                .iter_names()
+                .map(|(name, _)| name.to_lowercase().replace('_', "-"))
+                .collect()
+        };
+
+        Self {
+            id: info.object_id,
+            kind: info.object_type.to_string(),
+            label: info.label.to_string(),
+            algorithm: format!("{:?}", info.algorithm),
+            domains,
+            capabilities,
+            origin: format!("{:?}", info.origin),
+        }
+    }
+}
+
+/// Get a summary of each object in the YubiHSM matching the filters in
+/// `args`.
+pub fn list_objects(
+    client: &Client,
+    args: &ListArgs,
+) -> Result<Vec<ObjectSummary>> {
+    let mut filters = Vec::new();
+    if let Some(kind) = &args.kind {
+        // `Type` returns () on error, not a type implementing std::Error
+        let kind = Type::from_str(kind)
+            .map_err(|_| anyhow::anyhow!("Invalid object type."))?;
+        filters.push(Filter::Type(kind));
+    }
+    if let Some(label) = &args.label {
+        let label = Label::from_bytes(label.as_bytes())
+            .map_err(|_| HsmError::BadLabel)?;
+        filters.push(Filter::Label(label));
+    }
+    if let Some(domain) = args.domain {
+        let domain =
+            Domain::from_bits(1 << (domain - 1)).ok_or(HsmError::BadDomain)?;
+        filters.push(Filter::Domains(domain));
+    }
+
+    let mut summaries = Vec::new();
+    for entry in client.list_objects(&filters)? {
+        let info =
+            client.get_object_info(entry.object_id, entry.object_type)?;
+        debug!("object info: {:#?}", info);
+        summaries.push(ObjectSummary::from(&info));
+    }
+    summaries.sort_by_key(|s| (s.kind.clone(), s.id));
+
+    Ok(summaries)
+}
+
+/// List the objects in the YubiHSM matching `args` on stdout.
+pub fn dump_objects(client: &Client, args: &ListArgs) -> Result<()> {
+    let summaries = list_objects(client, args)?;
+
+    if args.json {
+        println!("{}", serde_json::to_string_pretty(&summaries)?);
+        return Ok(());
+    }
+
+    println!(
+        "{:<6} {:<20} {:<40} {:<24} {:<12} {:<10} CAPABILITIES",
+        "ID", "TYPE", "LABEL", "ALGORITHM", "DOMAINS", "ORIGIN"
+    );
+    for s in summaries {
+        let domains: Vec<String> =
+            s.domains.iter().map(|d| d.to_string()).collect();
+        println!(
+            "{:#06x} {:<20} {:<40} {:<24} {:<12} {:<10} {}",
+            s.id,
+            s.kind,
+            s.label,
+            s.algorithm,
+            domains.join(","),
+            s.origin,
+            s.capabilities.join(",")
+        );
+    }
+
+    Ok(())
+}
+
+/// Load the KeySpec file provided or all KeySpec files in the provided
+/// directory.
+fn load_keyspecs(key_spec: &Path) -> Result<Vec<(PathBuf, KeySpec)>> {
+    debug!("canonical KeySpec path: {}", key_spec.display());
+
+    let paths = if key_spec.is_file() {
+        vec![key_spec.to_path_buf()]
+    } else {
+        config::files_with_ext(key_spec, KEYSPEC_EXT)?
+    };
+
+    if paths.is_empty() {
+        return Err(anyhow::anyhow!(
+            "no files with extension \"{}\" found in dir: {}",
+            KEYSPEC_EXT,
+            &key_spec.display()
+        ));
+    }
+
+    let mut specs = Vec::new();
+    for path in paths {
+        let json = fs::read_to_string(&path)?;
+        debug!("spec as json: {}", json);
+
+        let spec = KeySpec::from_str(&json)?;
+        debug!("KeySpec from {}: {:#?}", path.display(), spec);
+
+        specs.push((path, spec));
+    }
+
+    Ok(specs)
+}
+
+/// Find the asymmetric key identified by `key`. This may be the key's label
+/// or its object id (decimal or hex prefixed with `0x`).
+pub fn find_key(client: &Client, key: &str) -> Result<Id> {
+    let id = match key.strip_prefix("0x") {
+        Some(hex) => Id::from_str_radix(hex, 16).ok(),
+        None => key.parse::<Id>().ok(),
+    };
+
+    let filter = match id {
+        Some(id) => Filter::Id(id),
+        None => Filter::Label(
+            Label::from_bytes(key.as_bytes())
+                .map_err(|_| HsmError::BadLabel)?,
+        ),
+    };
+
+    let objects =
+        client.list_objects(&[filter, Filter::Type(Type::AsymmetricKey)])?;
+    match objects.as_slice() {
+        [entry] => Ok(entry.object_id),
+        [] => Err(HsmError::NoKey {
+            key: key.to_string(),
+        }
+        .into()),
+        _ => Err(HsmError::AmbiguousKey {
+            key: key.to_string(),
+        }
+        .into()),
+    }
+}
+
+/// The public part of an asymmetric key from the YubiHSM.
+pub struct PublicKey {
+    algorithm: asymmetric::Algorithm,
+    bytes: Vec<u8>,
+}
+
+// The YubiHSM always generates RSA keys with this public exponent.
+const RSA_EXPONENT: u32 = 65537;
+
+pub(crate) const OID_EC_PUBLIC_KEY: ObjectIdentifier =
+    ObjectIdentifier::new_unwrap("1.2.840.10045.2.1");
+pub(crate) const OID_SECP256R1: ObjectIdentifier =
+    ObjectIdentifier::new_unwrap("1.2.840.10045.3.1.7");
+pub(crate) const OID_SECP384R1: ObjectIdentifier =
+    ObjectIdentifier::new_unwrap("1.3.132.0.34");
+pub(crate) const OID_SECP521R1: ObjectIdentifier =
+    ObjectIdentifier::new_unwrap("1.3.132.0.35");
+pub(crate) const OID_ED25519: ObjectIdentifier =
+    ObjectIdentifier::new_unwrap("1.3.101.112");
+
+impl PublicKey {
+    /// Get the public key for the asymmetric key with the given id.
+    pub fn from_hsm(client: &Client, id: Id) -> Result<Self> {
+        let key = client.get_public_key(id)?;
+        debug!("got {:?} public key for id {:#06x}", key.algorithm, id);
+
+        Ok(Self {
+            algorithm: key.algorithm,
+            bytes: key.bytes,
+        })
+    }
+
+    pub fn algorithm(&self) -> asymmetric::Algorithm {
+        self.algorithm
+    }
+
+    fn rsa(&self) -> Result<RsaPublicKey> {
+        match self.algorithm {
+            asymmetric::Algorithm::Rsa2048
+            | asymmetric::Algorithm::Rsa3072
+            | asymmetric::Algorithm::Rsa4096 => Ok(RsaPublicKey::new(
+                rsa::BigUint::from_bytes_be(&self.bytes),
+                rsa::BigUint::from(RSA_EXPONENT),
+            )?),
+            _ => Err(HsmError::NotRsa.into()),
+        }
+    }
+
+    /// The YubiHSM returns EC public keys as the concatenated affine
+    /// coordinates. Split them apart after checking the length.
+    fn ec_coordinates(&self, len: usize) -> Result<(&[u8], &[u8])> {
+        if self.bytes.len() != 2 * len {
+            return Err(anyhow::anyhow!(
+                "expected {} bytes of EC public key, got {}",
+                2 * len,
+                self.bytes.len()
+            ));
+        }
+
+        Ok(self.bytes.split_at(len))
+    }
+
+    /// DER encoded SubjectPublicKeyInfo.
+    pub fn to_spki_der(&self) -> Result<Vec<u8>> {
+        let ec_spki = |curve: ObjectIdentifier, len: usize| -> Result<_> {
+            // check length, SEC1 uncompressed point is 0x04 || x || y
+            self.ec_coordinates(len)?;
+            let mut point = vec![0x04];
+            point.extend_from_slice(&self.bytes);
+
+            Ok(SubjectPublicKeyInfoOwned {
+                algorithm: AlgorithmIdentifierOwned {
+                    oid: OID_EC_PUBLIC_KEY,
+                    parameters: Some(curve.into()),
+                },
+                subject_public_key: BitString::from_bytes(&point)?,
+            })
+        };
+
+        let spki = match self.algorithm {
+            asymmetric::Algorithm::Rsa2048
+            | asymmetric::Algorithm::Rsa3072
+            | asymmetric::Algorithm::Rsa4096 => {
+                return Ok(self.rsa()?.to_public_key_der()?.into_vec());
+            }
+            asymmetric::Algorithm::EcP256 => ec_spki(OID_SECP256R1, 32)?,
+            asymmetric::Algorithm::EcP384 => ec_spki(OID_SECP384R1, 48)?,
+            asymmetric::Algorithm::EcP521 => ec_spki(OID_SECP521R1, 66)?,
+            asymmetric::Algorithm::Ed25519 => SubjectPublicKeyInfoOwned {
+                algorithm: AlgorithmIdentifierOwned {
+                    oid: OID_ED25519,
+                    parameters: None,
+                },
+                subject_public_key: BitString::from_bytes(&self.bytes)?,
+            },
+            a => return Err(anyhow::anyhow!("unsupported algorithm: {:?}", a)),
+        };
+
+        Ok(spki.to_der()?)
+    }
+
+    /// PEM encoded SubjectPublicKeyInfo.
+    pub fn to_spki_pem(&self) -> Result<String> {
+        Ok(pem_rfc7468::encode_string(
+            "PUBLIC KEY",
+            LineEnding::default(),
+            &self.to_spki_der()?,
+        )?)
+    }
+
+    /// DER encoded PKCS#1 RSAPublicKey. Only RSA keys have this encoding.
+    pub fn to_pkcs1_der(&self) -> Result<Vec<u8>> {
+        Ok(self.rsa()?.to_pkcs1_der()?.into_vec())
+    }
+
+    /// PEM encoded PKCS#1 RSAPublicKey. Only RSA keys have this encoding.
+    pub fn to_pkcs1_pem(&self) -> Result<String> {
+        Ok(self.rsa()?.to_pkcs1_pem(LineEnding::default())?)
+    }
+
+    /// The public key as a JSON Web Key (RFC 7517).
+    pub fn to_jwk(&self) -> Result<String> {
+        let b64 = Base64UrlUnpadded::encode_string;
+        let ec_jwk = |crv: &str, len: usize| -> Result<_> {
+            let (x, y) = self.ec_coordinates(len)?;
+            Ok(serde_json::json!({
+                "kty": "EC",
+                "crv": crv,
+                "x": b64(x),
+                "y": b64(y),
+            }))
+        };
+
+        let jwk = match self.algorithm {
+            asymmetric::Algorithm::Rsa2048
+            | asymmetric::Algorithm::Rsa3072
+            | asymmetric::Algorithm::Rsa4096 => {
+                let key = self.rsa()?;
+                serde_json::json!({
+                    "kty": "RSA",
+                    "n": b64(&key.n().to_bytes_be()),
+                    "e": b64(&key.e().to_bytes_be()),
+                })
+            }
+            asymmetric::Algorithm::EcP256 => ec_jwk("P-256", 32)?,
+            asymmetric::Algorithm::EcP384 => ec_jwk("P-384", 48)?,
+            asymmetric::Algorithm::EcP521 => ec_jwk("P-521", 66)?,
+            asymmetric::Algorithm::Ed25519 => serde_json::json!({
+                "kty": "OKP",
+                "crv": "Ed25519",
+                "x": b64(&self.bytes),
+            }),
+            a => return Err(anyhow::anyhow!("unsupported algorithm: {:?}", a)),
+        };
+
+        Ok(serde_json::to_string_pretty(&jwk)?)
+    }
+
+    /// Hex encoded SHA-256 digest of the DER encoded SubjectPublicKeyInfo.
+    /// This is the same as the output from:
+    /// `openssl pkey -pubin -outform DER | sha256sum`
+    pub fn fingerprint(&self) -> Result<String> {
+        Ok(hex::encode(Sha256::digest(self.to_spki_der()?)))
+    }
+}
+
 pub fn reset(client: &Client) -> Result<()> {
     let info = client.device_info()?;
     info!("resetting device with SN: {}", info.serial_number);
@@ -485,3 +1186,55 @@ fn are_you_sure() -> Result<bool> {
 
     Ok(buffer == "y")
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use p384::elliptic_curve::sec1::ToEncodedPoint;
+    use rand::rngs::OsRng;
+
+    #[test]
+    fn firmware_version() -> Result<()> {
+        let version = FirmwareVersion::from_str("2.4.0")?;
+        assert_eq!(version, FirmwareVersion(2, 4, 0));
+        assert_eq!(version.to_string(), "2.4.0");
+        // versions compare field by field, not as strings
+        assert!(FirmwareVersion::from_str("2.10.0")? > version);
+        assert!(FirmwareVersion::from_str("2.1.9")? < MIN_FIRMWARE);
+
+        for bad in ["2.2", "2.2.0.1", "2.x.0", "2.256.0", ""] {
+            assert!(FirmwareVersion::from_str(bad).is_err(), "{}", bad);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn ecp384_spki_matches_p384() -> Result<()> {
+        let key = p384::SecretKey::random(&mut OsRng).public_key();
+        let point = key.to_encoded_point(false);
+        let pub_key = PublicKey {
+            algorithm: asymmetric::Algorithm::EcP384,
+            // the YubiHSM doesn't include the SEC1 tag byte
+            bytes: point.as_bytes()[1..].to_vec(),
+        };
+
+        assert_eq!(pub_key.to_spki_der()?, key.to_public_key_der()?.into_vec());
+        assert!(pub_key.to_pkcs1_der().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn ed25519_spki_matches_dalek() -> Result<()> {
+        let key = ed25519_dalek::SigningKey::generate(&mut OsRng);
+        let pub_key = PublicKey {
+            algorithm: asymmetric::Algorithm::Ed25519,
+            bytes: key.verifying_key().to_bytes().to_vec(),
+        };
+
+        assert_eq!(
+            pub_key.to_spki_der()?,
+            key.verifying_key().to_public_key_der()?.into_vec()
+        );
+        Ok(())
+    }
+}
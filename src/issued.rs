@@ -0,0 +1,631 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use anyhow::{Context, Result};
+use clap::ValueEnum;
+use log::{debug, info};
+use num_bigint::BigUint;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+    fs,
+    io::Write,
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::{Duration, SystemTime},
+};
+use thiserror::Error;
+use x509_cert::{
+    der::{DateTime, DecodePem, Encode},
+    Certificate,
+};
+use yubihsm::object::Id;
+
+use crate::config::SERIAL_LEN;
+
+/// Name of the file in the root of a CA directory holding the issuance
+/// database.
+pub const ISSUED_DB: &str = "issued.json";
+
+/// Name of the file in the root of a CA directory holding the `openssl ca`
+/// database. CA directories from before the issuance database may have one
+/// of these.
+pub const OPENSSL_INDEX: &str = "index.txt";
+
+/// Name of the directory in the root of a CA directory holding a copy of
+/// each cert issued.
+pub const NEWCERTS_DIR: &str = "newcerts";
+
+// The compact form of an `IssuedCert` is the serial number padded to
+// SERIAL_LEN bytes, the SHA-256 digest of the SPKI, the time the cert was
+// revoked in seconds since the epoch & the index of the `RevokeReason`.
+// Zeros stand in for a missing digest or revocation.
+const COMPACT_SPKI_OFFSET: usize = SERIAL_LEN;
+const COMPACT_REVOKED_OFFSET: usize = COMPACT_SPKI_OFFSET + 32;
+const COMPACT_REASON_OFFSET: usize = COMPACT_REVOKED_OFFSET + 8;
+
+/// Length of the compact form of an `IssuedCert`.
+pub const COMPACT_LEN: usize = COMPACT_REASON_OFFSET + 1;
+
+#[derive(Error, Debug)]
+pub enum IssuedError {
+    #[error("invalid serial number: \"{serial}\"")]
+    BadSerial { serial: String },
+    #[error("invalid entry in openssl CA database: \"{entry}\"")]
+    BadIndexEntry { entry: String },
+    #[error("cert with serial number {serial} is already in the database")]
+    DuplicateSerial { serial: String },
+    #[error("issuance database already exists: {}", path.display())]
+    DbExists { path: PathBuf },
+    #[error("invalid compact issuance record: {record}")]
+    BadCompactRecord { record: String },
+}
+
+/// The reasons a cert may be revoked, from RFC 5280 section 5.3.1.
+#[derive(
+    Clone,
+    Copy,
+    Debug,
+    Default,
+    Deserialize,
+    Eq,
+    PartialEq,
+    Serialize,
+    ValueEnum,
+)]
+#[serde(rename_all = "kebab-case")]
+pub enum RevokeReason {
+    #[default]
+    Unspecified,
+    KeyCompromise,
+    CaCompromise,
+    AffiliationChanged,
+    Superseded,
+    CessationOfOperation,
+    CertificateHold,
+    PrivilegeWithdrawn,
+}
+
+impl RevokeReason {
+    /// The name used for the reason in the `openssl ca` database.
+    fn index_name(&self) -> &'static str {
+        match self {
+            RevokeReason::Unspecified => "unspecified",
+            RevokeReason::KeyCompromise => "keyCompromise",
+            RevokeReason::CaCompromise => "CACompromise",
+            RevokeReason::AffiliationChanged => "affiliationChanged",
+            RevokeReason::Superseded => "superseded",
+            RevokeReason::CessationOfOperation => "cessationOfOperation",
+            RevokeReason::CertificateHold => "certificateHold",
+            RevokeReason::PrivilegeWithdrawn => "privilegeWithdrawn",
+        }
+    }
+
+    fn from_index_name(name: &str) -> Option<Self> {
+        Self::value_variants()
+            .iter()
+            .find(|r| r.index_name() == name)
+            .copied()
+    }
+}
+
+/// The spec file a cert was issued from.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct SpecRecord {
+    pub path: PathBuf,
+    /// SHA-256 digest of the spec file, hex encoded.
+    pub sha256: String,
+}
+
+impl SpecRecord {
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let data = fs::read(path).with_context(|| {
+            format!("Failed to read spec file {}", path.display())
+        })?;
+
+        Ok(Self {
+            path: path.to_path_buf(),
+            sha256: hex::encode(Sha256::digest(data)),
+        })
+    }
+}
+
+/// Where the request to issue a cert came from.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Provenance {
+    /// The CsrSpec the cert was issued from. Self signed certs have none.
+    pub csr_spec: Option<SpecRecord>,
+    /// The YubiHSM auth key used to issue the cert. Certs issued by sandbox
+    /// CAs have none.
+    pub auth_id: Option<Id>,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct Revocation {
+    /// When the cert was revoked (RFC 3339).
+    pub revoked_at: String,
+    pub reason: RevokeReason,
+}
+
+/// The record kept in the issuance database for each cert issued by a CA.
+/// Certs imported from an `openssl ca` database are missing the details
+/// that openssl doesn't record & certs restored from the compact form only
+/// have the serial number, SPKI digest and revocation.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct IssuedCert {
+    /// Serial number as upper case hex.
+    pub serial: String,
+    pub subject: Option<String>,
+    /// When the cert expires (RFC 3339).
+    pub not_after: Option<String>,
+    /// SHA-256 digest of the DER encoded SubjectPublicKeyInfo, hex encoded.
+    pub spki_sha256: Option<String>,
+    /// The name of the certificate profile the cert was issued with.
+    pub profile: Option<String>,
+    pub csr_spec: Option<SpecRecord>,
+    /// When the cert was issued (RFC 3339).
+    pub issued_at: Option<String>,
+    pub auth_id: Option<Id>,
+    pub revocation: Option<Revocation>,
+}
+
+impl IssuedCert {
+    pub fn new(
+        cert: &Certificate,
+        profile: &str,
+        provenance: &Provenance,
+    ) -> Result<Self> {
+        let mut record = Self::from_cert(cert)?;
+        record.profile = Some(profile.to_string());
+        record.csr_spec = provenance.csr_spec.clone();
+        record.issued_at =
+            Some(DateTime::from_system_time(SystemTime::now())?.to_string());
+        record.auth_id = provenance.auth_id;
+
+        Ok(record)
+    }
+
+    /// The details of the record that can be recovered from the cert.
+    fn from_cert(cert: &Certificate) -> Result<Self> {
+        let tbs = &cert.tbs_certificate;
+        let spki = tbs.subject_public_key_info.to_der()?;
+
+        Ok(Self {
+            serial: hex::encode_upper(tbs.serial_number.as_bytes()),
+            subject: Some(tbs.subject.to_string()),
+            not_after: Some(tbs.validity.not_after.to_date_time().to_string()),
+            spki_sha256: Some(hex::encode(Sha256::digest(spki))),
+            profile: None,
+            csr_spec: None,
+            issued_at: None,
+            auth_id: None,
+            revocation: None,
+        })
+    }
+
+    pub fn is_revoked(&self) -> bool {
+        self.revocation.is_some()
+    }
+
+    /// The compact form of this record. Every record is the same size so
+    /// revoking a cert doesn't change the size of the compact database.
+    fn to_compact(&self) -> Result<[u8; COMPACT_LEN]> {
+        let mut compact = [0u8; COMPACT_LEN];
+
+        let serial = parse_serial(&self.serial)?.to_bytes_be();
+        if serial.len() > SERIAL_LEN {
+            return Err(IssuedError::BadSerial {
+                serial: self.serial.clone(),
+            }
+            .into());
+        }
+        compact[SERIAL_LEN - serial.len()..SERIAL_LEN].copy_from_slice(&serial);
+
+        if let Some(spki_sha256) = &self.spki_sha256 {
+            hex::decode_to_slice(
+                spki_sha256,
+                &mut compact[COMPACT_SPKI_OFFSET..COMPACT_REVOKED_OFFSET],
+            )?;
+        }
+
+        if let Some(revocation) = &self.revocation {
+            let revoked_at = DateTime::from_str(&revocation.revoked_at)?
+                .unix_duration()
+                .as_secs();
+            compact[COMPACT_REVOKED_OFFSET..COMPACT_REASON_OFFSET]
+                .copy_from_slice(&revoked_at.to_be_bytes());
+            // the variants are in the order `value_variants` returns them
+            compact[COMPACT_REASON_OFFSET] = revocation.reason as u8;
+        }
+
+        Ok(compact)
+    }
+
+    /// Recreate a record from its compact form.
+    fn from_compact(compact: &[u8]) -> Result<Self> {
+        let bad = || IssuedError::BadCompactRecord {
+            record: hex::encode(compact),
+        };
+        if compact.len() != COMPACT_LEN {
+            return Err(bad().into());
+        }
+
+        let serial = BigUint::from_bytes_be(&compact[..SERIAL_LEN]);
+        let spki_sha256 = &compact[COMPACT_SPKI_OFFSET..COMPACT_REVOKED_OFFSET];
+        let mut revoked_at = [0u8; 8];
+        revoked_at.copy_from_slice(
+            &compact[COMPACT_REVOKED_OFFSET..COMPACT_REASON_OFFSET],
+        );
+        let revocation = match u64::from_be_bytes(revoked_at) {
+            0 => None,
+            secs => Some(Revocation {
+                revoked_at: DateTime::from_unix_duration(Duration::from_secs(
+                    secs,
+                ))?
+                .to_string(),
+                reason: *RevokeReason::value_variants()
+                    .get(usize::from(compact[COMPACT_REASON_OFFSET]))
+                    .ok_or_else(bad)?,
+            }),
+        };
+
+        Ok(Self {
+            serial: hex::encode_upper(serial.to_bytes_be()),
+            subject: None,
+            not_after: None,
+            spki_sha256: if spki_sha256.iter().all(|b| *b == 0) {
+                None
+            } else {
+                Some(hex::encode(spki_sha256))
+            },
+            profile: None,
+            csr_spec: None,
+            issued_at: None,
+            auth_id: None,
+            revocation,
+        })
+    }
+}
+
+/// The compact form of the issuance database: the compact form of each
+/// record in order. This is what's kept in the YubiHSM, the full database
+/// doesn't fit.
+pub fn compact_db(certs: &[IssuedCert]) -> Result<Vec<u8>> {
+    let mut compact = Vec::with_capacity(certs.len() * COMPACT_LEN);
+    for cert in certs {
+        compact.extend(cert.to_compact()?);
+    }
+
+    Ok(compact)
+}
+
+/// Recreate the records in a compact issuance database.
+pub fn expand_db(compact: &[u8]) -> Result<Vec<IssuedCert>> {
+    compact
+        .chunks(COMPACT_LEN)
+        .map(IssuedCert::from_compact)
+        .collect()
+}
+
+/// Parse a hex encoded serial (or CRL) number. The "0x" prefix is optional.
+pub fn parse_serial(serial: &str) -> Result<BigUint, IssuedError> {
+    let serial = serial.trim();
+    BigUint::parse_bytes(serial.trim_start_matches("0x").as_bytes(), 16).ok_or(
+        IssuedError::BadSerial {
+            serial: serial.to_string(),
+        },
+    )
+}
+
+/// The database of certs issued by a CA. This is a JSON array of
+/// `IssuedCert`s in the order they were issued.
+pub struct IssuanceDb {
+    path: PathBuf,
+}
+
+impl IssuanceDb {
+    /// The issuance database for the CA with its state in `root`.
+    pub fn new<P: AsRef<Path>>(root: P) -> Self {
+        Self {
+            path: root.as_ref().join(ISSUED_DB),
+        }
+    }
+
+    pub fn exists(&self) -> bool {
+        self.path.exists()
+    }
+
+    /// Create an empty issuance database.
+    pub fn create(&self) -> Result<()> {
+        if self.exists() {
+            return Err(IssuedError::DbExists {
+                path: self.path.clone(),
+            }
+            .into());
+        }
+
+        self.write(&[])
+    }
+
+    pub fn read(&self) -> Result<Vec<IssuedCert>> {
+        let json = fs::read_to_string(&self.path).with_context(|| {
+            format!("Failed to read issuance database {}", self.path.display())
+        })?;
+
+        Ok(serde_json::from_str(&json)?)
+    }
+
+    /// Replace the issuance database. The new database is written next to
+    /// the old one and then moved into place so that a failure part way
+    /// through can't leave us with a partial database.
+    pub fn write(&self, certs: &[IssuedCert]) -> Result<()> {
+        let tmp = self.path.with_extension("json.new");
+
+        let mut file = fs::File::create(&tmp)?;
+        file.write_all(serde_json::to_string_pretty(certs)?.as_bytes())?;
+        file.sync_all()?;
+
+        Ok(fs::rename(tmp, &self.path)?)
+    }
+
+    /// Add the record for a newly issued cert to the database.
+    pub fn add(&self, cert: IssuedCert) -> Result<()> {
+        let mut certs = self.read()?;
+        let serial = parse_serial(&cert.serial)?;
+        for issued in &certs {
+            if parse_serial(&issued.serial)? == serial {
+                return Err(IssuedError::DuplicateSerial {
+                    serial: cert.serial,
+                }
+                .into());
+            }
+        }
+
+        debug!(
+            "adding cert with serial {} to issuance database",
+            cert.serial
+        );
+        certs.push(cert);
+        self.write(&certs)
+    }
+
+    /// Get the index of the cert with the given serial number.
+    pub fn position(
+        certs: &[IssuedCert],
+        serial: &str,
+    ) -> Result<Option<usize>> {
+        let serial = parse_serial(serial)?;
+        for (i, cert) in certs.iter().enumerate() {
+            if parse_serial(&cert.serial)? == serial {
+                return Ok(Some(i));
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Get the record for the cert with the given serial number.
+    pub fn get(&self, serial: &str) -> Result<Option<IssuedCert>> {
+        let mut certs = self.read()?;
+        Ok(Self::position(&certs, serial)?.map(|i| certs.swap_remove(i)))
+    }
+
+    /// Create the issuance database for the CA with its state in `root`
+    /// from the `openssl ca` database & the copies of the certs it issued.
+    /// Details that openssl doesn't record are left empty.
+    pub fn import_index<P: AsRef<Path>>(root: P) -> Result<Self> {
+        let root = root.as_ref();
+        let db = Self::new(root);
+        if db.exists() {
+            return Err(IssuedError::DbExists { path: db.path }.into());
+        }
+
+        let index = fs::read_to_string(root.join(OPENSSL_INDEX))?;
+        let mut certs = Vec::new();
+        for line in index.lines().filter(|l| !l.is_empty()) {
+            let entry = IndexEntry::parse(line)?;
+            let bad_entry = || IssuedError::BadIndexEntry {
+                entry: line.to_string(),
+            };
+
+            // prefer the details from the cert if openssl kept a copy
+            let pem = root
+                .join(NEWCERTS_DIR)
+                .join(format!("{}.pem", entry.serial));
+            let mut cert = if pem.exists() {
+                IssuedCert::from_cert(&Certificate::from_pem(fs::read(pem)?)?)?
+            } else {
+                IssuedCert {
+                    serial: entry.serial.to_string(),
+                    subject: Some(entry.subject.to_string()),
+                    not_after: Some(
+                        parse_index_time(entry.expiry)
+                            .ok_or_else(bad_entry)?
+                            .to_string(),
+                    ),
+                    spki_sha256: None,
+                    profile: None,
+                    csr_spec: None,
+                    issued_at: None,
+                    auth_id: None,
+                    revocation: None,
+                }
+            };
+
+            if entry.status == "R" {
+                let (date, reason) = match entry.revocation.split_once(',') {
+                    Some((date, reason)) => (
+                        date,
+                        RevokeReason::from_index_name(reason)
+                            .ok_or_else(bad_entry)?,
+                    ),
+                    None => (entry.revocation, RevokeReason::Unspecified),
+                };
+                cert.revocation = Some(Revocation {
+                    revoked_at: parse_index_time(date)
+                        .ok_or_else(bad_entry)?
+                        .to_string(),
+                    reason,
+                });
+            }
+            certs.push(cert);
+        }
+
+        info!(
+            "importing {} certs from {} into {}",
+            certs.len(),
+            OPENSSL_INDEX,
+            db.path.display()
+        );
+        db.write(&certs)?;
+
+        Ok(db)
+    }
+}
+
+/// An entry in the `openssl ca` database: one tab separated line from
+/// `index.txt`.
+struct IndexEntry<'a> {
+    /// `V` for valid certs, `R` for revoked certs & `E` for expired certs.
+    status: &'a str,
+    expiry: &'a str,
+    /// When the cert was revoked & optionally why, separated by a comma.
+    /// This is empty for certs that haven't been revoked.
+    revocation: &'a str,
+    serial: &'a str,
+    subject: &'a str,
+}
+
+impl<'a> IndexEntry<'a> {
+    fn parse(line: &'a str) -> Result<Self, IssuedError> {
+        let fields: Vec<&str> = line.split('\t').collect();
+        match fields[..] {
+            [status, expiry, revocation, serial, _file, subject] => Ok(Self {
+                status,
+                expiry,
+                revocation,
+                serial,
+                subject,
+            }),
+            _ => Err(IssuedError::BadIndexEntry {
+                entry: line.to_string(),
+            }),
+        }
+    }
+}
+
+/// Parse a time from the `openssl ca` database. These are ASN.1 UTCTime or
+/// GeneralizedTime strings.
+fn parse_index_time(time: &str) -> Option<DateTime> {
+    let digits = time.strip_suffix('Z')?;
+    if !digits.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+
+    // UTCTime has a 2 digit year, GeneralizedTime has 4
+    let (year, rest) = match digits.len() {
+        12 => {
+            let year: u16 = digits[..2].parse().ok()?;
+            (
+                if year >= 50 { 1900 + year } else { 2000 + year },
+                &digits[2..],
+            )
+        }
+        14 => (digits[..4].parse().ok()?, &digits[4..]),
+        _ => return None,
+    };
+    let field = |i: usize| rest[i..i + 2].parse::<u8>().ok();
+
+    DateTime::new(year, field(0)?, field(2)?, field(4)?, field(6)?, field(8)?)
+        .ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    const INDEX: &str = "V\t99991231235959Z\t\t3CC3000000000000000000000000000000000000\tunknown\t/C=US/O=Oxide Computer Company/CN=root\n\
+        R\t99991231235959Z\t261018120000Z,keyCompromise\t3CC3000000000000000000000000000000000001\tunknown\t/C=US/CN=intermediate\n";
+
+    #[test]
+    fn index_time() {
+        assert_eq!(
+            parse_index_time("261018120000Z").unwrap().to_string(),
+            "2026-10-18T12:00:00Z"
+        );
+        assert_eq!(
+            parse_index_time("99991231235959Z").unwrap().to_string(),
+            "9999-12-31T23:59:59Z"
+        );
+        assert!(parse_index_time("2610181200Z").is_none());
+    }
+
+    #[test]
+    fn import_index() -> Result<()> {
+        let dir = TempDir::new()?;
+        fs::write(dir.path().join(OPENSSL_INDEX), INDEX)?;
+
+        let db = IssuanceDb::import_index(dir.path())?;
+        let certs = db.read()?;
+        assert_eq!(certs.len(), 2);
+        assert_eq!(
+            certs[0].subject.as_deref(),
+            Some("/C=US/O=Oxide Computer Company/CN=root")
+        );
+        assert_eq!(certs[0].not_after.as_deref(), Some("9999-12-31T23:59:59Z"));
+        assert!(!certs[0].is_revoked());
+        assert_eq!(
+            certs[1].revocation,
+            Some(Revocation {
+                revoked_at: "2026-10-18T12:00:00Z".to_string(),
+                reason: RevokeReason::KeyCompromise,
+            })
+        );
+
+        // serial numbers are compared by value
+        let cert = db.get("0x3cc3000000000000000000000000000000000001")?;
+        assert_eq!(cert.as_ref(), Some(&certs[1]));
+        assert!(db.add(certs[0].clone()).is_err());
+
+        // never clobber an existing database
+        assert!(IssuanceDb::import_index(dir.path()).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn compact() -> Result<()> {
+        let dir = TempDir::new()?;
+        fs::write(dir.path().join(OPENSSL_INDEX), INDEX)?;
+        let mut certs = IssuanceDb::import_index(dir.path())?.read()?;
+        certs[0].spki_sha256 = Some(hex::encode([0xa5; 32]));
+
+        // revoking a cert doesn't change the size of the compact database
+        let compact = compact_db(&certs)?;
+        assert_eq!(compact.len(), 2 * COMPACT_LEN);
+        let mut unrevoked = certs.clone();
+        unrevoked[1].revocation = None;
+        assert_eq!(compact_db(&unrevoked)?.len(), compact.len());
+
+        // the serial number, SPKI digest & revocation survive the trip
+        let expanded = expand_db(&compact)?;
+        assert_eq!(expanded.len(), 2);
+        for (cert, expanded) in certs.iter().zip(&expanded) {
+            assert_eq!(
+                parse_serial(&expanded.serial)?,
+                parse_serial(&cert.serial)?
+            );
+            assert_eq!(expanded.spki_sha256, cert.spki_sha256);
+            assert_eq!(expanded.revocation, cert.revocation);
+            assert_eq!(expanded.subject, None);
+        }
+
+        // a truncated database is an error
+        assert!(expand_db(&compact[..COMPACT_LEN + 1]).is_err());
+
+        Ok(())
+    }
+}
@@ -8,6 +8,9 @@ pub mod ca;
 pub mod cdrw;
 pub mod config;
 pub mod hsm;
+pub mod issued;
+pub mod profile;
+pub mod sandbox;
 pub mod secret_reader;
 pub mod secret_writer;
 pub mod util;
@@ -3,28 +3,44 @@
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
 use anyhow::{anyhow, Context, Result};
-use clap::{Parser, Subcommand};
+use clap::{Parser, Subcommand, ValueEnum};
 use env_logger::Builder;
 use log::{debug, error, info, LevelFilter};
+use num_bigint::BigUint;
 use std::{
-    collections::HashMap,
+    collections::{BTreeMap, HashMap},
     env, fs,
+    io::{self, Write},
     ops::{Deref, DerefMut},
     path::{Path, PathBuf},
     str::FromStr,
 };
-use yubihsm::object::{Id, Type};
+use x509_cert::{
+    der::{pem::LineEnding, DecodePem, EncodePem},
+    name::Name,
+    request::CertReq,
+};
+use yubihsm::{
+    object::{Id, Type},
+    Client,
+};
 use zeroize::Zeroizing;
 
 use oks::{
     alphabet::Alphabet,
     backup::{BackupKey, Share, Verifier, LIMIT, THRESHOLD},
-    ca::{Ca, CertOrCsr},
+    ca::{
+        ca_subject, check_csr, check_dcsr_roots, Ca, CaError, CertOrCsr, Cmpa,
+        KeyStore,
+    },
     config::{
-        self, CsrSpec, DcsrSpec, KeySpec, Transport, CSRSPEC_EXT, DCSRSPEC_EXT,
-        KEYSPEC_EXT,
+        self, CsrSpec, DcsrSpec, ImageSpec, KeySpec, SerialPolicy, Transport,
+        CSRSPEC_EXT, DCSRSPEC_EXT, IMAGESPEC_EXT, KEYSPEC_EXT,
     },
-    hsm::Hsm,
+    hsm::{FirmwareVersion, Hsm, ListArgs, PublicKey, MIN_FIRMWARE},
+    issued::{Provenance, RevokeReason, SpecRecord},
+    profile::Profiles,
+    sandbox::{Sandbox, SANDBOX_DIR, SANDBOX_TAG},
     secret_reader::{
         self, AuthInputArg, PasswordReader, ShareInputArg, StdioPasswordReader,
     },
@@ -47,10 +63,34 @@ const GEN_PASSWD_LENGTH: usize = 16;
 // when we write out signed certs to the file system this suffix is appended
 const CERT_SUFFIX: &str = "cert.pem";
 
+// when we write out the chain of issuer certs for a signed cert this suffix is
+// appended
+const CHAIN_SUFFIX: &str = "chain.pem";
+
+// when we write out a signed cert followed by the chain of issuer certs this
+// suffix is appended
+const FULLCHAIN_SUFFIX: &str = "fullchain.pem";
+
+// when we write out CSRs for intermediate CAs this suffix is appended
+const CSR_SUFFIX: &str = "csr.pem";
+
 // when we write out signed debug credentials to the file system this suffix
 // is appended
 const DCSR_SUFFIX: &str = "dc.bin";
 
+// when we write out signed lpc55 images to the file system this suffix is
+// appended
+const IMAGE_SUFFIX: &str = "signed.bin";
+
+// when we write out the CMPA for lpc55 RoTs these suffixes are appended to
+// CMPA_PREFIX
+const CMPA_PREFIX: &str = "cmpa";
+const CMPA_SUFFIX: &str = "bin";
+const CMPA_JSON_SUFFIX: &str = "json";
+
+// when we write out CRLs this suffix is appended to the CA name
+const CRL_SUFFIX: &str = "crl.pem";
+
 // string for environment variable used to pass in the authentication
 // password for the HSM
 pub const ENV_PASSWORD: &str = "OKS_PASSWORD";
@@ -79,6 +119,22 @@ struct Args {
     #[clap(long, env, default_value = "usb")]
     transport: Transport,
 
+    /// The oldest YubiHSM firmware version to work with, written as
+    /// major.minor.build
+    #[clap(long, env = "OKS_MIN_FIRMWARE", default_value_t = MIN_FIRMWARE)]
+    min_firmware: FirmwareVersion,
+
+    /// Keep keys in a software keystore in the state directory instead of
+    /// the YubiHSM. For development and testing only: everything produced
+    /// is marked as sandbox output.
+    #[clap(long, env = "OKS_SANDBOX")]
+    sandbox: bool,
+
+    /// Certificate profile config used when issuing certs. The profiles
+    /// built in to oks are used if this isn't provided.
+    #[clap(long, env = "OKS_PROFILES")]
+    profiles: Option<PathBuf>,
+
     /// subcommands
     #[command(subcommand)]
     command: Command,
@@ -115,14 +171,6 @@ enum Command {
         #[clap(long, env, default_value = INPUT_PATH)]
         key_spec: PathBuf,
 
-        /// Path to the YubiHSM PKCS#11 module
-        #[clap(
-            long,
-            env = "OKS_PKCS11_PATH",
-            default_value = "/usr/lib/pkcs11/yubihsm_pkcs11.so"
-        )]
-        pkcs11_path: PathBuf,
-
         #[clap(flatten)]
         secret_method: SecretOutputArg,
 
@@ -130,25 +178,22 @@ enum Command {
         /// Challenge the caller for a new password, don't generate a
         /// random one for them.
         passwd_challenge: bool,
+
+        /// Report the CAs that would be initialized & what they would sign
+        /// without generating keys or connecting to the YubiHSM.
+        #[clap(long)]
+        dry_run: bool,
     },
 }
 
 #[derive(Subcommand, Debug, PartialEq)]
 /// Commands for operating on the CAs associated with keys in the HSM.
 enum CaCommand {
-    /// Initialize an OpenSSL CA for the given key.
+    /// Initialize a CA for the given key.
     Initialize {
         /// Spec file describing the CA signing key
         #[clap(long, env, default_value = INPUT_PATH)]
         key_spec: PathBuf,
-
-        /// Path to the YubiHSM PKCS#11 module
-        #[clap(
-            long,
-            env = "OKS_PKCS11_PATH",
-            default_value = "/usr/lib/pkcs11/yubihsm_pkcs11.so"
-        )]
-        pkcs11_path: PathBuf,
     },
 
     /// Use the CA associated with the provided key spec to sign the
@@ -156,6 +201,97 @@ enum CaCommand {
     Sign {
         #[clap(long, env, default_value = INPUT_PATH)]
         csr_spec: PathBuf,
+
+        /// Report the CA, subject, profile & serial number for each spec
+        /// along with the files that would be written, without signing
+        /// anything or connecting to the YubiHSM.
+        #[clap(long)]
+        dry_run: bool,
+    },
+
+    /// Recreate the CA state directory from the CA state stored in the
+    /// YubiHSM. Use this after restoring the YubiHSM from backup. The
+    /// issuance database only keeps the serial number, SPKI digest and
+    /// revocation of each cert.
+    RebuildState,
+
+    /// Install the cert for an intermediate CA that was signed by an
+    /// external CA. The cert must be for the CA's key & chain to the
+    /// issuer cert(s).
+    ImportCert {
+        /// Label of the intermediate CA
+        #[clap(long)]
+        label: String,
+
+        /// PEM encoded cert issued for the intermediate CA
+        #[clap(long)]
+        cert: PathBuf,
+
+        /// PEM encoded cert of the issuer, followed by the certs above it
+        /// if the issuer isn't a root
+        #[clap(long)]
+        issuer: PathBuf,
+    },
+
+    /// Mark the cert with the given serial number as revoked in the
+    /// database of the CA that issued it.
+    Revoke {
+        /// Serial number of the cert as hex
+        #[clap(long)]
+        serial: String,
+
+        /// Why the cert is being revoked
+        #[clap(long, value_enum, default_value_t = RevokeReason::Unspecified)]
+        reason: RevokeReason,
+
+        /// Label of the CA that issued the cert. Only required if more
+        /// than one CA has issued a cert with this serial number.
+        #[clap(long)]
+        label: Option<String>,
+    },
+
+    /// List the certs issued by the CA with the given label, or by each CA
+    /// if no label is provided, as JSON.
+    ListIssued {
+        #[clap(long)]
+        label: Option<String>,
+    },
+
+    /// Show the issuance record for the cert with the given serial number
+    /// as JSON.
+    Show {
+        /// Serial number of the cert as hex
+        serial: String,
+
+        /// Label of the CA that issued the cert. Only required if more
+        /// than one CA has issued a cert with this serial number.
+        #[clap(long)]
+        label: Option<String>,
+    },
+
+    /// Create the issuance database for each CA from the `index.txt`
+    /// database kept by `openssl ca`. Use this on CA state directories
+    /// created by older versions of oks.
+    ImportIndex,
+
+    /// Generate a signed CRL for the CA with the given label, or for each
+    /// CA with a cert if no label is provided.
+    Crl {
+        #[clap(long)]
+        label: Option<String>,
+
+        /// Number of days until the next CRL is expected
+        #[clap(long, default_value_t = 30)]
+        next_update: u32,
+    },
+
+    /// Compute the lpc55 root key table hash from the certs of the root
+    /// CAs with the given labels & write out a CMPA holding it, along with
+    /// JSON describing it.
+    Cmpa {
+        /// Labels of the root CAs in root key table order
+        #[clap(long, value_delimiter = ',', required = true)]
+        root_labels: Vec<String>,
     },
 }
 
@@ -183,6 +319,16 @@ enum HsmCommand {
         secret_method: SecretOutputArg,
     },
 
+    /// Compare the keys in the YubiHSM to the key specs. Exits with an
+    /// error if they differ.
+    Diff {
+        #[clap(flatten)]
+        auth_method: AuthInputArg,
+
+        #[clap(long, env, default_value = INPUT_PATH)]
+        key_spec: PathBuf,
+    },
+
     /// Generate keys in YubiHSM from specification.
     Generate {
         #[clap(flatten)]
@@ -218,11 +364,65 @@ enum HsmCommand {
         verifier: PathBuf,
     },
 
+    /// Report on the state of the YubiHSM: firmware, storage, audit log,
+    /// objects and whether the default authentication key is present.
+    Health {
+        #[clap(flatten)]
+        auth_method: AuthInputArg,
+    },
+
+    /// List objects in the YubiHSM.
+    List {
+        #[clap(flatten)]
+        auth_method: AuthInputArg,
+
+        #[clap(flatten)]
+        list: ListArgs,
+    },
+
     /// Get serial number from YubiHSM and dump to console.
     SerialNumber {
         #[clap(flatten)]
         auth_method: AuthInputArg,
     },
+
+    /// Get the public part of an asymmetric key from the YubiHSM and dump
+    /// it to stdout in the requested format and encoding.
+    Pubkey {
+        #[clap(flatten)]
+        auth_method: AuthInputArg,
+
+        /// The encoding used to serialize SPKI and PKCS#1 public keys.
+        #[clap(default_value_t, long, value_enum)]
+        encoding: Encoding,
+
+        /// The format used to represent the public key.
+        #[clap(default_value_t, long, value_enum)]
+        format: PubkeyFormat,
+
+        /// The label or object id of the key.
+        key: String,
+    },
+}
+
+#[derive(ValueEnum, Copy, Clone, Debug, Default, PartialEq)]
+enum PubkeyFormat {
+    /// SHA-256 digest of the DER encoded SPKI
+    Fingerprint,
+    /// JSON Web Key
+    Jwk,
+    /// PKCS#1 RSAPublicKey, RSA keys only
+    Pkcs1,
+    /// SubjectPublicKeyInfo
+    #[default]
+    Spki,
+}
+
+#[derive(ValueEnum, Copy, Clone, Debug, Default, PartialEq)]
+enum Encoding {
+    Der,
+    #[default]
+    Pem,
 }
 
 fn make_dir(path: &Path) -> Result<()> {
@@ -337,16 +537,53 @@ fn get_new_passwd(hsm: Option<&mut Hsm>) -> Result<Zeroizing<String>> {
     Ok(passwd)
 }
 
+/// Build the name of a file written to the output directory. Artifacts
+/// produced by the sandbox are tagged so they can't be mistaken for those
+/// produced by the YubiHSM.
+fn out_file_name(prefix: &str, suffix: &str, sandbox: bool) -> String {
+    if sandbox {
+        format!("{}.{}.{}", prefix, SANDBOX_TAG, suffix)
+    } else {
+        format!("{}.{}", prefix, suffix)
+    }
+}
+
 /// Perform all operations that make up the ceremony for provisioning an
 /// offline keystore.
 fn do_ceremony<P: AsRef<Path>>(
     csr_spec: P,
     key_spec: P,
-    pkcs11_path: P,
     output: &SecretOutputArg,
     challenge: bool,
     args: &Args,
 ) -> Result<()> {
+    let profiles = load_profiles(args.profiles.as_deref())?;
+
+    if args.sandbox {
+        // there's no HSM to set up: the sandbox keystore is created with
+        // the first key
+        let passwd = get_new_passwd(None)?;
+        let sandbox = Sandbox::new(&args.state, &passwd)?;
+        sandbox.generate(key_spec.as_ref())?;
+
+        let cas = initialize_all_ca(
+            key_spec.as_ref(),
+            &args.state,
+            &args.output,
+            &sandbox,
+            None,
+            &profiles,
+        )?;
+        return sign_all(
+            &cas,
+            csr_spec.as_ref(),
+            &args.output,
+            &sandbox,
+            None,
+            &profiles,
+        );
+    }
+
     let passwd_new = {
         // assume YubiHSM is in default state: use default auth credentials
         let passwd = Zeroizing::new("password".to_string());
@@ -357,6 +594,7 @@ fn do_ceremony<P: AsRef<Path>>(
             &args.state,
             true,
             args.transport,
+            Some(args.min_firmware),
         )?;
 
         let wrap = BackupKey::from_rng(&mut hsm)?;
@@ -415,44 +653,60 @@ fn do_ceremony<P: AsRef<Path>>(
         hsm.replace_default_auth(&passwd)?;
         passwd
     };
-    {
-        // use new password to auth
-        let hsm = Hsm::new(
-            2,
-            &passwd_new,
-            &args.output,
-            &args.state,
-            true,
-            args.transport,
-        )?;
-        hsm.generate(key_spec.as_ref())?;
-    }
+
+    // use new password to auth
+    let hsm = Hsm::new(
+        2,
+        &passwd_new,
+        &args.output,
+        &args.state,
+        true,
+        args.transport,
+        Some(args.min_firmware),
+    )?;
+    hsm.generate(key_spec.as_ref())?;
 
     // for each key_spec in `key_spec` initialize Ca
     let cas = initialize_all_ca(
         key_spec.as_ref(),
-        pkcs11_path.as_ref(),
         &args.state,
         &args.output,
-        &passwd_new,
+        &hsm.client,
+        Some(&hsm),
+        &profiles,
     )?;
+    store_ca_state(cas.values(), &hsm.client, &args.output)?;
     sign_all(
         &cas,
         csr_spec.as_ref(),
-        &args.state,
         &args.output,
-        args.transport,
-        &passwd_new,
-    )
+        &hsm.client,
+        Some(&hsm),
+        &profiles,
+    )?;
+
+    Ok(hsm.client.close_session()?)
 }
 
+/// Initialize a `Ca` for the KeySpec file provided or for each KeySpec file
+/// in the provided directory. The CA keys are held by `keys`. When this is
+/// the YubiHSM the `Hsm` must also be provided in `hsm`. Without it we're
+/// initializing sandbox CAs. Each KeySpec must name a CA profile from
+/// `profiles`.
 pub fn initialize_all_ca<P: AsRef<Path>>(
     key_spec: P,
-    pkcs11_path: P,
     ca_state: P,
     out: P,
-    password: &Zeroizing<String>,
+    keys: &dyn KeyStore,
+    hsm: Option<&Hsm>,
+    profiles: &Profiles,
 ) -> Result<HashMap<String, Ca>> {
+    let sandbox = hsm.is_none();
+    let provenance = Provenance {
+        csr_spec: None,
+        auth_id: hsm.map(|h| h.auth_id),
+    };
+
     let key_spec = fs::canonicalize(key_spec)?;
     debug!("canonical KeySpec path: {}", key_spec.display());
 
@@ -497,8 +751,10 @@ pub fn initialize_all_ca<P: AsRef<Path>>(
         let cert_or_csr = Ca::initialize(
             &spec,
             ca_dir.as_path(),
-            pkcs11_path.as_ref(),
-            password,
+            keys,
+            sandbox,
+            &provenance,
+            profiles,
         )
         .with_context(|| {
             format!(
@@ -507,14 +763,11 @@ pub fn initialize_all_ca<P: AsRef<Path>>(
             )
         })?;
 
-        let (path, pem) = match cert_or_csr {
-            CertOrCsr::Cert(p) => {
-                (out.as_ref().join(format!("{}.cert.pem", spec.label)), p)
-            }
-            CertOrCsr::Csr(p) => {
-                (out.as_ref().join(format!("{}.csr.pem", spec.label)), p)
-            }
+        let (suffix, pem) = match cert_or_csr {
+            CertOrCsr::Cert(p) => (CERT_SUFFIX, p),
+            CertOrCsr::Csr(p) => (CSR_SUFFIX, p),
         };
+        let path = out.as_ref().join(out_file_name(&label, suffix, sandbox));
         fs::write(&path, pem).with_context(|| {
             format!("Failed to write PEM to path: {}", path.display())
         })?;
@@ -529,7 +782,116 @@ pub fn initialize_all_ca<P: AsRef<Path>>(
     Ok(map)
 }
 
-pub fn load_all_ca<P: AsRef<Path>>(ca_state: P) -> Result<HashMap<String, Ca>> {
+/// Keep a copy of the state for each of the provided `Ca`s in the YubiHSM
+/// so that the CA state directory can be rebuilt if it's lost. The objects
+/// holding this state are backed up to `out`.
+fn store_ca_state<'a, P: AsRef<Path>>(
+    cas: impl IntoIterator<Item = &'a Ca>,
+    client: &Client,
+    out: P,
+) -> Result<()> {
+    for ca in cas {
+        ca.store_state(client, out.as_ref())?;
+    }
+
+    Ok(())
+}
+
+/// Find the `Ca` that issued the cert with the given serial number. If
+/// `label` is provided only the `Ca` with that label is considered.
+fn find_issuer<'a>(
+    cas: &'a HashMap<String, Ca>,
+    serial: &str,
+    label: Option<&str>,
+) -> Result<&'a Ca> {
+    if let Some(label) = label {
+        let ca = cas
+            .get(label)
+            .ok_or(anyhow!("no CA with label \"{}\"", label))?;
+        return if ca.has_issued(serial)? {
+            Ok(ca)
+        } else {
+            Err(anyhow!(
+                "CA \"{}\" has no cert with serial {}",
+                label,
+                serial
+            ))
+        };
+    }
+
+    let mut issuers = Vec::new();
+    for ca in cas.values() {
+        if ca.has_issued(serial)? {
+            issuers.push(ca);
+        }
+    }
+
+    match issuers[..] {
+        [ca] => Ok(ca),
+        [] => Err(anyhow!("no CA has issued a cert with serial {}", serial)),
+        _ => Err(anyhow!(
+            "more than one CA has issued a cert with serial {}, use --label",
+            serial
+        )),
+    }
+}
+
+// Generate a CRL for the CA with the given label, or for every CA with a
+// cert if `label` is `None`. The CRLs are written to `out`. As with
+// `sign_all`, when `keys` is the YubiHSM the `Hsm` must also be provided in
+// `hsm` so that the CRL number is kept in it.
+fn crl_all<P: AsRef<Path>>(
+    cas: &HashMap<String, Ca>,
+    label: Option<&str>,
+    out: P,
+    next_update: u32,
+    keys: &dyn KeyStore,
+    hsm: Option<&Hsm>,
+) -> Result<()> {
+    let cas: Vec<&Ca> = match label {
+        Some(label) => vec![cas
+            .get(label)
+            .ok_or(anyhow!("no CA with label \"{}\"", label))?],
+        // intermediate CAs without a cert can't issue CRLs
+        None => cas.values().filter(|ca| ca.has_cert()).collect(),
+    };
+
+    for ca in cas {
+        let crl = ca.crl(keys, next_update)?;
+
+        let path = out.as_ref().join(out_file_name(
+            &ca.name(),
+            CRL_SUFFIX,
+            hsm.is_none(),
+        ));
+        debug!("writing CRL to: {}", path.display());
+        fs::write(path, crl)?;
+
+        // issuing a CRL advanced the CRL number
+        if let Some(hsm) = hsm {
+            store_ca_state([ca], &hsm.client, &out)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Load the certificate profile config from `path`, or the built in profiles
+/// if there isn't one.
+fn load_profiles(path: Option<&Path>) -> Result<Profiles> {
+    match path {
+        Some(path) => {
+            info!("Using certificate profiles from: {}", path.display());
+            Profiles::from_path(path)
+        }
+        None => Ok(Profiles::default()),
+    }
+}
+
+pub fn load_all_ca<P: AsRef<Path>>(
+    ca_state: P,
+    sandbox: bool,
+) -> Result<HashMap<String, Ca>> {
     // find all directories under `ca_state`
     // for each directory in `ca_state`, Ca::load(directory)
     // insert into hash map
@@ -537,10 +899,20 @@ pub fn load_all_ca<P: AsRef<Path>>(ca_state: P) -> Result<HashMap<String, Ca>> {
         .filter(|x| x.is_ok()) // filter out error variant to make unwrap safe
         .map(|r| r.unwrap().path()) // get paths
         .filter(|x| x.is_dir()) // filter out every path that isn't a directory
+        .filter(|x| !x.ends_with(SANDBOX_DIR)) // the sandbox keystore isn't a CA
         .collect();
     let mut cas: HashMap<String, Ca> = HashMap::new();
     for dir in dirs {
         let ca = Ca::load(dir)?;
+        // never let sandbox & YubiHSM CAs be used together
+        if ca.is_sandbox() != sandbox {
+            return Err(anyhow!(
+                "CA \"{}\" is {}a sandbox CA: use a separate state directory \
+                for the sandbox",
+                ca.name(),
+                if ca.is_sandbox() { "" } else { "not " },
+            ));
+        }
         if cas.insert(ca.name(), ca).is_some() {
             return Err(anyhow!("found CA with duplicate key label"));
         }
@@ -549,139 +921,504 @@ pub fn load_all_ca<P: AsRef<Path>>(ca_state: P) -> Result<HashMap<String, Ca>> {
     Ok(cas)
 }
 
-// Get the CsrSpec from the provided file and use the HashMap of `Ca`s to find
-// the `Ca` that should sign it. Returns a PEM encoded x509 certificate as a
-// Vec<u8>.
-fn sign_csrspec<P: AsRef<Path>>(
-    spec: P,
-    cas: &HashMap<String, Ca>,
-    password: &Zeroizing<String>,
-) -> Result<Vec<u8>> {
+// Get the paths to the spec files (CsrSpec, DcsrSpec & ImageSpec) from
+// `spec`: either the file itself or each spec file in the directory.
+fn spec_paths<P: AsRef<Path>>(spec: P) -> Result<Vec<PathBuf>> {
+    let spec = fs::canonicalize(spec)?;
+    debug!("canonical spec path: {}", &spec.display());
+
+    let paths = if spec.is_file() {
+        vec![spec.clone()]
+    } else {
+        config::files_with_ext(&spec, CSRSPEC_EXT)?
+            .into_iter()
+            .chain(config::files_with_ext(&spec, DCSRSPEC_EXT)?)
+            .chain(config::files_with_ext(&spec, IMAGESPEC_EXT)?)
+            .collect::<Vec<PathBuf>>()
+    };
+
+    if paths.is_empty() {
+        return Err(anyhow!(
+            "no files with extensions \"{}\", \"{}\" or \"{}\" found in \
+            dir: {}",
+            CSRSPEC_EXT,
+            DCSRSPEC_EXT,
+            IMAGESPEC_EXT,
+            &spec.display()
+        ));
+    }
+
+    Ok(paths)
+}
+
+// Get the prefix of the spec file at `path`: everything in the file name
+// before the first '.'. Files produced from the spec get the same prefix.
+fn spec_prefix(path: &Path) -> Result<String> {
+    let filename = match path
+        .file_name()
+        .ok_or(anyhow!("Invalid path to spec file"))?
+        .to_os_string()
+        .into_string()
+    {
+        Ok(s) => s,
+        Err(s) => {
+            return Err(anyhow!("Invalid path to spec file: \"{:?}\"", s))
+        }
+    };
+
+    Ok(match filename.find('.') {
+        Some(i) => filename[..i].to_string(),
+        None => filename,
+    })
+}
+
+fn read_csrspec<P: AsRef<Path>>(spec: P) -> Result<CsrSpec> {
     let json = fs::read_to_string(&spec).with_context(|| {
         format!(
             "Failed to read CsrSpec json from {}",
             spec.as_ref().display()
         )
     })?;
-    let csr_spec = CsrSpec::from_str(&json)?;
+
+    Ok(CsrSpec::from_str(&json)?)
+}
+
+fn read_dcsrspec<P: AsRef<Path>>(spec: P) -> Result<DcsrSpec> {
+    let json = fs::read_to_string(&spec).with_context(|| {
+        format!(
+            "Failed to read DcsrSpec json from {}",
+            spec.as_ref().display()
+        )
+    })?;
+
+    serde_json::from_str(&json)
+        .context("Failed to deserialize DcsrSpec from json")
+}
+
+// Get the ImageSpec from the provided file along with the image & cert chain
+// it names. The paths in the ImageSpec are relative to the ImageSpec.
+fn read_imagespec<P: AsRef<Path>>(
+    spec: P,
+) -> Result<(ImageSpec, Vec<u8>, Vec<u8>)> {
+    let json = fs::read_to_string(&spec).with_context(|| {
+        format!(
+            "Failed to read ImageSpec json from {}",
+            spec.as_ref().display()
+        )
+    })?;
+    let image_spec: ImageSpec = serde_json::from_str(&json)
+        .context("Failed to deserialize ImageSpec from json")?;
+
+    let dir = spec.as_ref().parent().unwrap_or(Path::new("."));
+    let image_path = dir.join(&image_spec.image);
+    let image = fs::read(&image_path).with_context(|| {
+        format!("Failed to read image from {}", image_path.display())
+    })?;
+    let chain_path = dir.join(&image_spec.cert_chain);
+    let chain = fs::read(&chain_path).with_context(|| {
+        format!("Failed to read cert chain from {}", chain_path.display())
+    })?;
+
+    Ok((image_spec, image, chain))
+}
+
+// Get the CsrSpec from the provided file and use the HashMap of `Ca`s to find
+// the `Ca` that should sign it. Returns the `Ca` that signed the CSR, a PEM
+// encoded x509 certificate as a Vec<u8> and the PEM encoded chain of issuer
+// certs from the signer to its root. The chain is built before signing so
+// that we don't issue a cert we can't chain to its root.
+fn sign_csrspec<'a, P: AsRef<Path>>(
+    spec: P,
+    cas: &'a HashMap<String, Ca>,
+    keys: &dyn KeyStore,
+    auth_id: Option<Id>,
+    profiles: &Profiles,
+) -> Result<(&'a Ca, Vec<u8>, String)> {
+    let csr_spec = read_csrspec(&spec)?;
 
     let ca_name = csr_spec.label.to_string();
     let signer = cas
         .get(&ca_name)
         .ok_or(anyhow!("no CA \"{}\" for CsrSpec", ca_name))?;
 
+    let mut chain = String::new();
+    for cert in signer.chain(cas)? {
+        chain.push_str(&cert.to_pem(LineEnding::default())?);
+    }
+
+    let provenance = Provenance {
+        csr_spec: Some(SpecRecord::from_path(&spec)?),
+        auth_id,
+    };
+
     info!("Signing CSR from CsrSpec: {}", spec.as_ref().display());
-    signer.sign_csrspec(&csr_spec, password)
+    let cert =
+        signer.sign_csrspec(&csr_spec, keys, &provenance, profiles, cas)?;
+
+    Ok((signer, cert, chain))
 }
 
 // Get the DcsrSpec from the provided file, generate a debug credential from
-// it, then sign it with the appropriate `Ca`.
+// it, then sign it with the appropriate `Ca` if the debug credential policy
+// from its profile in `profiles` allows.
 fn sign_dcsrspec<P: AsRef<Path>>(
     spec: P,
     cas: &HashMap<String, Ca>,
-    hsm: &mut Hsm,
+    keys: &dyn KeyStore,
+    profiles: &Profiles,
 ) -> Result<Vec<u8>> {
-    let json = std::fs::read_to_string(&spec).with_context(|| {
-        format!(
-            "Failed to read DcsrSpec json from {}",
-            spec.as_ref().display()
-        )
-    })?;
-    let dcsr_spec: DcsrSpec = serde_json::from_str(&json)
-        .context("Failed to deserialize DcsrSpec from json")?;
+    let dcsr_spec = read_dcsrspec(&spec)?;
     let ca_name = dcsr_spec.label.to_string();
     let signer = cas
         .get(&ca_name)
         .ok_or(anyhow!("no Ca \"{}\" for DcsrSpec", ca_name))?;
 
     info!("Signing DCSR from DcsrSpec: {}", spec.as_ref().display());
-    let dc = signer.sign_dcsrspec(dcsr_spec, cas, &hsm.client)?;
-    hsm.client.close_session()?;
+    signer.sign_dcsrspec(dcsr_spec, cas, keys, profiles)
+}
+
+// Get the ImageSpec from the provided file, read the image & cert chain it
+// names, then sign the image with the appropriate `Ca`.
+fn sign_imagespec<P: AsRef<Path>>(
+    spec: P,
+    cas: &HashMap<String, Ca>,
+    keys: &dyn KeyStore,
+) -> Result<Vec<u8>> {
+    let (image_spec, image, chain) = read_imagespec(&spec)?;
+    let ca_name = image_spec.label.to_string();
+    let signer = cas
+        .get(&ca_name)
+        .ok_or(anyhow!("no Ca \"{}\" for ImageSpec", ca_name))?;
 
-    Ok(dc)
+    info!("Signing image from ImageSpec: {}", spec.as_ref().display());
+    signer.sign_imagespec(&image_spec, image, &chain, cas, keys)
 }
 
-// Process all relevant spec files (CsrSpec & DcsrSpec) from the provided
-// path. From these spec files we determine which Ca should sign them. The
-// resulting certs / credentials are written to `out`.
+// Process all relevant spec files (CsrSpec, DcsrSpec & ImageSpec) from the
+// provided path. From these spec files we determine which Ca should sign
+// them. The resulting certs / credentials / images are written to `out`. Each
+// cert is written along with the chain of its issuer certs and a bundle of
+// both. The CA keys are held by `keys`. When this is the YubiHSM the `Hsm`
+// must also be provided in `hsm` so that the state of each CA is kept in it
+// & the auth key is recorded in the issuance database. Without it we're
+// signing with the sandbox. Certs are issued with the profiles from
+// `profiles`.
 pub fn sign_all<P: AsRef<Path>>(
     cas: &HashMap<String, Ca>,
     spec: P,
-    state: P,
     out: P,
-    transport: Transport,
-    password: &Zeroizing<String>,
+    keys: &dyn KeyStore,
+    hsm: Option<&Hsm>,
+    profiles: &Profiles,
 ) -> Result<()> {
-    let spec = fs::canonicalize(spec)?;
-    debug!("canonical spec path: {}", &spec.display());
+    for path in spec_paths(spec)? {
+        let filename = path.file_name().unwrap().to_string_lossy();
 
-    let paths = if spec.is_file() {
-        vec![spec.clone()]
-    } else {
-        config::files_with_ext(&spec, CSRSPEC_EXT)?
-            .into_iter()
-            .chain(config::files_with_ext(&spec, DCSRSPEC_EXT)?)
-            .collect::<Vec<PathBuf>>()
-    };
+        // Write the cert to the output directory. We give this output file
+        // the same prefix as the spec file.
+        let prefix = spec_prefix(&path)?;
+
+        let (suffix, data, signer) = if filename.ends_with(CSRSPEC_EXT) {
+            let (signer, cert, chain) = sign_csrspec(
+                path,
+                cas,
+                keys,
+                hsm.map(|h| h.auth_id),
+                profiles,
+            )?;
 
-    if paths.is_empty() {
-        return Err(anyhow!(
-            "no files with extensions \"{}\" or \"{}\" found in dir: {}",
-            CSRSPEC_EXT,
-            DCSRSPEC_EXT,
-            &spec.display()
+            let chain_path = out.as_ref().join(out_file_name(
+                &prefix,
+                CHAIN_SUFFIX,
+                hsm.is_none(),
+            ));
+            debug!("writing issuer chain to: {}", chain_path.display());
+            fs::write(chain_path, &chain)?;
+
+            let fullchain_path = out.as_ref().join(out_file_name(
+                &prefix,
+                FULLCHAIN_SUFFIX,
+                hsm.is_none(),
+            ));
+            debug!("writing full chain to: {}", fullchain_path.display());
+            fs::write(fullchain_path, [&cert, chain.as_bytes()].concat())?;
+
+            (CERT_SUFFIX, cert, Some(signer))
+        } else if filename.ends_with(DCSRSPEC_EXT) {
+            (DCSR_SUFFIX, sign_dcsrspec(path, cas, keys, profiles)?, None)
+        } else if filename.ends_with(IMAGESPEC_EXT) {
+            (IMAGE_SUFFIX, sign_imagespec(path, cas, keys)?, None)
+        } else {
+            return Err(anyhow!("Unknown input spec: {}", path.display()));
+        };
+
+        let path = PathBuf::from(out.as_ref()).join(out_file_name(
+            &prefix,
+            suffix,
+            hsm.is_none(),
         ));
+        debug!("writing credential to: {}", path.display());
+        std::fs::write(path, &data)?;
+
+        // signing a cert advanced the serial number
+        if let (Some(signer), Some(hsm)) = (signer, hsm) {
+            store_ca_state([signer], &hsm.client, &out)?;
+        }
+
+        // the cert has been written out but the CA state needs fixing
+        // before anything else is signed
+        if let Some(signer) = signer {
+            signer.check_recorded(&data)?;
+        }
     }
 
-    for path in paths {
-        let filename = path.file_name().unwrap().to_string_lossy();
+    Ok(())
+}
 
-        let prefix = {
-            // Write the cert to the output directory. We give this output
-            // file the same prefix as the spec file.
-            let filename = match path
-                .file_name()
-                .ok_or(anyhow!("Invalid path to DcsrSpec file"))?
-                .to_os_string()
-                .into_string()
-            {
-                Ok(s) => s,
-                Err(s) => {
-                    return Err(anyhow!(
-                        "Invalid path to CsrSpec file: \"{:?}\"",
-                        s
-                    ))
+// What a dry run knows about a CA that may sign specs: the `Ca` if it has
+// been initialized, the spec for its key, its subject if it has (or would
+// have) a cert & the serial number of the next cert it would issue if its
+// state could be read.
+struct DryRunCa<'a> {
+    ca: Option<&'a Ca>,
+    spec: &'a KeySpec,
+    subject: Option<Name>,
+    serial: Option<BigUint>,
+}
+
+impl DryRunCa<'_> {
+    // The serial number of the next cert issued by the CA. Only sequential
+    // serial numbers are known before the cert is issued, for the others we
+    // report the serial policy.
+    fn next_serial(&mut self) -> String {
+        match (&self.spec.serial_policy, &mut self.serial) {
+            (None | Some(SerialPolicy::Sequential), Some(serial)) => {
+                let next = format!("{:X}", serial);
+                *serial += 1u32;
+                next
+            }
+            (None | Some(SerialPolicy::Sequential), None) => {
+                "(unknown)".to_string()
+            }
+            (Some(policy), _) => format!("({})", policy),
+        }
+    }
+}
+
+// Parse the KeySpecs from `key_spec` (if provided) & the spec files from
+// `spec`, then report what `initialize_all_ca` & `sign_all` would do with
+// them: the CA that signs each spec along with the subject, profile & serial
+// number of each cert and the files written to the output directory. The
+// CAs from `cas` are not modified and no keys are used. Each problem found
+// is reported & we keep going: an error is returned at the end if there
+// were any.
+fn dry_run(
+    key_spec: Option<&Path>,
+    spec: &Path,
+    cas: &HashMap<String, Ca>,
+    profiles: &Profiles,
+    sandbox: bool,
+) -> Result<()> {
+    let mut problems = 0;
+    let mut report = |result: Result<()>| {
+        if let Err(e) = result {
+            println!("  error: {:#}", e);
+            problems += 1;
+        }
+    };
+
+    let mut keyspecs = Vec::new();
+    if let Some(key_spec) = key_spec {
+        let key_spec = fs::canonicalize(key_spec)?;
+        let paths = if key_spec.is_file() {
+            vec![key_spec.clone()]
+        } else {
+            config::files_with_ext(&key_spec, KEYSPEC_EXT)?
+        };
+        if paths.is_empty() {
+            return Err(anyhow!(
+                "no files with extension \"{}\" found in dir: {}",
+                KEYSPEC_EXT,
+                &key_spec.display()
+            ));
+        }
+
+        for path in paths {
+            let spec = fs::read_to_string(&path)
+                .context("Failed to read KeySpec json")
+                .and_then(|json| Ok(KeySpec::from_str(&json)?));
+            match spec {
+                Ok(spec) => keyspecs.push((path, spec)),
+                Err(e) => {
+                    println!("{}:", path.display());
+                    report(Err(e));
                 }
-            };
-            match filename.find('.') {
-                Some(i) => filename[..i].to_string(),
-                None => filename,
+            }
+        }
+    }
+
+    let mut signers: HashMap<String, DryRunCa> = HashMap::new();
+    for ca in cas.values() {
+        let subject = match ca.cert() {
+            Ok(cert) => Some(cert.tbs_certificate.subject),
+            Err(_) => None,
+        };
+        let serial = match ca.peek_serial() {
+            Ok(serial) => Some(serial),
+            Err(e) => {
+                println!("CA {}:", ca.name());
+                report(Err(e));
+                None
             }
         };
+        signers.insert(
+            ca.name(),
+            DryRunCa {
+                ca: Some(ca),
+                spec: ca.spec(),
+                subject,
+                serial,
+            },
+        );
+    }
 
-        let (suffix, data) = if filename.ends_with(CSRSPEC_EXT) {
-            (CERT_SUFFIX, sign_csrspec(path, cas, password)?)
-        } else if filename.ends_with(DCSRSPEC_EXT) {
-            let mut hsm = Hsm::new(
-                0x0002,
-                password,
-                out.as_ref(),
-                state.as_ref(),
-                false,
-                transport,
-            )?;
-            (DCSR_SUFFIX, sign_dcsrspec(path, cas, &mut hsm)?)
-        } else {
-            return Err(anyhow!("Unknown input spec: {}", path.display()));
+    for (path, spec) in &keyspecs {
+        let label = spec.label.to_string();
+        println!("{}:", path.display());
+        println!("  CA: {}", label);
+        let mut signer = DryRunCa {
+            ca: None,
+            spec,
+            subject: None,
+            serial: Some(spec.initial_serial_number.clone()),
         };
+        report((|| {
+            if signers.contains_key(&label) {
+                return Err(anyhow!("CA \"{}\" already exists", label));
+            }
+            let subject = ca_subject(spec, sandbox)?;
+            println!("  subject: {}", subject);
+            let profile = profiles.ca_profile(&spec.profile_name())?;
+            println!("  profile: {}", profile.name);
+            if spec.self_signed {
+                println!("  serial: {}", signer.next_serial());
+                signer.subject = Some(subject);
+                println!(
+                    "  output: {}",
+                    out_file_name(&label, CERT_SUFFIX, sandbox)
+                );
+            } else {
+                println!(
+                    "  output: {}",
+                    out_file_name(&label, CSR_SUFFIX, sandbox)
+                );
+            }
+            Ok(())
+        })());
+        signers.entry(label).or_insert(signer);
+    }
 
-        let path =
-            PathBuf::from(out.as_ref()).join(format!("{}.{}", prefix, suffix));
-        debug!("writing credential to: {}", path.display());
-        std::fs::write(path, &data)?;
+    for path in spec_paths(spec)? {
+        let filename = path.file_name().unwrap().to_string_lossy();
+        println!("{}:", path.display());
+        report((|| {
+            let prefix = spec_prefix(&path)?;
+            if filename.ends_with(CSRSPEC_EXT) {
+                let csr_spec = read_csrspec(&path)?;
+                let label = csr_spec.label.to_string();
+                let signer = signers
+                    .get_mut(&label)
+                    .ok_or(anyhow!("no CA \"{}\" for CsrSpec", label))?;
+                println!("  signer: {}", label);
+                let (subject, profile) = match signer.ca {
+                    Some(ca) => {
+                        let (_, subject, profile) =
+                            ca.check_csrspec(&csr_spec, profiles, cas, None)?;
+                        ca.chain(cas)?;
+                        (subject, profile)
+                    }
+                    None => {
+                        let issuer = signer.subject.as_ref().ok_or(
+                            CaError::NoCaCert {
+                                name: label.clone(),
+                            },
+                        )?;
+                        let profile = profiles.issued_profile(
+                            &signer.spec.profile_name(),
+                            csr_spec.profile.as_deref(),
+                        )?;
+                        let csr = CertReq::from_pem(csr_spec.csr.as_bytes())
+                            .context("Failed to parse CSR from CsrSpec")?;
+                        let subject = check_csr(
+                            &csr,
+                            profile,
+                            signer.spec,
+                            issuer,
+                            cas,
+                            None,
+                        )?;
+                        (subject, profile)
+                    }
+                };
+                println!("  subject: {}", subject);
+                println!("  profile: {}", profile.name);
+                println!("  serial: {}", signer.next_serial());
+                for suffix in [CERT_SUFFIX, CHAIN_SUFFIX, FULLCHAIN_SUFFIX] {
+                    println!(
+                        "  output: {}",
+                        out_file_name(&prefix, suffix, sandbox)
+                    );
+                }
+            } else if filename.ends_with(DCSRSPEC_EXT) {
+                let dcsr_spec = read_dcsrspec(&path)?;
+                let label = dcsr_spec.label.to_string();
+                let signer = signers
+                    .get(&label)
+                    .ok_or(anyhow!("no CA \"{}\" for DcsrSpec", label))?;
+                println!("  signer: {}", label);
+                match signer.ca {
+                    Some(ca) => {
+                        ca.dcsr_tbs(dcsr_spec, cas, profiles)?;
+                    }
+                    // without the cert for the new CA all we can check are
+                    // the root keys
+                    None => {
+                        let mut labels = Vec::new();
+                        for label in &dcsr_spec.root_labels {
+                            labels.push(label.try_as_str()?.to_string());
+                        }
+                        check_dcsr_roots(&label, &labels)?;
+                    }
+                }
+                println!(
+                    "  output: {}",
+                    out_file_name(&prefix, DCSR_SUFFIX, sandbox)
+                );
+            } else if filename.ends_with(IMAGESPEC_EXT) {
+                let (image_spec, image, chain) = read_imagespec(&path)?;
+                let label = image_spec.label.to_string();
+                let signer = signers
+                    .get(&label)
+                    .ok_or(anyhow!("no CA \"{}\" for ImageSpec", label))?;
+                println!("  signer: {}", label);
+                if let Some(ca) = signer.ca {
+                    ca.image_tbs(&image_spec, image, &chain, cas)?;
+                }
+                println!(
+                    "  output: {}",
+                    out_file_name(&prefix, IMAGE_SUFFIX, sandbox)
+                );
+            } else {
+                return Err(anyhow!("Unknown input spec: {}", path.display()));
+            }
+            Ok(())
+        })());
     }
 
-    Ok(())
+    match problems {
+        0 => Ok(()),
+        n => Err(anyhow!("dry run found {} problem(s)", n)),
+    }
 }
 
 fn main() -> Result<()> {
@@ -704,34 +1441,308 @@ fn main() -> Result<()> {
             auth_method,
             command,
         } => {
-            let mut passwd_reader =
-                secret_reader::get_passwd_reader(&auth_method)?;
-            let password = passwd_reader.read(PASSWD_PROMPT)?;
+            // only the commands that use the CA keys or update the CA state
+            // in the YubiHSM ask for the password
+            let keys = || -> Result<(Zeroizing<String>, Option<Sandbox>)> {
+                let mut passwd_reader =
+                    secret_reader::get_passwd_reader(&auth_method)?;
+                let password = passwd_reader.read(PASSWD_PROMPT)?;
+                let sandbox = if args.sandbox {
+                    Some(Sandbox::new(&args.state, &password)?)
+                } else {
+                    None
+                };
+                Ok((password, sandbox))
+            };
 
             match command {
-                CaCommand::Initialize {
-                    key_spec,
-                    pkcs11_path,
-                } => {
-                    let _ = initialize_all_ca(
+                CaCommand::Initialize { key_spec } => {
+                    let profiles = load_profiles(args.profiles.as_deref())?;
+                    let (password, sandbox) = keys()?;
+                    if let Some(sandbox) = sandbox {
+                        initialize_all_ca(
+                            &key_spec,
+                            &args.state,
+                            &args.output,
+                            &sandbox,
+                            None,
+                            &profiles,
+                        )?;
+                        return Ok(());
+                    }
+
+                    let hsm = Hsm::new(
+                        0x0002,
+                        &password,
+                        &args.output,
+                        &args.state,
+                        true,
+                        args.transport,
+                        Some(args.min_firmware),
+                    )?;
+                    let cas = initialize_all_ca(
                         &key_spec,
-                        &pkcs11_path,
                         &args.state,
                         &args.output,
+                        &hsm.client,
+                        Some(&hsm),
+                        &profiles,
+                    )?;
+                    store_ca_state(cas.values(), &hsm.client, &args.output)?;
+                    Ok(hsm.client.close_session()?)
+                }
+                CaCommand::RebuildState => {
+                    if args.sandbox {
+                        return Err(anyhow!(
+                            "sandbox CAs have no state in the YubiHSM"
+                        ));
+                    }
+
+                    let (password, _) = keys()?;
+                    let hsm = Hsm::new(
+                        0x0002,
                         &password,
+                        &args.output,
+                        &args.state,
+                        false,
+                        args.transport,
+                        Some(args.min_firmware),
                     )?;
+                    let cas = Ca::rebuild(&hsm.client, &args.state)?;
+                    hsm.client.close_session()?;
+                    for ca in cas {
+                        println!("rebuilt state for CA: {}", ca.name());
+                    }
                     Ok(())
                 }
-                CaCommand::Sign { csr_spec } => {
-                    let cas = load_all_ca(&args.state)?;
+                CaCommand::ImportCert {
+                    label,
+                    cert,
+                    issuer,
+                } => {
+                    let cas = load_all_ca(&args.state, args.sandbox)?;
+                    let ca = cas
+                        .get(&label)
+                        .ok_or(anyhow!("no CA with label \"{}\"", label))?;
+                    let cert = fs::read(&cert).with_context(|| {
+                        format!("Failed to read cert from {}", cert.display())
+                    })?;
+                    let issuer = fs::read(&issuer).with_context(|| {
+                        format!(
+                            "Failed to read issuer cert from {}",
+                            issuer.display()
+                        )
+                    })?;
+                    let (password, sandbox) = keys()?;
+                    if let Some(sandbox) = sandbox {
+                        return ca.import_cert(&sandbox, &cert, &issuer);
+                    }
+
+                    let hsm = Hsm::new(
+                        0x0002,
+                        &password,
+                        &args.output,
+                        &args.state,
+                        false,
+                        args.transport,
+                        Some(args.min_firmware),
+                    )?;
+                    ca.import_cert(&hsm.client, &cert, &issuer)?;
+                    store_ca_state([ca], &hsm.client, &args.output)?;
+                    Ok(hsm.client.close_session()?)
+                }
+                CaCommand::Sign {
+                    csr_spec,
+                    dry_run: true,
+                } => {
+                    let cas = load_all_ca(&args.state, args.sandbox)?;
+                    let profiles = load_profiles(args.profiles.as_deref())?;
+                    dry_run(None, &csr_spec, &cas, &profiles, args.sandbox)
+                }
+                CaCommand::Sign { csr_spec, .. } => {
+                    let cas = load_all_ca(&args.state, args.sandbox)?;
+                    let profiles = load_profiles(args.profiles.as_deref())?;
+                    let (password, sandbox) = keys()?;
+                    if let Some(sandbox) = sandbox {
+                        return sign_all(
+                            &cas,
+                            &csr_spec,
+                            &args.output,
+                            &sandbox,
+                            None,
+                            &profiles,
+                        );
+                    }
+
+                    let hsm = Hsm::new(
+                        0x0002,
+                        &password,
+                        &args.output,
+                        &args.state,
+                        false,
+                        args.transport,
+                        Some(args.min_firmware),
+                    )?;
                     sign_all(
                         &cas,
                         &csr_spec,
-                        &args.state,
                         &args.output,
+                        &hsm.client,
+                        Some(&hsm),
+                        &profiles,
+                    )?;
+                    Ok(hsm.client.close_session()?)
+                }
+                CaCommand::Revoke {
+                    serial,
+                    reason,
+                    label,
+                } => {
+                    let cas = load_all_ca(&args.state, args.sandbox)?;
+                    let ca = find_issuer(&cas, &serial, label.as_deref())?;
+                    if args.sandbox {
+                        return ca.revoke(&serial, reason);
+                    }
+
+                    // revoking a cert changes the issuance database
+                    let (password, _) = keys()?;
+                    let hsm = Hsm::new(
+                        0x0002,
+                        &password,
+                        &args.output,
+                        &args.state,
+                        false,
                         args.transport,
+                        Some(args.min_firmware),
+                    )?;
+                    ca.revoke(&serial, reason)?;
+                    store_ca_state([ca], &hsm.client, &args.output)?;
+                    Ok(hsm.client.close_session()?)
+                }
+                CaCommand::Crl { label, next_update } => {
+                    let cas = load_all_ca(&args.state, args.sandbox)?;
+                    let (password, sandbox) = keys()?;
+                    if let Some(sandbox) = sandbox {
+                        return crl_all(
+                            &cas,
+                            label.as_deref(),
+                            &args.output,
+                            next_update,
+                            &sandbox,
+                            None,
+                        );
+                    }
+
+                    let hsm = Hsm::new(
+                        0x0002,
                         &password,
-                    )
+                        &args.output,
+                        &args.state,
+                        false,
+                        args.transport,
+                        Some(args.min_firmware),
+                    )?;
+                    crl_all(
+                        &cas,
+                        label.as_deref(),
+                        &args.output,
+                        next_update,
+                        &hsm.client,
+                        Some(&hsm),
+                    )?;
+                    Ok(hsm.client.close_session()?)
+                }
+                CaCommand::ListIssued { label } => {
+                    let cas = load_all_ca(&args.state, args.sandbox)?;
+                    let mut issued = BTreeMap::new();
+                    for (name, ca) in &cas {
+                        match &label {
+                            Some(label) if label != name => continue,
+                            _ => issued.insert(name, ca.issued()?),
+                        };
+                    }
+                    if let Some(label) = label {
+                        if issued.is_empty() {
+                            return Err(anyhow!(
+                                "no CA with label \"{}\"",
+                                label
+                            ));
+                        }
+                    }
+
+                    println!("{}", serde_json::to_string_pretty(&issued)?);
+                    Ok(())
+                }
+                CaCommand::Show { serial, label } => {
+                    let cas = load_all_ca(&args.state, args.sandbox)?;
+                    let cert = find_issuer(&cas, &serial, label.as_deref())?
+                        .issued_cert(&serial)?;
+
+                    println!("{}", serde_json::to_string_pretty(&cert)?);
+                    Ok(())
+                }
+                CaCommand::Cmpa { root_labels } => {
+                    let cas = load_all_ca(&args.state, args.sandbox)?;
+                    let cmpa = Cmpa::new(&root_labels, &cas)?;
+
+                    let path = args.output.join(out_file_name(
+                        CMPA_PREFIX,
+                        CMPA_SUFFIX,
+                        args.sandbox,
+                    ));
+                    debug!("writing CMPA to: {}", path.display());
+                    fs::write(path, &cmpa.page)?;
+
+                    let json = serde_json::to_string_pretty(&cmpa)?;
+                    let path = args.output.join(out_file_name(
+                        CMPA_PREFIX,
+                        CMPA_JSON_SUFFIX,
+                        args.sandbox,
+                    ));
+                    debug!("writing CMPA description to: {}", path.display());
+                    fs::write(path, &json)?;
+
+                    println!("{}", json);
+                    Ok(())
+                }
+                CaCommand::ImportIndex => {
+                    let cas = load_all_ca(&args.state, args.sandbox)?;
+                    let hsm = if args.sandbox {
+                        None
+                    } else {
+                        let (password, _) = keys()?;
+                        Some(Hsm::new(
+                            0x0002,
+                            &password,
+                            &args.output,
+                            &args.state,
+                            false,
+                            args.transport,
+                            Some(args.min_firmware),
+                        )?)
+                    };
+                    for ca in cas.values() {
+                        if ca.import_index()? {
+                            println!(
+                                "imported index.txt for CA: {}",
+                                ca.name()
+                            );
+                            // the issuance database is new
+                            if let Some(hsm) = &hsm {
+                                store_ca_state(
+                                    [ca],
+                                    &hsm.client,
+                                    &args.output,
+                                )?;
+                            }
+                        } else {
+                            info!("CA {} has an issuance database", ca.name());
+                        }
+                    }
+                    match hsm {
+                        Some(hsm) => Ok(hsm.client.close_session()?),
+                        None => Ok(()),
+                    }
                 }
             }
         }
@@ -740,6 +1751,23 @@ fn main() -> Result<()> {
             command,
             no_backup,
         } => {
+            if args.sandbox {
+                // the sandbox keystore only stands in for the keys
+                return match command {
+                    HsmCommand::Generate {
+                        ref auth_method,
+                        ref key_spec,
+                    } => {
+                        let passwd =
+                            get_passwd(auth_id, auth_method, &command)?;
+                        Sandbox::new(&args.state, &passwd)?.generate(key_spec)
+                    }
+                    _ => Err(anyhow!(
+                        "only `hsm generate` is supported with --sandbox"
+                    )),
+                };
+            }
+
             match command {
                 HsmCommand::Initialize {
                     passwd_challenge,
@@ -753,6 +1781,7 @@ fn main() -> Result<()> {
                         &args.state,
                         !no_backup,
                         args.transport,
+                        Some(args.min_firmware),
                     )?;
 
                     debug!("Initialize");
@@ -830,6 +1859,7 @@ fn main() -> Result<()> {
                         &args.state,
                         !no_backup,
                         args.transport,
+                        Some(args.min_firmware),
                     )?;
 
                     // generate a new secret
@@ -865,6 +1895,7 @@ fn main() -> Result<()> {
                         &args.state,
                         !no_backup,
                         args.transport,
+                        Some(args.min_firmware),
                     )?;
 
                     // add new auth value to auth-id 2, remove old value from
@@ -891,6 +1922,7 @@ fn main() -> Result<()> {
                         &args.state,
                         !no_backup,
                         args.transport,
+                        Some(args.min_firmware),
                     )?;
 
                     hsm.generate(key_spec)
@@ -908,6 +1940,7 @@ fn main() -> Result<()> {
                         &args.state,
                         !no_backup,
                         args.transport,
+                        Some(args.min_firmware),
                     )?;
 
                     let verifier = fs::read_to_string(verifier)?;
@@ -955,22 +1988,134 @@ fn main() -> Result<()> {
                         &args.state,
                         !no_backup,
                         args.transport,
+                        Some(args.min_firmware),
                     )?;
 
                     oks::hsm::dump_sn(&hsm.client)
                 }
+                HsmCommand::Diff {
+                    ref auth_method,
+                    ref key_spec,
+                } => {
+                    let passwd = get_passwd(auth_id, auth_method, &command)?;
+                    let auth_id = get_auth_id(auth_id, &command);
+                    let hsm = Hsm::new(
+                        auth_id,
+                        &passwd,
+                        &args.output,
+                        &args.state,
+                        !no_backup,
+                        args.transport,
+                        Some(args.min_firmware),
+                    )?;
+
+                    hsm.diff(key_spec)
+                }
+                HsmCommand::List {
+                    ref auth_method,
+                    ref list,
+                } => {
+                    let passwd = get_passwd(auth_id, auth_method, &command)?;
+                    let auth_id = get_auth_id(auth_id, &command);
+                    let hsm = Hsm::new(
+                        auth_id,
+                        &passwd,
+                        &args.output,
+                        &args.state,
+                        !no_backup,
+                        args.transport,
+                        Some(args.min_firmware),
+                    )?;
+
+                    oks::hsm::dump_objects(&hsm.client, list)
+                }
+                HsmCommand::Health { ref auth_method } => {
+                    let passwd = get_passwd(auth_id, auth_method, &command)?;
+                    let auth_id = get_auth_id(auth_id, &command);
+                    let hsm = Hsm::new(
+                        auth_id,
+                        &passwd,
+                        &args.output,
+                        &args.state,
+                        !no_backup,
+                        args.transport,
+                        // the firmware version is part of the report
+                        None,
+                    )?;
+
+                    oks::hsm::health(&hsm.client, args.min_firmware)
+                }
+                HsmCommand::Pubkey {
+                    ref auth_method,
+                    encoding,
+                    format,
+                    ref key,
+                } => {
+                    let passwd = get_passwd(auth_id, auth_method, &command)?;
+                    let auth_id = get_auth_id(auth_id, &command);
+                    let hsm = Hsm::new(
+                        auth_id,
+                        &passwd,
+                        &args.output,
+                        &args.state,
+                        !no_backup,
+                        args.transport,
+                        Some(args.min_firmware),
+                    )?;
+
+                    let id = oks::hsm::find_key(&hsm.client, key)?;
+                    let pub_key = PublicKey::from_hsm(&hsm.client, id)?;
+                    let out = match (format, encoding) {
+                        (PubkeyFormat::Spki, Encoding::Der) => {
+                            pub_key.to_spki_der()?
+                        }
+                        (PubkeyFormat::Spki, Encoding::Pem) => {
+                            pub_key.to_spki_pem()?.into_bytes()
+                        }
+                        (PubkeyFormat::Pkcs1, Encoding::Der) => {
+                            pub_key.to_pkcs1_der()?
+                        }
+                        (PubkeyFormat::Pkcs1, Encoding::Pem) => {
+                            pub_key.to_pkcs1_pem()?.into_bytes()
+                        }
+                        (PubkeyFormat::Jwk, _) => {
+                            format!("{}\n", pub_key.to_jwk()?).into_bytes()
+                        }
+                        (PubkeyFormat::Fingerprint, _) => {
+                            format!("{}\n", pub_key.fingerprint()?).into_bytes()
+                        }
+                    };
+
+                    io::stdout()
+                        .write_all(&out)
+                        .context("write encoded public key to stdout")
+                }
             }
         }
         Command::Ceremony {
             ref csr_spec,
             ref key_spec,
-            ref pkcs11_path,
+            dry_run: true,
+            ..
+        } => {
+            let profiles = load_profiles(args.profiles.as_deref())?;
+            dry_run(
+                Some(key_spec),
+                csr_spec,
+                &load_all_ca(&args.state, args.sandbox)?,
+                &profiles,
+                args.sandbox,
+            )
+        }
+        Command::Ceremony {
+            ref csr_spec,
+            ref key_spec,
             ref secret_method,
             passwd_challenge,
+            dry_run: false,
         } => do_ceremony(
             csr_spec,
             key_spec,
-            pkcs11_path,
             secret_method,
             passwd_challenge,
             &args,
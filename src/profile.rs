@@ -0,0 +1,563 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use serde_with::{
+    serde_as, DeserializeFromStr, DisplayFromStr, SerializeDisplay,
+};
+use std::{
+    collections::BTreeMap,
+    fmt::{self, Display, Formatter},
+    fs,
+    net::IpAddr,
+    path::Path,
+    str::FromStr,
+};
+use thiserror::Error;
+use x509_cert::{
+    der::{asn1::ObjectIdentifier, Any, Decode},
+    name::Name,
+};
+
+/// The version of the profile config format understood by this version of
+/// oks.
+pub const PROFILES_VERSION: u32 = 1;
+
+/// The profiles used when no profile config is provided. These are the
+/// extension profiles oks has always used, named for the purposes of the
+/// keys they were used with.
+pub const DEFAULT_PROFILES: &str = include_str!("../specs/profiles.json");
+
+#[derive(Error, Debug)]
+pub enum ProfileError {
+    #[error(
+        "unsupported profile config version {version}, expected {}",
+        PROFILES_VERSION
+    )]
+    BadVersion { version: u32 },
+    #[error("no certificate profile named \"{name}\"")]
+    NoProfile { name: String },
+    #[error("profile \"{name}\" must be a CA profile")]
+    NotCa { name: String },
+    #[error("profile \"{name}\" has a path length but isn't a CA profile")]
+    PathLenNotCa { name: String },
+    #[error(
+        "profile \"{issuer}\" has path length 0 but may issue CA profile \
+        \"{subject}\""
+    )]
+    PathLenExceeded { issuer: String, subject: String },
+    #[error(
+        "profile \"{issuer}\" may not issue certs with profile \"{subject}\""
+    )]
+    NotAllowed { issuer: String, subject: String },
+    #[error("profile \"{issuer}\" may not issue certs")]
+    NoIssuance { issuer: String },
+    #[error(
+        "profile \"{issuer}\" may issue more than one profile, the CSR spec \
+        must name one"
+    )]
+    AmbiguousProfile { issuer: String },
+    #[error("invalid name constraint: \"{constraint}\"")]
+    BadNameConstraint { constraint: String },
+    #[error("invalid device UUID: \"{uuid}\"")]
+    BadUuid { uuid: String },
+    #[error("value of extension {oid} isn't DER")]
+    BadExtensionValue { oid: ObjectIdentifier },
+}
+
+/// The bits of the key usage extension, using the names from RFC 5280.
+#[derive(Copy, Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum KeyUsageBit {
+    DigitalSignature,
+    NonRepudiation,
+    KeyEncipherment,
+    DataEncipherment,
+    KeyAgreement,
+    KeyCertSign,
+    #[serde(rename = "cRLSign")]
+    CrlSign,
+    EncipherOnly,
+    DecipherOnly,
+}
+
+/// The types of key that may be certified, named like the algorithms in
+/// key specs.
+#[derive(Copy, Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub enum KeyAlgorithm {
+    Rsa2048,
+    Rsa3072,
+    Rsa4096,
+    Ecp256,
+    Ecp384,
+    Ecp521,
+    Ed25519,
+}
+
+/// A name in a name constraint, written like the names in an openssl.cnf:
+/// "DNS:example.com", "email:example.com", "URI:example.com",
+/// "dirName:O=Example,C=US" or "IP:192.168.0.0/16".
+#[derive(Clone, Debug, DeserializeFromStr, Eq, PartialEq, SerializeDisplay)]
+pub enum ConstraintName {
+    Dns(String),
+    Email(String),
+    Uri(String),
+    DirName(String),
+    Ip { addr: IpAddr, prefix: u8 },
+}
+
+impl FromStr for ConstraintName {
+    type Err = ProfileError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let err = || ProfileError::BadNameConstraint {
+            constraint: s.to_string(),
+        };
+
+        let (kind, name) = s.split_once(':').ok_or_else(err)?;
+        if name.is_empty() || !name.is_ascii() {
+            return Err(err());
+        }
+
+        match kind {
+            "DNS" => Ok(Self::Dns(name.to_string())),
+            "email" => Ok(Self::Email(name.to_string())),
+            "URI" => Ok(Self::Uri(name.to_string())),
+            "dirName" => {
+                Name::from_str(name).map_err(|_| err())?;
+                Ok(Self::DirName(name.to_string()))
+            }
+            "IP" => {
+                let (addr, prefix) = name.split_once('/').ok_or_else(err)?;
+                let addr = IpAddr::from_str(addr).map_err(|_| err())?;
+                let prefix = u8::from_str(prefix).map_err(|_| err())?;
+                let max = if addr.is_ipv4() { 32 } else { 128 };
+                if prefix > max {
+                    return Err(err());
+                }
+                Ok(Self::Ip { addr, prefix })
+            }
+            _ => Err(err()),
+        }
+    }
+}
+
+impl Display for ConstraintName {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Dns(name) => write!(f, "DNS:{}", name),
+            Self::Email(name) => write!(f, "email:{}", name),
+            Self::Uri(name) => write!(f, "URI:{}", name),
+            Self::DirName(name) => write!(f, "dirName:{}", name),
+            Self::Ip { addr, prefix } => write!(f, "IP:{}/{}", addr, prefix),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct NameConstraintsSpec {
+    #[serde(default)]
+    pub permitted: Vec<ConstraintName>,
+    #[serde(default)]
+    pub excluded: Vec<ConstraintName>,
+}
+
+/// The UUID of a device, hex encoded.
+#[derive(
+    Clone, Copy, Debug, DeserializeFromStr, Eq, PartialEq, SerializeDisplay,
+)]
+pub struct DeviceUuid(pub [u8; 16]);
+
+impl FromStr for DeviceUuid {
+    type Err = ProfileError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut uuid = [0u8; 16];
+        hex::decode_to_slice(s, &mut uuid).map_err(|_| {
+            ProfileError::BadUuid {
+                uuid: s.to_string(),
+            }
+        })?;
+
+        Ok(Self(uuid))
+    }
+}
+
+impl Display for DeviceUuid {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", hex::encode(self.0))
+    }
+}
+
+/// Limits on the lpc55 debug credentials signed by a CA with the profile.
+/// CAs with profiles that have no debug credential policy can't sign them.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct DebugPolicy {
+    /// The smallest debug key allowed in bits.
+    pub min_key_bits: Option<usize>,
+    /// The debug permissions (CC_SOCU bits) that may be granted. Any may be
+    /// granted if this is omitted.
+    pub permissions: Option<u32>,
+    /// The devices credentials may be signed for. Any device if this is
+    /// omitted.
+    pub uuids: Option<Vec<DeviceUuid>>,
+}
+
+/// An extension not otherwise supported by profiles. The value is the DER
+/// encoded extension value, hex encoded.
+#[serde_as]
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct CustomExtension {
+    #[serde_as(as = "DisplayFromStr")]
+    pub oid: ObjectIdentifier,
+    #[serde(default)]
+    pub critical: bool,
+    #[serde(with = "hex")]
+    pub value: Vec<u8>,
+}
+
+/// The extensions & constraints applied to a cert issued with this profile.
+/// The subject & authority key identifiers are always included.
+#[serde_as]
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct Profile {
+    /// The name of the profile in the config it was loaded from.
+    #[serde(skip)]
+    pub name: String,
+    /// The types of key allowed in CSRs for certs with this profile. Any
+    /// supported key is allowed if this is empty.
+    #[serde(default)]
+    pub key_algorithms: Vec<KeyAlgorithm>,
+    #[serde(default)]
+    pub ca: bool,
+    pub path_len: Option<u8>,
+    pub key_usage: Vec<KeyUsageBit>,
+    #[serde_as(as = "Vec<DisplayFromStr>")]
+    #[serde(default)]
+    pub extended_key_usage: Vec<ObjectIdentifier>,
+    #[serde_as(as = "Vec<DisplayFromStr>")]
+    #[serde(default)]
+    pub policies: Vec<ObjectIdentifier>,
+    #[serde(default)]
+    pub policies_critical: bool,
+    pub name_constraints: Option<NameConstraintsSpec>,
+    #[serde(default)]
+    pub extensions: Vec<CustomExtension>,
+    /// The longest validity period in days allowed for certs issued with
+    /// this profile. Certs issued without a validity period in their spec
+    /// get the longest one allowed.
+    pub max_validity_days: Option<u32>,
+    /// The debug credentials CAs with this profile may sign, if any.
+    pub debug_credentials: Option<DebugPolicy>,
+}
+
+/// Permission for CAs with the `issuer` profile to issue certs with the
+/// `subject` profile.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct IssuanceRule {
+    pub issuer: String,
+    pub subject: String,
+}
+
+/// A versioned collection of certificate profiles & the rules for which
+/// profiles may issue which.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Profiles {
+    pub version: u32,
+    pub profiles: BTreeMap<String, Profile>,
+    #[serde(default)]
+    pub issuance: Vec<IssuanceRule>,
+}
+
+impl Default for Profiles {
+    fn default() -> Self {
+        Self::from_str(DEFAULT_PROFILES)
+            .expect("the default profile config is valid")
+    }
+}
+
+impl FromStr for Profiles {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut profiles: Self = serde_json::from_str(s)?;
+        for (name, profile) in profiles.profiles.iter_mut() {
+            profile.name.clone_from(name);
+        }
+        profiles.check()?;
+
+        Ok(profiles)
+    }
+}
+
+impl Profiles {
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let json = fs::read_to_string(path.as_ref()).with_context(|| {
+            format!(
+                "Failed to read profile config from {}",
+                path.as_ref().display()
+            )
+        })?;
+
+        Self::from_str(&json).with_context(|| {
+            format!(
+                "Failed to load profile config from {}",
+                path.as_ref().display()
+            )
+        })
+    }
+
+    /// Get the profile with the given name.
+    pub fn get(&self, name: &str) -> Result<&Profile, ProfileError> {
+        self.profiles
+            .get(name)
+            .ok_or_else(|| ProfileError::NoProfile {
+                name: name.to_string(),
+            })
+    }
+
+    /// Get the profile with the given name, which must be for a CA.
+    pub fn ca_profile(&self, name: &str) -> Result<&Profile, ProfileError> {
+        let profile = self.get(name)?;
+        if !profile.ca {
+            return Err(ProfileError::NotCa {
+                name: name.to_string(),
+            });
+        }
+
+        Ok(profile)
+    }
+
+    /// Get the profile for a cert issued by a CA with the `issuer` profile.
+    /// The `requested` profile must be allowed by an issuance rule. If no
+    /// profile is requested the issuer must be allowed to issue exactly
+    /// one.
+    pub fn issued_profile(
+        &self,
+        issuer: &str,
+        requested: Option<&str>,
+    ) -> Result<&Profile, ProfileError> {
+        let mut allowed = self
+            .issuance
+            .iter()
+            .filter(|r| r.issuer == issuer)
+            .map(|r| r.subject.as_str());
+
+        match requested {
+            Some(subject) => {
+                if !allowed.any(|s| s == subject) {
+                    return Err(ProfileError::NotAllowed {
+                        issuer: issuer.to_string(),
+                        subject: subject.to_string(),
+                    });
+                }
+                self.get(subject)
+            }
+            None => match (allowed.next(), allowed.next()) {
+                (Some(subject), None) => self.get(subject),
+                (None, _) => Err(ProfileError::NoIssuance {
+                    issuer: issuer.to_string(),
+                }),
+                (Some(_), Some(_)) => Err(ProfileError::AmbiguousProfile {
+                    issuer: issuer.to_string(),
+                }),
+            },
+        }
+    }
+
+    /// Check that the config is one we understand & that it's consistent.
+    fn check(&self) -> Result<(), ProfileError> {
+        if self.version != PROFILES_VERSION {
+            return Err(ProfileError::BadVersion {
+                version: self.version,
+            });
+        }
+
+        for (name, profile) in &self.profiles {
+            if profile.path_len.is_some() && !profile.ca {
+                return Err(ProfileError::PathLenNotCa { name: name.clone() });
+            }
+            for ext in &profile.extensions {
+                if Any::from_der(&ext.value).is_err() {
+                    return Err(ProfileError::BadExtensionValue {
+                        oid: ext.oid,
+                    });
+                }
+            }
+        }
+
+        for rule in &self.issuance {
+            let issuer = self.ca_profile(&rule.issuer)?;
+            let subject = self.get(&rule.subject)?;
+            if issuer.path_len == Some(0) && subject.ca {
+                return Err(ProfileError::PathLenExceeded {
+                    issuer: rule.issuer.clone(),
+                    subject: rule.subject.clone(),
+                });
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_profiles() -> Result<()> {
+        let profiles = Profiles::default();
+
+        let root = profiles.ca_profile("v3_rot_release_root")?;
+        assert_eq!(root.name, "v3_rot_release_root");
+        assert_eq!(
+            root.key_usage,
+            [KeyUsageBit::KeyCertSign, KeyUsageBit::CrlSign]
+        );
+        assert_eq!(
+            profiles.issued_profile("v3_rot_release_root", None)?.name,
+            "v3_code_signing_rel"
+        );
+        assert_eq!(
+            profiles.issued_profile("v3_identity", None)?.name,
+            "v3_identity"
+        );
+
+        // release roots may only sign debug credentials once allowed
+        let debug = root.debug_credentials.as_ref().unwrap();
+        assert_eq!(debug.permissions, Some(0));
+        assert_eq!(debug.uuids.as_deref(), Some([].as_slice()));
+        let dev_root = profiles.ca_profile("v3_rot_development_root")?;
+        assert_eq!(dev_root.debug_credentials.as_ref().unwrap().uuids, None);
+        assert!(profiles
+            .ca_profile("v3_identity")?
+            .debug_credentials
+            .is_none());
+
+        // code signing keys aren't CAs & may not issue certs
+        assert!(profiles.ca_profile("v3_code_signing_rel").is_err());
+        assert!(profiles
+            .issued_profile("v3_code_signing_rel", None)
+            .is_err());
+
+        // a release root may not issue development code signing certs
+        assert!(profiles
+            .issued_profile("v3_rot_release_root", Some("v3_code_signing_dev"))
+            .is_err());
+
+        Ok(())
+    }
+
+    const JSON_PROFILES: &str = r#"{
+        "version": 1,
+        "profiles": {
+            "root": {
+                "ca": true,
+                "path_len": 1,
+                "key_usage": ["keyCertSign", "cRLSign"],
+                "name_constraints": {
+                    "permitted": ["DNS:example.com", "IP:10.0.0.0/8"],
+                    "excluded": ["dirName:O=Other,C=US"]
+                }
+            },
+            "intermediate": {
+                "ca": true,
+                "path_len": 0,
+                "key_usage": ["keyCertSign"],
+                "max_validity_days": 3650,
+                "debug_credentials": {
+                    "permissions": 1023,
+                    "uuids": ["000102030405060708090a0b0c0d0e0f"]
+                }
+            },
+            "leaf": {
+                "key_algorithms": ["Ecp256", "Ed25519"],
+                "key_usage": ["digitalSignature"],
+                "extended_key_usage": ["1.3.6.1.5.5.7.3.1"],
+                "extensions": [
+                    { "oid": "1.3.6.1.4.1.57551.9", "value": "0500" }
+                ]
+            }
+        },
+        "issuance": [
+            { "issuer": "root", "subject": "intermediate" },
+            { "issuer": "root", "subject": "leaf" },
+            { "issuer": "intermediate", "subject": "leaf" }
+        ]
+    }"#;
+
+    #[test]
+    fn custom_profiles() -> Result<()> {
+        let profiles = Profiles::from_str(JSON_PROFILES)?;
+
+        let root = profiles.get("root")?;
+        let constraints = root.name_constraints.as_ref().unwrap();
+        assert_eq!(
+            constraints.permitted[1],
+            ConstraintName::Ip {
+                addr: IpAddr::from([10, 0, 0, 0]),
+                prefix: 8
+            }
+        );
+        assert_eq!(constraints.excluded[0].to_string(), "dirName:O=Other,C=US");
+        let leaf = profiles.get("leaf")?;
+        assert_eq!(
+            leaf.key_algorithms,
+            [KeyAlgorithm::Ecp256, KeyAlgorithm::Ed25519]
+        );
+        assert_eq!(leaf.extensions[0].value, [0x05, 0x00]);
+        let debug = profiles.get("intermediate")?.debug_credentials.as_ref();
+        assert_eq!(
+            debug.and_then(|d| d.uuids.as_deref()),
+            Some([DeviceUuid(core::array::from_fn(|i| i as u8))].as_slice())
+        );
+
+        // the root issues more than one profile so the spec must pick
+        assert!(profiles.issued_profile("root", None).is_err());
+        assert_eq!(
+            profiles.issued_profile("root", Some("intermediate"))?.name,
+            "intermediate"
+        );
+        assert_eq!(profiles.issued_profile("intermediate", None)?.name, "leaf");
+        assert!(profiles
+            .issued_profile("intermediate", Some("intermediate"))
+            .is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn bad_profiles() {
+        let bad = [
+            JSON_PROFILES.replace(r#""version": 1"#, r#""version": 2"#),
+            // issuers must be CAs
+            JSON_PROFILES.replace(
+                r#"{ "issuer": "intermediate", "subject": "leaf" }"#,
+                r#"{ "issuer": "leaf", "subject": "leaf" }"#,
+            ),
+            // rules must refer to profiles in the config
+            JSON_PROFILES.replace(
+                r#""subject": "intermediate""#,
+                r#""subject": "missing""#,
+            ),
+            // path length 0 CAs can't issue CA certs
+            JSON_PROFILES.replace(
+                r#"{ "issuer": "intermediate", "subject": "leaf" }"#,
+                r#"{ "issuer": "intermediate", "subject": "root" }"#,
+            ),
+            JSON_PROFILES.replace("IP:10.0.0.0/8", "IP:10.0.0.0/33"),
+            JSON_PROFILES.replace("DNS:example.com", "dns:example.com"),
+            JSON_PROFILES.replace(r#""0500""#, r#""05""#),
+            JSON_PROFILES.replace("keyCertSign\"]", "certSign\"]"),
+            JSON_PROFILES.replace("Ed25519", "Ed448"),
+            JSON_PROFILES.replace("0e0f\"]", "0e\"]"),
+        ];
+
+        for json in bad {
+            assert!(Profiles::from_str(&json).is_err(), "{}", json);
+        }
+    }
+}
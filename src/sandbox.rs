@@ -0,0 +1,370 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use anyhow::{Context, Result};
+use log::{debug, info};
+use rand::{rngs::OsRng, RngCore};
+use rsa::{
+    pkcs1v15::SigningKey,
+    pkcs8::{
+        pkcs5::{pbes2, scrypt},
+        DecodePrivateKey, EncodePrivateKey, EncodePublicKey,
+        EncryptedPrivateKeyInfo, LineEnding, PrivateKeyInfo, SecretDocument,
+    },
+    signature::{hazmat::PrehashSigner, SignatureEncoding, Signer},
+    RsaPrivateKey, RsaPublicKey,
+};
+use sha2::Sha256;
+use std::{
+    fs::{self, Permissions},
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+use thiserror::Error;
+use yubihsm::{asymmetric, object::Id};
+use zeroize::Zeroizing;
+
+use crate::{
+    ca::KeyStore,
+    config::{self, KeySpec, KEYSPEC_EXT},
+};
+
+/// Name of the directory under the state directory where the sandbox keeps
+/// its keys.
+pub const SANDBOX_DIR: &str = "sandbox";
+
+/// Every artifact produced from a sandbox key has this tag added to its file
+/// name.
+pub const SANDBOX_TAG: &str = "sandbox";
+
+const KEY_EXT: &str = ".key.pem";
+const ENCRYPTED_LABEL: &str = "ENCRYPTED PRIVATE KEY";
+
+// scrypt parameters used to derive the key that encrypts each private key.
+// We don't use the defaults from the `pkcs8` crate (log2(N) = 15) because
+// they require more memory than `openssl` allows by default and it's useful
+// to be able to inspect these keys with `openssl`.
+const SCRYPT_LOG_N: u8 = 14;
+const SCRYPT_R: u32 = 8;
+const SCRYPT_P: u32 = 1;
+const SCRYPT_LEN: usize = 32;
+
+#[derive(Error, Debug)]
+pub enum SandboxError {
+    #[error("key with id {id:#06x} already exists in the sandbox")]
+    KeyExists { id: Id },
+    #[error("no key with id {id:#06x} in the sandbox")]
+    NoKey { id: Id },
+    #[error("sandbox key with id {id:#06x} is not an RSA key")]
+    NotRsa { id: Id },
+    #[error("sandbox key with id {id:#06x} is not an ECDSA key")]
+    NotEcdsa { id: Id },
+    #[error("sandbox key with id {id:#06x} is not an Ed25519 key")]
+    NotEd25519 { id: Id },
+    #[error("Unsupported Algorithm")]
+    UnsupportedAlgorithm,
+}
+
+/// A software stand-in for the YubiHSM. Keys are generated in software from
+/// the same `KeySpec`s used with the HSM and are kept in the state directory
+/// as PKCS#8 documents encrypted under the sandbox password.
+///
+/// NOTE: This is for development and testing only. Nothing produced by the
+/// sandbox should ever be used for anything real.
+pub struct Sandbox {
+    root: PathBuf,
+    password: Zeroizing<String>,
+}
+
+impl Sandbox {
+    pub fn new(state_dir: &Path, password: &Zeroizing<String>) -> Result<Self> {
+        let root = state_dir.join(SANDBOX_DIR);
+        if !root.exists() {
+            info!("creating sandbox keystore: {}", root.display());
+            fs::create_dir_all(&root).with_context(|| {
+                format!("Failed to create directory \"{}\"", root.display())
+            })?;
+            let perms = Permissions::from_mode(0o700);
+            debug!("setting permissions on {} to {:#?}", root.display(), perms);
+            fs::set_permissions(&root, perms)?;
+        }
+
+        Ok(Self {
+            root,
+            password: password.clone(),
+        })
+    }
+
+    /// Path to the file holding the encrypted key with the given id.
+    pub fn key_path(&self, id: Id) -> PathBuf {
+        self.root.join(format!("{:04x}{}", id, KEY_EXT))
+    }
+
+    /// Generate keys from the KeySpec file provided or from all KeySpec
+    /// files in the provided directory. This mirrors `Hsm::generate`.
+    pub fn generate(&self, key_spec: &Path) -> Result<()> {
+        debug!("canonical KeySpec path: {}", key_spec.display());
+
+        let paths = if key_spec.is_file() {
+            vec![key_spec.to_path_buf()]
+        } else {
+            config::files_with_ext(key_spec, KEYSPEC_EXT)?
+        };
+
+        if paths.is_empty() {
+            return Err(anyhow::anyhow!(
+                "no files with extension \"{}\" found in dir: {}",
+                KEYSPEC_EXT,
+                &key_spec.display()
+            ));
+        }
+
+        for path in paths {
+            let json = fs::read_to_string(&path)?;
+            debug!("spec as json: {}", json);
+
+            let spec = KeySpec::from_str(&json)?;
+            debug!("KeySpec from {}: {:#?}", path.display(), spec);
+
+            info!("Generating sandbox key for spec: {:?}", path);
+            self.generate_keyspec(&spec)?;
+        }
+
+        Ok(())
+    }
+
+    /// Generate a private key in software from the provided specification
+    /// and write it to the keystore encrypted under the sandbox password.
+    pub fn generate_keyspec(&self, spec: &KeySpec) -> Result<Id> {
+        let path = self.key_path(spec.id);
+        if path.exists() {
+            return Err(SandboxError::KeyExists { id: spec.id }.into());
+        }
+
+        let der = match spec.algorithm {
+            asymmetric::Algorithm::Rsa2048 => rsa_pkcs8_der(2048)?,
+            asymmetric::Algorithm::Rsa3072 => rsa_pkcs8_der(3072)?,
+            asymmetric::Algorithm::Rsa4096 => rsa_pkcs8_der(4096)?,
+            // p256 is pinned to an older version than the other curves (see
+            // Cargo.toml) so we go through its re-export of the pkcs8 crate
+            asymmetric::Algorithm::EcP256 => Zeroizing::new(
+                p256::pkcs8::EncodePrivateKey::to_pkcs8_der(
+                    &p256::SecretKey::random(&mut OsRng),
+                )
+                .map_err(|e| anyhow::anyhow!("P-256 to PKCS#8: {}", e))?
+                .as_bytes()
+                .to_vec(),
+            ),
+            asymmetric::Algorithm::EcP384 => Zeroizing::new(
+                p384::SecretKey::random(&mut OsRng)
+                    .to_pkcs8_der()?
+                    .as_bytes()
+                    .to_vec(),
+            ),
+            asymmetric::Algorithm::EcP521 => Zeroizing::new(
+                p521::SecretKey::random(&mut OsRng)
+                    .to_pkcs8_der()?
+                    .as_bytes()
+                    .to_vec(),
+            ),
+            asymmetric::Algorithm::Ed25519 => Zeroizing::new(
+                ed25519_dalek::SigningKey::generate(&mut OsRng)
+                    .to_pkcs8_der()?
+                    .as_bytes()
+                    .to_vec(),
+            ),
+            _ => return Err(SandboxError::UnsupportedAlgorithm.into()),
+        };
+        debug!("new {:#?} sandbox key w/ id: {}", spec.algorithm, spec.id);
+
+        let mut salt = [0u8; 16];
+        let mut iv = [0u8; 16];
+        OsRng.try_fill_bytes(&mut salt)?;
+        OsRng.try_fill_bytes(&mut iv)?;
+
+        let params =
+            scrypt::Params::new(SCRYPT_LOG_N, SCRYPT_R, SCRYPT_P, SCRYPT_LEN)
+                .map_err(|e| anyhow::anyhow!("bad scrypt params: {}", e))?;
+        let params = pbes2::Parameters::scrypt_aes256cbc(params, &salt, &iv)
+            .map_err(|e| anyhow::anyhow!("bad PBES2 params: {}", e))?;
+        let pem = PrivateKeyInfo::try_from(der.as_slice())?
+            .encrypt_with_params(params, self.password.as_bytes())?
+            .to_pem(ENCRYPTED_LABEL, LineEnding::default())?;
+
+        info!(
+            "Writing sandbox key with label \"{}\" to: {}",
+            spec.label,
+            path.display()
+        );
+        fs::write(&path, pem.as_bytes())?;
+        fs::set_permissions(&path, Permissions::from_mode(0o600))?;
+
+        Ok(spec.id)
+    }
+
+    /// Decrypt the key with the given id from the keystore.
+    fn key(&self, id: Id) -> Result<SandboxKey> {
+        let path = self.key_path(id);
+        if !path.exists() {
+            return Err(SandboxError::NoKey { id }.into());
+        }
+
+        let pem = Zeroizing::new(fs::read_to_string(&path)?);
+        let (_, doc) = SecretDocument::from_pem(&pem)?;
+        let der = EncryptedPrivateKeyInfo::try_from(doc.as_bytes())?
+            .decrypt(self.password.as_bytes())?;
+        let der = der.as_bytes();
+
+        // the PKCS#8 document identifies the type of key but it's simpler
+        // to try each of the types the sandbox generates
+        if let Ok(key) = RsaPrivateKey::from_pkcs8_der(der) {
+            Ok(SandboxKey::Rsa(key))
+        } else if let Ok(key) =
+            p256::pkcs8::DecodePrivateKey::from_pkcs8_der(der)
+        {
+            Ok(SandboxKey::EcP256(key))
+        } else if let Ok(key) = p384::SecretKey::from_pkcs8_der(der) {
+            Ok(SandboxKey::EcP384(key))
+        } else if let Ok(key) = p521::SecretKey::from_pkcs8_der(der) {
+            Ok(SandboxKey::EcP521(key))
+        } else if let Ok(key) = ed25519_dalek::SigningKey::from_pkcs8_der(der) {
+            Ok(SandboxKey::Ed25519(key))
+        } else {
+            Err(SandboxError::UnsupportedAlgorithm.into())
+        }
+    }
+}
+
+/// The types of private key held by the sandbox.
+enum SandboxKey {
+    Rsa(RsaPrivateKey),
+    EcP256(p256::SecretKey),
+    EcP384(p384::SecretKey),
+    EcP521(p521::SecretKey),
+    Ed25519(ed25519_dalek::SigningKey),
+}
+
+fn rsa_pkcs8_der(bits: usize) -> Result<Zeroizing<Vec<u8>>> {
+    let der = RsaPrivateKey::new(&mut OsRng, bits)?.to_pkcs8_der()?;
+    Ok(Zeroizing::new(der.as_bytes().to_vec()))
+}
+
+impl KeyStore for Sandbox {
+    fn sign_rsa_pkcs1v15_sha256(&self, id: Id, data: &[u8]) -> Result<Vec<u8>> {
+        match self.key(id)? {
+            SandboxKey::Rsa(key) => {
+                let key = SigningKey::<Sha256>::new(key);
+                Ok(key.try_sign(data)?.to_vec())
+            }
+            _ => Err(SandboxError::NotRsa { id }.into()),
+        }
+    }
+
+    fn sign_ecdsa_prehash(&self, id: Id, digest: &[u8]) -> Result<Vec<u8>> {
+        match self.key(id)? {
+            SandboxKey::EcP256(key) => {
+                let key = p256::ecdsa::SigningKey::from(key);
+                let sig: p256::ecdsa::Signature = key.sign_prehash(digest)?;
+                Ok(sig.to_der().as_bytes().to_vec())
+            }
+            SandboxKey::EcP384(key) => {
+                let key = p384::ecdsa::SigningKey::from(key);
+                let sig: p384::ecdsa::Signature = key.sign_prehash(digest)?;
+                Ok(sig.to_der().as_bytes().to_vec())
+            }
+            SandboxKey::EcP521(key) => {
+                let key = p521::ecdsa::SigningKey::from_bytes(&key.to_bytes())?;
+                let sig: p521::ecdsa::Signature = key.sign_prehash(digest)?;
+                Ok(sig.to_der().as_bytes().to_vec())
+            }
+            _ => Err(SandboxError::NotEcdsa { id }.into()),
+        }
+    }
+
+    fn sign_ed25519(&self, id: Id, data: &[u8]) -> Result<Vec<u8>> {
+        match self.key(id)? {
+            SandboxKey::Ed25519(key) => Ok(key.sign(data).to_bytes().to_vec()),
+            _ => Err(SandboxError::NotEd25519 { id }.into()),
+        }
+    }
+
+    fn public_key_der(&self, id: Id) -> Result<Vec<u8>> {
+        let der = match self.key(id)? {
+            SandboxKey::Rsa(key) => {
+                RsaPublicKey::from(&key).to_public_key_der()?.into_vec()
+            }
+            // see the note on p256 in `generate_keyspec`
+            SandboxKey::EcP256(key) => {
+                p256::pkcs8::EncodePublicKey::to_public_key_der(
+                    &key.public_key(),
+                )
+                .map_err(|e| anyhow::anyhow!("P-256 to SPKI: {}", e))?
+                .as_bytes()
+                .to_vec()
+            }
+            SandboxKey::EcP384(key) => {
+                key.public_key().to_public_key_der()?.into_vec()
+            }
+            SandboxKey::EcP521(key) => {
+                key.public_key().to_public_key_der()?.into_vec()
+            }
+            SandboxKey::Ed25519(key) => {
+                key.verifying_key().to_public_key_der()?.into_vec()
+            }
+        };
+
+        Ok(der)
+    }
+
+    fn random_bytes(&self, len: usize) -> Result<Vec<u8>> {
+        let mut bytes = vec![0u8; len];
+        OsRng.fill_bytes(&mut bytes);
+        Ok(bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    const JSON_ECP384: &str = r#"{
+        "common_name": "RoT Identity Signing Offline CA",
+        "id": 2,
+        "algorithm":"Ecp384",
+        "capabilities":"All",
+        "domain":"DOM1",
+        "hash":"Sha384",
+        "label":"rot-identity-signing-ca",
+        "purpose":"Identity",
+        "initial_serial_number":"0000000000000000000000000000000000000000",
+        "self_signed":true
+    }"#;
+
+    #[test]
+    fn generate_and_decrypt() -> Result<()> {
+        let dir = TempDir::new()?;
+        let password = Zeroizing::new("password".to_string());
+        let sandbox = Sandbox::new(dir.path(), &password)?;
+
+        let spec = KeySpec::from_str(JSON_ECP384)?;
+        let id = sandbox.generate_keyspec(&spec)?;
+
+        let pem = fs::read_to_string(sandbox.key_path(id))?;
+        assert!(
+            p384::SecretKey::from_pkcs8_encrypted_pem(&pem, "password").is_ok()
+        );
+        assert!(
+            p384::SecretKey::from_pkcs8_encrypted_pem(&pem, "wrong").is_err()
+        );
+
+        // the same id can't be generated twice
+        assert!(sandbox.generate_keyspec(&spec).is_err());
+        // and the key isn't RSA so it can't sign a debug credential
+        assert!(sandbox.sign_rsa_pkcs1v15_sha256(id, b"data").is_err());
+
+        Ok(())
+    }
+}